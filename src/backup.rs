@@ -0,0 +1,184 @@
+use bincode::SizeLimit;
+use bincode::rustc_serialize::{encode, decode};
+
+use wal_file::{WalRecord, encode_record, decode_record, record_size};
+use codec::{Codec, Bincode};
+use comparator::{Comparator, Ascending};
+
+use ::{BTree, KeyType, ValueType};
+
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write, ErrorKind};
+use std::io::Error as IOError;
+
+const FILE_HEADER: &'static str = "Backup\0";
+const CURRENT_VERSION: u8 = 0x01;
+const HEADER_SIZE: usize = 8;
+const INFO_SIZE: usize = 32;  // a bincode BackupInfo
+
+/// Describes the changes held by an incremental backup
+#[derive(RustcEncodable, RustcDecodable, Clone, PartialEq, Debug)]
+pub struct BackupInfo {
+    pub key_size: usize,
+    pub value_size: usize,
+    pub since: u64,          // the backup holds the changes after this sequence number...
+    pub last_sequence: u64,  // ...up to & including this one
+}
+
+/// Writes an incremental backup file. The format is:
+/// |-------------------------------------------|
+/// | "Backup\0" | version (u8)                 |
+/// |-------------------------------------------|
+/// | BackupInfo in bincode format              |
+/// |-------------------------------------------|
+/// | WalRecord, padded like in the WAL         |
+/// |-------------------------------------------|
+/// | ... one per change, in sequence order     |
+/// |-------------------------------------------|
+pub fn write_backup<K: KeyType, V: ValueType, KC: Codec<K>, VC: Codec<V>>(file_path: &String, info: &BackupInfo, records: &[WalRecord<K,V>]) -> Result<(), Box<Error>> {
+    let mut fd = try!(OpenOptions::new().write(true).create(true).truncate(true).open(file_path));
+
+    try!(fd.write_all(FILE_HEADER.as_bytes()));
+    try!(fd.write_all(&[CURRENT_VERSION]));
+    try!(fd.write_all(&try!(encode(info, SizeLimit::Bounded(INFO_SIZE as u64)))));
+
+    for record in records {
+        try!(fd.write_all(&try!(encode_record::<K,V,KC,VC>(record, record_size(info.key_size, info.value_size)))));
+    }
+
+    Ok(try!(fd.sync_all()))
+}
+
+/// Reads an incremental backup file, failing if any of it is damaged
+pub fn read_backup<K: KeyType, V: ValueType, KC: Codec<K>, VC: Codec<V>>(file_path: &String) -> Result<(BackupInfo, Vec<WalRecord<K,V>>), Box<Error>> {
+    let mut buff = Vec::new();
+
+    try!(try!(File::open(file_path)).read_to_end(&mut buff));
+
+    if buff.len() < HEADER_SIZE + INFO_SIZE || &buff[..HEADER_SIZE - 1] != FILE_HEADER.as_bytes() {
+        return Err(invalid_backup(file_path, "not a backup file"));
+    }
+
+    if buff[HEADER_SIZE - 1] != CURRENT_VERSION {
+        return Err(invalid_backup(file_path, &format!("unknown version {}", buff[HEADER_SIZE - 1])));
+    }
+
+    let info: BackupInfo = try!(decode(&buff[HEADER_SIZE..HEADER_SIZE + INFO_SIZE]));
+    let size = record_size(info.key_size, info.value_size);
+    let body = &buff[HEADER_SIZE + INFO_SIZE..];
+
+    if body.len() % size != 0 || (body.len() / size) as u64 != info.last_sequence - info.since {
+        return Err(invalid_backup(file_path, "wrong number of records"));
+    }
+
+    let mut records = Vec::new();
+
+    for chunk in body.chunks(size) {
+        match decode_record::<K,V,KC,VC>(chunk) {
+            Ok(record) => records.push(record),
+            Err(_) => return Err(invalid_backup(file_path, "record can't be decoded"))
+        }
+    }
+
+    return Ok((info, records));
+}
+
+fn invalid_backup(file_path: &String, msg: &str) -> Box<Error> {
+    return From::from(IOError::new(ErrorKind::InvalidData, format!("{}: {}", file_path, msg)));
+}
+
+/// Restores a tree into a new directory from a full backup, a directory written by
+/// BTree::checkpoint, and the chain of incremental backups taken after it with
+/// BTree::backup_since, applied in order. Returns the sequence number of the last
+/// change restored. The tree must use bincode for its keys & values and order keys by
+/// their Ord; use BTree::restore for other codecs or comparators.
+pub fn restore<K, V>(full_backup_dir: &String, incremental_backups: &[String], dest_dir: &String) -> Result<u64, Box<Error>>
+    where K: KeyType, V: ValueType, Bincode: Codec<K> + Codec<V>
+{
+    return restore_tree::<K,V,Bincode,Bincode,Ascending>(full_backup_dir, incremental_backups, dest_dir);
+}
+
+/// Same as restore, for a tree using the given codecs & comparator
+pub fn restore_tree<K: KeyType, V: ValueType, KC: Codec<K>, VC: Codec<V>, C: Comparator<K>>(full_backup_dir: &String, incremental_backups: &[String], dest_dir: &String) -> Result<u64, Box<Error>> {
+    let (key_size, value_size) = {
+        let full = try!(BTree::<K,V,KC,VC,C>::open_read_only(full_backup_dir, true));
+
+        try!(full.checkpoint(dest_dir));
+
+        (full.key_size(), full.value_size())
+    };
+
+    let mut btree = try!(BTree::<K,V,KC,VC,C>::open(dest_dir, key_size, value_size));
+
+    for backup in incremental_backups {
+        try!(btree.apply_backup(backup));
+    }
+
+    return Ok(btree.last_sequence());
+}
+
+
+#[cfg(test)]
+mod tests {
+    use tests::gen_temp_name;
+    use backup::restore;
+    use ::{BTree, Options};
+    use std::fs;
+
+    #[test]
+    fn restores_incremental_chain() {
+        let dir_path = gen_temp_name();
+        let full_path = gen_temp_name();
+        let restore_path = gen_temp_name();
+        let backups = vec![gen_temp_name(), gen_temp_name()];
+
+        let options = Options{archive_wal: true, ..Options::default()};
+        let mut btree = BTree::<u32, u32>::open_with_options(&dir_path, 4, 4, options).unwrap();
+
+        for i in 0..5 {
+            btree.insert(i, i).unwrap();
+        }
+
+        assert_eq!(btree.checkpoint(&full_path).unwrap(), 5);
+
+        for i in 5..8 {
+            btree.insert(i, i).unwrap();
+        }
+
+        btree.compact().unwrap();
+        btree.delete(1, 1).unwrap();
+
+        let info = btree.backup_since(5, &backups[0]).unwrap();
+
+        assert_eq!((info.since, info.last_sequence), (5, 9));
+
+        // enough inserts to compact again
+        for i in 8..1200 {
+            btree.insert(i, i).unwrap();
+        }
+
+        btree.backup_since(9, &backups[1]).unwrap();
+
+        assert_eq!(restore::<u32, u32>(&full_path, &backups, &restore_path).unwrap(), btree.last_sequence());
+        assert_eq!(BTree::<u32, u32>::open(&restore_path, 4, 4).unwrap().iter().unwrap().collect::<Result<Vec<_>, _>>().unwrap(), btree.iter().unwrap().collect::<Result<Vec<_>, _>>().unwrap());
+
+        // the second backup doesn't follow on from the full one
+        fs::remove_dir_all(&restore_path).unwrap();
+        assert!(restore::<u32, u32>(&full_path, &backups[1..], &restore_path).is_err());
+
+        // once purged, older changes can't be backed up
+        btree.purge_archive(9).unwrap();
+
+        assert!(btree.backup_since(0, &backups[0]).is_err());
+        assert!(btree.backup_since(9, &backups[0]).is_ok());
+
+        fs::remove_dir_all(&dir_path).unwrap();
+        fs::remove_dir_all(&full_path).unwrap();
+        fs::remove_dir_all(&restore_path).unwrap();
+
+        for backup in backups {
+            fs::remove_file(&backup).unwrap();
+        }
+    }
+}
@@ -0,0 +1,451 @@
+extern crate btree;
+extern crate getopts;
+extern crate rustc_serialize;
+
+use btree::{BTree, ExportFormat, KeyType, ValueType, TreeStats, VerifyReport, WalRecord};
+use rustc_serialize::{Encodable, Decodable};
+use rustc_serialize::json::Json;
+
+use std::cmp;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::File;
+use std::error::Error;
+use std::io::{BufReader, ErrorKind, Write};
+use std::io::Error as IOError;
+use std::ops::Bound;
+use std::path::Path;
+use std::process;
+
+const DEFAULT_KEY_SIZE: usize = 64;
+const DEFAULT_VALUE_SIZE: usize = 256;
+
+const COMMANDS: &'static str = "
+Commands:
+    stats PATH              Show the sizes & counters of the tree
+    dump PATH               Print every record
+    get PATH KEY            Print the values of a key
+    put PATH KEY VALUE      Insert a record
+    delete PATH KEY VALUE   Delete a record
+    scan PATH [FROM [TO]]   Print the records with FROM <= key < TO
+    verify PATH             Check every block of the tree, exiting with 1 if it is damaged
+    compact PATH            Merge the WAL into a new tree file
+    wal-dump PATH           Print the records in the WAL with their sequence numbers
+    export PATH [FILE]      Write every record to FILE, or stdout, as JSON Lines or CSV
+    import PATH [FILE]      Add the records exported to FILE, or read from stdin
+    checkpoint PATH DEST    Copy the tree to the new directory DEST, printing its sequence number
+
+PATH is the directory of a tree. export & import use JSON Lines unless given
+--format csv. Commands that only read the tree don't take its
+lock, so they can be run while another process has the tree open.";
+
+/// How keys & values are given on the command line & printed
+trait Field: KeyType + ValueType + Encodable + Decodable {
+    fn parse(s: &str) -> Result<Self, Box<Error>>;
+    fn to_text(&self) -> String;
+
+    fn to_json(&self) -> Json {
+        return Json::String(self.to_text());
+    }
+}
+
+impl Field for String {
+    fn parse(s: &str) -> Result<String, Box<Error>> {
+        return Ok(s.to_owned());
+    }
+
+    fn to_text(&self) -> String {
+        return self.clone();
+    }
+}
+
+/// Bytes are given & printed in hex
+impl Field for Vec<u8> {
+    fn parse(s: &str) -> Result<Vec<u8>, Box<Error>> {
+        if s.len() % 2 != 0 || !s.chars().all(|c| c.is_digit(16)) {
+            return Err(From::from(IOError::new(ErrorKind::InvalidInput, format!("{} is not a hex string", s))));
+        }
+
+        return Ok((0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i+2], 16).unwrap()).collect());
+    }
+
+    fn to_text(&self) -> String {
+        return self.iter().map(|b| format!("{:02x}", b)).collect();
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Table,
+    Json,
+    Csv,
+}
+
+struct Command {
+    name: String,
+    path: String,
+    args: Vec<String>,
+    format: Format,
+    key_size: usize,    // used when creating a tree
+    value_size: usize,
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let mut opts = getopts::Options::new();
+
+    opts.optopt("f", "format", "output format: table (default) or json, or csv for export & import", "FORMAT");
+    opts.optflag("b", "bytes", "keys & values are hex encoded bytes instead of strings");
+    opts.optopt("k", "key-size", &format!("the key size when creating a tree (default {})", DEFAULT_KEY_SIZE), "SIZE");
+    opts.optopt("v", "value-size", &format!("the value size when creating a tree (default {})", DEFAULT_VALUE_SIZE), "SIZE");
+    opts.optflag("h", "help", "print this help");
+
+    let usage = opts.usage("Usage: btree [options] COMMAND PATH [ARGS]") + COMMANDS;
+
+    let matches = match opts.parse(&args[1..]) {
+        Ok(matches) => matches,
+        Err(e) => fail(&format!("{}\n\n{}", e, usage))
+    };
+
+    if matches.opt_present("h") {
+        println!("{}", usage);
+        return;
+    }
+
+    if matches.free.len() < 2 {
+        fail(&usage);
+    }
+
+    let format = match matches.opt_str("f").as_ref().map(|f| f.as_str()) {
+        None | Some("table") => Format::Table,
+        Some("json") => Format::Json,
+        Some("csv") => Format::Csv,
+        Some(f) => fail(&format!("Unknown format {}", f))
+    };
+
+    let parse_size = |name: &str, default: usize| -> usize {
+        match matches.opt_str(name) {
+            None => default,
+            Some(size) => size.parse().unwrap_or_else(|_| fail(&format!("Invalid size {}", size)))
+        }
+    };
+
+    let command = Command{name: matches.free[0].to_owned(),
+                          path: matches.free[1].to_owned(),
+                          args: matches.free[2..].to_vec(),
+                          format: format,
+                          key_size: parse_size("k", DEFAULT_KEY_SIZE),
+                          value_size: parse_size("v", DEFAULT_VALUE_SIZE)};
+
+    let result = if matches.opt_present("b") {
+        run::<Vec<u8>>(&command)
+    } else {
+        run::<String>(&command)
+    };
+
+    match result {
+        Ok(code) => process::exit(code),
+        Err(e) => fail(&e.to_string())
+    }
+}
+
+fn fail(msg: &str) -> ! {
+    writeln!(&mut std::io::stderr(), "btree: {}", msg).unwrap();
+    process::exit(2);
+}
+
+/// Runs the command, returning the exit code
+fn run<T: Field>(cmd: &Command) -> Result<i32, Box<Error>> {
+    let export_format = if cmd.format == Format::Csv { ExportFormat::Csv } else { ExportFormat::JsonLines };
+
+    if cmd.format == Format::Csv && cmd.name != "export" && cmd.name != "import" {
+        return Err(From::from(IOError::new(ErrorKind::InvalidInput, "csv is only supported by export & import")));
+    }
+
+    match (cmd.name.as_str(), cmd.args.len()) {
+        ("stats", 0) => {
+            print_stats(&try!(try!(open_read_only::<T>(cmd)).stats()), cmd.format);
+        },
+        ("dump", 0) => {
+            try!(print_records(try!(try!(open_read_only::<T>(cmd)).iter()), cmd.format));
+        },
+        ("get", 1) => {
+            let key = try!(T::parse(&cmd.args[0]));
+            let values: Vec<T> = try!(try!(open_read_only::<T>(cmd)).get(&key)).collect();
+
+            match cmd.format {
+                Format::Table | Format::Csv => print_table(&["VALUE"], values.iter().map(|v| vec![v.to_text()]).collect()),
+                Format::Json => {
+                    let mut obj = BTreeMap::new();
+
+                    obj.insert(String::from("key"), key.to_json());
+                    obj.insert(String::from("values"), Json::Array(values.iter().map(|v| v.to_json()).collect()));
+
+                    println!("{}", Json::Object(obj));
+                }
+            }
+        },
+        ("put", 2) => {
+            try!(try!(open_writable::<T>(cmd)).insert(try!(T::parse(&cmd.args[0])), try!(T::parse(&cmd.args[1]))));
+        },
+        ("delete", 2) => {
+            try!(try!(open_writable::<T>(cmd)).delete(try!(T::parse(&cmd.args[0])), try!(T::parse(&cmd.args[1]))));
+        },
+        ("scan", n) if n <= 2 => {
+            let from = match cmd.args.get(0) { Some(arg) => Some(try!(T::parse(arg))), None => None };
+            let to = match cmd.args.get(1) { Some(arg) => Some(try!(T::parse(arg))), None => None };
+            let btree = try!(open_read_only::<T>(cmd));
+
+            // an error is passed on to be reported, wherever it comes
+            let records = try!(btree.iter())
+                                    .skip_while(|record| record.as_ref().ok().map_or(false, |&(ref k, _)| from.as_ref().map_or(false, |from| k < from)))
+                                    .take_while(|record| record.as_ref().ok().map_or(true, |&(ref k, _)| to.as_ref().map_or(true, |to| k < to)));
+
+            try!(print_records(records, cmd.format));
+        },
+        ("verify", 0) => {
+            let report = try!(try!(open_read_only::<T>(cmd)).verify());
+
+            print_report(&report, cmd.format);
+
+            return Ok(if report.is_ok() { 0 } else { 1 });
+        },
+        ("compact", 0) => {
+            try!(try!(open_writable::<T>(cmd)).compact());
+        },
+        ("wal-dump", 0) => {
+            print_wal(try!(try!(open_read_only::<T>(cmd)).wal_records()), cmd.format);
+        },
+        ("checkpoint", 1) => {
+            println!("{}", try!(try!(open_read_only::<T>(cmd)).checkpoint(&cmd.args[0])));
+        },
+        ("export", n) if n <= 1 => {
+            let btree = try!(open_read_only::<T>(cmd));
+
+            match cmd.args.get(0) {
+                Some(file) => try!(btree.export(try!(File::create(file)), export_format)),
+                None => try!(btree.export(std::io::stdout(), export_format))
+            };
+        },
+        ("import", n) if n <= 1 => {
+            let mut btree = try!(open_writable::<T>(cmd));
+
+            match cmd.args.get(0) {
+                Some(file) => { try!(btree.import(BufReader::new(try!(File::open(file))), export_format)); },
+                None => {
+                    let stdin = std::io::stdin();
+
+                    try!(btree.import(stdin.lock(), export_format));
+                }
+            }
+        },
+        _ => return Err(From::from(IOError::new(ErrorKind::InvalidInput, format!("Unknown command or wrong arguments: {} (see --help)", cmd.name))))
+    }
+
+    return Ok(0);
+}
+
+fn check_exists(path: &str) -> Result<(), Box<Error>> {
+    if !Path::new(path).join("MANIFEST").exists() {
+        return Err(From::from(IOError::new(ErrorKind::NotFound, format!("{} is not a tree directory", path))));
+    }
+
+    Ok( () )
+}
+
+/// Opens an existing tree without taking its lock
+fn open_read_only<T: Field>(cmd: &Command) -> Result<BTree<T,T>, Box<Error>> {
+    try!(check_exists(&cmd.path));
+
+    return BTree::open_read_only(&cmd.path, true);
+}
+
+/// Opens a tree for writing, creating it with the key & value sizes from the command line if needed
+fn open_writable<T: Field>(cmd: &Command) -> Result<BTree<T,T>, Box<Error>> {
+    // an existing tree has to be opened with the sizes it was created with
+    let (key_size, value_size) = match check_exists(&cmd.path) {
+        Ok(_) => {
+            let btree = try!(BTree::<T,T>::open_read_only(&cmd.path, false));
+
+            (btree.key_size(), btree.value_size())
+        },
+        Err(_) => (cmd.key_size, cmd.value_size)
+    };
+
+    return BTree::open(&cmd.path, key_size, value_size);
+}
+
+/// Prints the rows with each column padded to its widest cell
+fn print_table(headers: &[&str], rows: Vec<Vec<String>>) {
+    let mut widths: Vec<usize> = headers.iter().map(|h| h.len()).collect();
+
+    for row in &rows {
+        for (i, cell) in row.iter().enumerate() {
+            widths[i] = cmp::max(widths[i], cell.chars().count());
+        }
+    }
+
+    let print_row = |row: Vec<String>| {
+        let cells: Vec<String> = row.iter().enumerate().map(|(i, cell)| {
+            if i + 1 == row.len() {
+                cell.to_owned()
+            } else {
+                format!("{:width$}", cell, width = widths[i])
+            }
+        }).collect();
+
+        println!("{}", cells.join("  "));
+    };
+
+    print_row(headers.iter().map(|h| h.to_string()).collect());
+
+    for row in rows {
+        print_row(row);
+    }
+}
+
+/// Prints records as a table, or as JSON Lines with one object per record
+/// Prints the records, failing at the first that is an error. A table is only printed
+/// once every record has been read.
+fn print_records<T: Field, I: Iterator<Item=Result<(T,T), Box<Error>>>>(records: I, format: Format) -> Result<(), Box<Error>> {
+    match format {
+        Format::Table | Format::Csv => print_table(&["KEY", "VALUE"], try!(records.map(|record| record.map(|(k, v)| vec![k.to_text(), v.to_text()])).collect())),
+        Format::Json => {
+            for record in records {
+                let (key, value) = try!(record);
+                let mut obj = BTreeMap::new();
+
+                obj.insert(String::from("key"), key.to_json());
+                obj.insert(String::from("value"), value.to_json());
+
+                println!("{}", Json::Object(obj));
+            }
+        }
+    }
+
+    Ok( () )
+}
+
+/// Shows a bound of a deleted range in interval notation, such as "[key" or "key)"
+fn bound_text<T: Field>(bound: &Bound<T>, start: bool) -> String {
+    match (bound, start) {
+        (&Bound::Included(ref key), true) => format!("[{}", key.to_text()),
+        (&Bound::Excluded(ref key), true) => format!("({}", key.to_text()),
+        (&Bound::Included(ref key), false) => format!("{}]", key.to_text()),
+        (&Bound::Excluded(ref key), false) => format!("{})", key.to_text()),
+        (&Bound::Unbounded, _) => String::from("*")
+    }
+}
+
+fn print_wal<T: Field>(records: Vec<(u64, WalRecord<T,T>)>, format: Format) {
+    // the key & value, or merge operand, as text & JSON; a replace shows the number of values that follow
+    // it, and a delete range its start & end bounds
+    let rows: Vec<(u64, &str, (String, Json), (String, Json))> = records.iter().map(|&(seq, ref record)| {
+        match *record {
+            WalRecord::Insert(ref key, ref value) => (seq, "insert", (key.to_text(), key.to_json()), (value.to_text(), value.to_json())),
+            WalRecord::Delete(ref key, ref value) => (seq, "delete", (key.to_text(), key.to_json()), (value.to_text(), value.to_json())),
+            WalRecord::Merge(ref key, ref operand) => (seq, "merge", (key.to_text(), key.to_json()), (operand.to_text(), operand.to_json())),
+            WalRecord::DeleteKey(ref key) => (seq, "delete_key", (key.to_text(), key.to_json()), (String::new(), Json::Null)),
+            WalRecord::Replace(ref key, count) => (seq, "replace", (key.to_text(), key.to_json()), (count.to_string(), Json::U64(count as u64))),
+            WalRecord::DeleteRange(ref start, ref end) => {
+                let (start, end) = (bound_text(start, true), bound_text(end, false));
+
+                (seq, "delete_range", (start.clone(), Json::String(start)), (end.clone(), Json::String(end)))
+            }
+        }
+    }).collect();
+
+    match format {
+        Format::Table | Format::Csv => print_table(&["SEQUENCE", "OP", "KEY", "VALUE"],
+                                     rows.into_iter().map(|(seq, op, k, v)| vec![seq.to_string(), op.to_owned(), k.0, v.0]).collect()),
+        Format::Json => {
+            for (seq, op, key, value) in rows {
+                let mut obj = BTreeMap::new();
+
+                obj.insert(String::from("sequence"), Json::U64(seq));
+                obj.insert(String::from("op"), Json::String(op.to_owned()));
+                obj.insert(String::from("key"), key.1);
+                obj.insert(String::from("value"), value.1);
+
+                println!("{}", Json::Object(obj));
+            }
+        }
+    }
+}
+
+fn print_stats(stats: &TreeStats, format: Format) {
+    let mut rows = vec![("key_size", Json::U64(stats.key_size as u64)),
+                        ("value_size", Json::U64(stats.value_size as u64)),
+                        ("last_sequence", Json::U64(stats.last_sequence)),
+                        ("tree_files", Json::Array(stats.tree_files.iter().map(|f| Json::String(f.to_owned())).collect())),
+                        ("disk_records", Json::U64(stats.disk_records)),
+                        ("disk_bytes", Json::U64(stats.disk_bytes)),
+                        ("memory_records", Json::U64(stats.memory_records as u64)),
+                        ("memory_deletes", Json::U64(stats.memory_deletes as u64)),
+                        ("wal_bytes", Json::U64(stats.wal_bytes)),
+                        ("bloom_lookups", Json::U64(stats.bloom_filter.lookups)),
+                        ("bloom_negatives", Json::U64(stats.bloom_filter.negatives)),
+                        ("bloom_false_positives", Json::U64(stats.bloom_filter.false_positives)),
+                        ("compression_raw_bytes", Json::U64(stats.compression.raw_bytes)),
+                        ("compression_stored_bytes", Json::U64(stats.compression.stored_bytes)),
+                        ("compression_ratio", Json::F64(stats.compression.ratio()))];
+
+    if let Some(ref cache) = stats.block_cache {
+        rows.push(("block_cache_hits", Json::U64(cache.hits)));
+        rows.push(("block_cache_misses", Json::U64(cache.misses)));
+        rows.push(("block_cache_bytes", Json::U64(cache.bytes as u64)));
+        rows.push(("block_cache_capacity", Json::U64(cache.capacity as u64)));
+    }
+
+    match format {
+        Format::Table | Format::Csv => {
+            print_table(&["STAT", "VALUE"], rows.into_iter().map(|(name, value)| {
+                let value = match value {
+                    Json::Array(items) => items.iter().map(|i| i.as_string().unwrap_or("").to_owned()).collect::<Vec<_>>().join(", "),
+                    Json::F64(f) => format!("{:.2}", f),
+                    value => value.to_string()
+                };
+
+                vec![name.to_owned(), value]
+            }).collect());
+        },
+        Format::Json => println!("{}", Json::Object(rows.into_iter().map(|(name, value)| (name.to_owned(), value)).collect()))
+    }
+}
+
+fn print_report(report: &VerifyReport, format: Format) {
+    match format {
+        Format::Table | Format::Csv => {
+            println!("{} files, {} blocks, {} records checked: {}", report.files, report.blocks, report.records,
+                     if report.is_ok() { "OK" } else { "DAMAGED" });
+
+            if !report.is_ok() {
+                println!("");
+                print_table(&["FILE", "OFFSET", "KIND", "DETAIL"],
+                            report.damage.iter().map(|d| vec![d.file.to_owned(), d.offset.to_string(), format!("{:?}", d.kind), d.detail.to_owned()]).collect());
+            }
+        },
+        Format::Json => {
+            let damage = report.damage.iter().map(|d| {
+                let mut obj = BTreeMap::new();
+
+                obj.insert(String::from("file"), Json::String(d.file.to_owned()));
+                obj.insert(String::from("offset"), Json::U64(d.offset));
+                obj.insert(String::from("kind"), Json::String(format!("{:?}", d.kind)));
+                obj.insert(String::from("detail"), Json::String(d.detail.to_owned()));
+
+                Json::Object(obj)
+            }).collect();
+
+            let mut obj = BTreeMap::new();
+
+            obj.insert(String::from("files"), Json::U64(report.files as u64));
+            obj.insert(String::from("blocks"), Json::U64(report.blocks));
+            obj.insert(String::from("records"), Json::U64(report.records));
+            obj.insert(String::from("ok"), Json::Boolean(report.is_ok()));
+            obj.insert(String::from("damage"), Json::Array(damage));
+
+            println!("{}", Json::Object(obj));
+        }
+    }
+}
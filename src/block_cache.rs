@@ -0,0 +1,157 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+// each open tree file gets its own id so blocks from different files never collide
+static NEXT_FILE_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+
+// rough per-block bookkeeping cost counted against the budget
+const BLOCK_OVERHEAD: usize = 64;
+
+/// Counts of how the cache has been used
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct BlockCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub blocks: usize,    // the number of blocks currently cached
+    pub bytes: usize,     // the bytes currently charged against the budget
+    pub capacity: usize,  // the budget in bytes
+}
+
+/// A LRU cache of the blocks (leaves & internal nodes) read from tree files, held
+/// uncompressed along with their size in the file. A single
+/// cache is shared by all of the tree files of a BTree, and can be shared between
+/// BTrees through Options.
+#[derive(Debug)]
+pub struct BlockCache {
+    inner: Mutex<CacheInner>,
+}
+
+#[derive(Debug)]
+struct CacheInner {
+    blocks: HashMap<(usize, u64), (Arc<Vec<u8>>, u64, u64)>,  // (file id, offset) -> (block, size in file, last use)
+    lru: BTreeMap<u64, (usize, u64)>,                          // last use -> (file id, offset)
+    tick: u64,
+    stats: BlockCacheStats,
+}
+
+impl BlockCache {
+    /// Creates a cache holding at most capacity bytes of blocks
+    pub fn new(capacity: usize) -> BlockCache {
+        let stats = BlockCacheStats{capacity: capacity, ..BlockCacheStats::default()};
+
+        BlockCache{inner: Mutex::new(CacheInner{blocks: HashMap::new(), lru: BTreeMap::new(), tick: 0, stats: stats})}
+    }
+
+    /// Returns a new id for a tree file to use when caching its blocks
+    pub fn new_file_id() -> usize {
+        return NEXT_FILE_ID.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Returns the block & its size in the file if it is cached
+    pub fn get(&self, file_id: usize, offset: u64) -> Option<(Arc<Vec<u8>>, u64)> {
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+
+        inner.tick += 1;
+
+        match inner.blocks.get_mut(&(file_id, offset)) {
+            Some(&mut (ref block, stored_len, ref mut last_use)) => {
+                inner.lru.remove(last_use);
+                inner.lru.insert(inner.tick, (file_id, offset));
+                *last_use = inner.tick;
+                inner.stats.hits += 1;
+
+                Some((block.clone(), stored_len))
+            },
+            None => {
+                inner.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Adds a block, evicting the least recently used blocks to stay within the budget
+    pub fn insert(&self, file_id: usize, offset: u64, block: Arc<Vec<u8>>, stored_len: u64) {
+        let charge = block.len() + BLOCK_OVERHEAD;
+        let mut inner = self.inner.lock().unwrap();
+
+        if charge > inner.stats.capacity {
+            return;
+        }
+
+        inner.tick += 1;
+
+        let tick = inner.tick;
+
+        if let Some((old_block, _, last_use)) = inner.blocks.insert((file_id, offset), (block, stored_len, tick)) {
+            inner.lru.remove(&last_use);
+            inner.stats.bytes -= old_block.len() + BLOCK_OVERHEAD;
+        }
+
+        inner.lru.insert(tick, (file_id, offset));
+        inner.stats.bytes += charge;
+
+        while inner.stats.bytes > inner.stats.capacity {
+            let oldest = *inner.lru.keys().next().unwrap();
+            let key = inner.lru.remove(&oldest).unwrap();
+            let (old_block, _, _) = inner.blocks.remove(&key).unwrap();
+
+            inner.stats.bytes -= old_block.len() + BLOCK_OVERHEAD;
+            inner.stats.evictions += 1;
+        }
+
+        inner.stats.blocks = inner.blocks.len();
+    }
+
+    pub fn stats(&self) -> BlockCacheStats {
+        return self.inner.lock().unwrap().stats;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use block_cache::{BlockCache, BLOCK_OVERHEAD};
+    use std::sync::Arc;
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let cache = BlockCache::new(3 * (100 + BLOCK_OVERHEAD));
+        let file_id = BlockCache::new_file_id();
+
+        for offset in 0..3 {
+            cache.insert(file_id, offset, Arc::new(vec![offset as u8; 100]), 50);
+        }
+
+        // touch the first block so the second is the oldest
+        assert_eq!(cache.get(file_id, 0).unwrap(), (Arc::new(vec![0; 100]), 50));
+
+        cache.insert(file_id, 3, Arc::new(vec![3; 100]), 50);
+
+        assert!(cache.get(file_id, 1).is_none());
+        assert!(cache.get(file_id, 0).is_some());
+        assert!(cache.get(file_id, 3).is_some());
+        assert!(cache.get(BlockCache::new_file_id(), 3).is_none());
+
+        let stats = cache.stats();
+
+        assert_eq!(stats.hits, 3);
+        assert_eq!(stats.misses, 2);
+        assert_eq!(stats.evictions, 1);
+        assert_eq!(stats.blocks, 3);
+        assert_eq!(stats.bytes, 3 * (100 + BLOCK_OVERHEAD));
+    }
+
+    #[test]
+    fn skips_blocks_larger_than_capacity() {
+        let cache = BlockCache::new(100);
+        let file_id = BlockCache::new_file_id();
+
+        cache.insert(file_id, 0, Arc::new(vec![0; 200]), 200);
+
+        assert!(cache.get(file_id, 0).is_none());
+        assert_eq!(cache.stats().bytes, 0);
+    }
+}
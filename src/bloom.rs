@@ -0,0 +1,115 @@
+use std::cmp;
+
+/// A bloom filter over the keys in a tree file. The on-disk format is the bit
+/// array followed by a single byte holding the number of hash functions.
+pub struct BloomFilter {
+    bits: Vec<u8>,
+    num_hashes: u8,
+}
+
+/// Counts of how the bloom filter of a tree file has answered lookups
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct BloomFilterStats {
+    pub lookups: u64,          // the number of times the filter was consulted
+    pub negatives: u64,        // lookups where the filter ruled the key out without touching the tree
+    pub false_positives: u64,  // lookups where the filter allowed the key but it wasn't in the tree
+}
+
+impl BloomFilter {
+    /// Creates an empty filter sized for the given number of keys
+    pub fn new(num_keys: usize, bits_per_key: usize) -> BloomFilter {
+        // small filters have a very high false positive rate, so use at least 64 bits
+        let num_bits = cmp::max(num_keys * bits_per_key, 64);
+
+        // k = ln(2) * bits per key minimizes the false positive rate
+        let num_hashes = cmp::min(cmp::max((bits_per_key as f64 * 0.69) as u8, 1), 30);
+
+        return BloomFilter{bits: vec![0; (num_bits + 7) / 8], num_hashes: num_hashes};
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<BloomFilter> {
+        if bytes.len() < 2 {
+            return None;
+        }
+
+        let (bits, num_hashes) = bytes.split_at(bytes.len() - 1);
+
+        return Some(BloomFilter{bits: bits.to_vec(), num_hashes: num_hashes[0]});
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.bits.clone();
+
+        bytes.push(self.num_hashes);
+
+        return bytes;
+    }
+
+    /// Builds a filter from the hashes of the keys, computed with BloomFilter::hash
+    pub fn from_hashes(hashes: &[u64], bits_per_key: usize) -> BloomFilter {
+        let mut filter = BloomFilter::new(hashes.len(), bits_per_key);
+
+        for hash in hashes {
+            filter.add_hash(*hash);
+        }
+
+        return filter;
+    }
+
+    fn add_hash(&mut self, hash: u64) {
+        let num_bits = (self.bits.len() * 8) as u64;
+
+        for bit in BloomFilter::bit_positions(hash, self.num_hashes, num_bits) {
+            self.bits[(bit / 8) as usize] |= 1 << (bit % 8);
+        }
+    }
+
+    /// Returns false if the key was definitely never added
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        let num_bits = (self.bits.len() * 8) as u64;
+
+        return BloomFilter::bit_positions(BloomFilter::hash(key), self.num_hashes, num_bits).all(|bit| self.bits[(bit / 8) as usize] & (1 << (bit % 8)) != 0);
+    }
+
+    /// Double hashing: the i-th probe is h1 + i*h2, both halves derived from one hash
+    fn bit_positions(hash: u64, num_hashes: u8, num_bits: u64) -> Box<Iterator<Item=u64>> {
+        let delta = hash.rotate_left(31);
+
+        return Box::new((0..num_hashes as u64).map(move |i| hash.wrapping_add(i.wrapping_mul(delta)) % num_bits));
+    }
+
+    /// 64-bit FNV-1a, which is stable across runs & platforms so filters can be persisted
+    pub fn hash(key: &[u8]) -> u64 {
+        let mut hash: u64 = 0xcbf29ce484222325;
+
+        for b in key {
+            hash ^= *b as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+
+        return hash;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use bloom::BloomFilter;
+
+    #[test]
+    fn no_false_negatives() {
+        let hashes: Vec<u64> = (0..1000u32).map(|i| BloomFilter::hash(format!("key{}", i).as_bytes())).collect();
+        let filter = BloomFilter::from_hashes(&hashes, 10);
+
+        let filter = BloomFilter::from_bytes(&filter.to_bytes()).unwrap();
+
+        for i in 0..1000u32 {
+            assert!(filter.may_contain(format!("key{}", i).as_bytes()));
+        }
+
+        // with 10 bits per key we expect around 1% false positives
+        let false_positives = (1000..11000u32).filter(|i| filter.may_contain(format!("key{}", i).as_bytes())).count();
+
+        assert!(false_positives < 300, "{} false positives", false_positives);
+    }
+}
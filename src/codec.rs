@@ -0,0 +1,149 @@
+use bincode::SizeLimit;
+use bincode::rustc_serialize::{encode_into, decode};
+use bincode_serde;
+use rustc_serialize::{Encodable, Decodable};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use std::error::Error;
+use std::io::ErrorKind;
+use std::io::Error as IOError;
+
+/// Converts keys or values to & from the bytes stored in the WAL & tree files. A tree
+/// is given a codec for its keys and one for its values when it is created, and their
+/// names are recorded in the manifest so it can't be opened with different ones.
+pub trait Codec<T> {
+    /// The name recorded in the manifest
+    fn name() -> &'static str;
+
+    /// True if encoded items sort in the same order as the items themselves, letting
+    /// the tree compare encoded keys as bytes instead of decoding them
+    fn preserves_order() -> bool {
+        return false;
+    }
+
+    /// Appends the encoding of the item to the buffer
+    fn encode(item: &T, buff: &mut Vec<u8>) -> Result<(), Box<Error>>;
+
+    /// Decodes an item from the whole of the buffer
+    fn decode(buff: &[u8]) -> Result<T, Box<Error>>;
+}
+
+/// Bincode for types implementing rustc-serialize's Encodable & Decodable; the default
+pub struct Bincode;
+
+/// Bincode for types implementing serde's Serialize & Deserialize
+pub struct SerdeBincode;
+
+/// Stores byte vectors, and strings as UTF-8, as they are
+pub struct Raw;
+
+impl <T: Encodable + Decodable> Codec<T> for Bincode {
+    fn name() -> &'static str {
+        return "bincode";
+    }
+
+    fn encode(item: &T, buff: &mut Vec<u8>) -> Result<(), Box<Error>> {
+        return Ok(try!(encode_into(item, buff, SizeLimit::Infinite)));
+    }
+
+    fn decode(buff: &[u8]) -> Result<T, Box<Error>> {
+        return Ok(try!(decode(buff)));
+    }
+}
+
+impl <T: Serialize + DeserializeOwned> Codec<T> for SerdeBincode {
+    fn name() -> &'static str {
+        return "serde-bincode";
+    }
+
+    fn encode(item: &T, buff: &mut Vec<u8>) -> Result<(), Box<Error>> {
+        return Ok(try!(bincode_serde::serialize_into(buff, item)));
+    }
+
+    fn decode(buff: &[u8]) -> Result<T, Box<Error>> {
+        return Ok(try!(bincode_serde::deserialize(buff)));
+    }
+}
+
+impl Codec<Vec<u8>> for Raw {
+    fn name() -> &'static str {
+        return "raw";
+    }
+
+    fn preserves_order() -> bool {
+        return true;
+    }
+
+    fn encode(item: &Vec<u8>, buff: &mut Vec<u8>) -> Result<(), Box<Error>> {
+        buff.extend_from_slice(item);
+
+        Ok( () )
+    }
+
+    fn decode(buff: &[u8]) -> Result<Vec<u8>, Box<Error>> {
+        return Ok(buff.to_vec());
+    }
+}
+
+impl Codec<String> for Raw {
+    fn name() -> &'static str {
+        return "raw";
+    }
+
+    // UTF-8 sorts the same way as the chars it encodes
+    fn preserves_order() -> bool {
+        return true;
+    }
+
+    fn encode(item: &String, buff: &mut Vec<u8>) -> Result<(), Box<Error>> {
+        buff.extend_from_slice(item.as_bytes());
+
+        Ok( () )
+    }
+
+    fn decode(buff: &[u8]) -> Result<String, Box<Error>> {
+        match String::from_utf8(buff.to_vec()) {
+            Ok(s) => Ok(s),
+            Err(_) => Err(From::from(IOError::new(ErrorKind::InvalidData, "String is not valid UTF-8")))
+        }
+    }
+}
+
+/// Encodes an item into a new buffer
+pub fn encode_to_vec<T, C: Codec<T>>(item: &T) -> Result<Vec<u8>, Box<Error>> {
+    let mut buff = Vec::new();
+
+    try!(C::encode(item, &mut buff));
+
+    return Ok(buff);
+}
+
+
+#[cfg(test)]
+mod tests {
+    use codec::{Codec, Bincode, SerdeBincode, Raw, encode_to_vec};
+
+    // only implements serde
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Point {
+        x: i32,
+        y: i32,
+    }
+
+    #[test]
+    fn round_trips() {
+        let buff = encode_to_vec::<_, Bincode>(&(String::from("key"), 7u32)).unwrap();
+
+        assert_eq!(<Bincode as Codec<(String, u32)>>::decode(&buff).unwrap(), (String::from("key"), 7));
+
+        let buff = encode_to_vec::<_, SerdeBincode>(&Point{x: 1, y: -2}).unwrap();
+
+        assert_eq!(<SerdeBincode as Codec<Point>>::decode(&buff).unwrap(), Point{x: 1, y: -2});
+
+        // raw bytes are stored without a length
+        assert_eq!(encode_to_vec::<_, Raw>(&String::from("key")).unwrap(), b"key".to_vec());
+        assert_eq!(<Raw as Codec<Vec<u8>>>::decode(&[1, 2, 3]).unwrap(), vec![1, 2, 3]);
+        assert!(<Raw as Codec<String>>::decode(&[0xff]).is_err());
+    }
+}
@@ -0,0 +1,239 @@
+use std::cmp::Ordering;
+use std::iter::Peekable;
+use std::marker::PhantomData;
+use std::ops::Bound;
+use std::str::Chars;
+
+/// Orders the keys of a tree. A tree is given its comparator when it is created, and
+/// its name is recorded in the header of every tree file so the file can't be read
+/// with a different one. Keys the comparator finds equal are the same key.
+pub trait Comparator<K> {
+    /// The name recorded in tree files, at most 32 bytes
+    fn name() -> &'static str;
+
+    fn compare(a: &K, b: &K) -> Ordering;
+
+    /// True if keys are ordered by their Ord, so keys stored with an order preserving
+    /// codec can be compared as bytes
+    fn orders_like_ord() -> bool {
+        return false;
+    }
+
+    /// True if only identical keys compare equal. Otherwise a bloom filter of the
+    /// encoded keys could rule out a key equal to one in the file, so none is written.
+    fn equal_only_if_identical() -> bool {
+        return true;
+    }
+}
+
+/// Orders keys by their Ord; the default
+pub struct Ascending;
+
+/// Orders keys by their Ord, largest first
+pub struct Descending;
+
+/// Orders strings ignoring case, so keys differing only in case are the same key
+pub struct CaseInsensitive;
+
+/// Orders strings comparing each run of digits as a number, so "item9" comes before "item10"
+pub struct Numeric;
+
+impl <K: Ord> Comparator<K> for Ascending {
+    fn name() -> &'static str {
+        return "ascending";
+    }
+
+    fn compare(a: &K, b: &K) -> Ordering {
+        return a.cmp(b);
+    }
+
+    fn orders_like_ord() -> bool {
+        return true;
+    }
+}
+
+impl <K: Ord> Comparator<K> for Descending {
+    fn name() -> &'static str {
+        return "descending";
+    }
+
+    fn compare(a: &K, b: &K) -> Ordering {
+        return b.cmp(a);
+    }
+}
+
+impl Comparator<String> for CaseInsensitive {
+    fn name() -> &'static str {
+        return "case-insensitive";
+    }
+
+    fn compare(a: &String, b: &String) -> Ordering {
+        return a.chars().flat_map(char::to_lowercase).cmp(b.chars().flat_map(char::to_lowercase));
+    }
+
+    fn equal_only_if_identical() -> bool {
+        return false;
+    }
+}
+
+/// Takes the run of digits at the front of the iterator, without leading zeros
+fn take_number(chars: &mut Peekable<Chars>) -> String {
+    let mut number = String::new();
+
+    while let Some(&c) = chars.peek() {
+        if !c.is_digit(10) {
+            break;
+        }
+
+        if c != '0' || !number.is_empty() {
+            number.push(c);
+        }
+
+        chars.next();
+    }
+
+    return number;
+}
+
+impl Comparator<String> for Numeric {
+    fn name() -> &'static str {
+        return "numeric";
+    }
+
+    fn compare(a: &String, b: &String) -> Ordering {
+        let mut a_chars = a.chars().peekable();
+        let mut b_chars = b.chars().peekable();
+
+        loop {
+            let (a_char, b_char) = match (a_chars.peek().cloned(), b_chars.peek().cloned()) {
+                (Some(a_char), Some(b_char)) => (a_char, b_char),
+                (a_char, b_char) => {
+                    let order = a_char.is_some().cmp(&b_char.is_some());
+
+                    // equal numbers written differently, like "01" & "1", are still different keys
+                    return if order == Ordering::Equal { a.cmp(b) } else { order };
+                }
+            };
+
+            let order = if a_char.is_digit(10) && b_char.is_digit(10) {
+                let a_number = take_number(&mut a_chars);
+                let b_number = take_number(&mut b_chars);
+
+                // without leading zeros the longer number is the larger
+                a_number.len().cmp(&b_number.len()).then(a_number.cmp(&b_number))
+            } else {
+                a_chars.next();
+                b_chars.next();
+
+                a_char.cmp(&b_char)
+            };
+
+            if order != Ordering::Equal {
+                return order;
+            }
+        }
+    }
+}
+
+/// Compares two records by the comparator on their keys then by their values
+pub fn compare_records<K, V: Ord, C: Comparator<K>>(a: (&K, &V), b: (&K, &V)) -> Ordering {
+    return C::compare(a.0, b.0).then_with(|| a.1.cmp(b.1));
+}
+
+/// Coalesces two neighbouring records of a sorted stream, keeping one of a repeated
+/// record. A map's records are sorted by key alone, each key's values oldest first, so
+/// the newest is kept.
+pub fn coalesce_records<K, V: Ord, C: Comparator<K>>(map: bool, a: (K, V), b: (K, V)) -> Result<(K, V), ((K, V), (K, V))> {
+    if map && C::compare(&a.0, &b.0) == Ordering::Equal {
+        return Ok(b);
+    }
+
+    if compare_records::<K,V,C>((&a.0, &a.1), (&b.0, &b.1)) == Ordering::Equal {
+        return Ok(a);
+    }
+
+    return Err((a, b));
+}
+
+/// Returns true if the key is within the range, ordering keys by the comparator
+pub fn range_contains<K, C: Comparator<K>>(range: &(Bound<K>, Bound<K>), key: &K) -> bool {
+    let after_start = match range.0 {
+        Bound::Included(ref start) => C::compare(key, start) != Ordering::Less,
+        Bound::Excluded(ref start) => C::compare(key, start) == Ordering::Greater,
+        Bound::Unbounded => true
+    };
+
+    let before_end = match range.1 {
+        Bound::Included(ref end) => C::compare(key, end) != Ordering::Greater,
+        Bound::Excluded(ref end) => C::compare(key, end) == Ordering::Less,
+        Bound::Unbounded => true
+    };
+
+    return after_start && before_end;
+}
+
+/// A key ordered by a comparator instead of its Ord, for keying a BTreeMap
+pub struct ComparedKey<K, C> {
+    pub key: K,
+    _c_marker: PhantomData<C>,
+}
+
+impl <K, C: Comparator<K>> ComparedKey<K,C> {
+    pub fn new(key: K) -> ComparedKey<K,C> {
+        return ComparedKey{key: key, _c_marker: PhantomData};
+    }
+}
+
+impl <K, C: Comparator<K>> PartialEq for ComparedKey<K,C> {
+    fn eq(&self, other: &ComparedKey<K,C>) -> bool {
+        return C::compare(&self.key, &other.key) == Ordering::Equal;
+    }
+}
+
+impl <K, C: Comparator<K>> Eq for ComparedKey<K,C> {}
+
+impl <K, C: Comparator<K>> PartialOrd for ComparedKey<K,C> {
+    fn partial_cmp(&self, other: &ComparedKey<K,C>) -> Option<Ordering> {
+        return Some(self.cmp(other));
+    }
+}
+
+impl <K, C: Comparator<K>> Ord for ComparedKey<K,C> {
+    fn cmp(&self, other: &ComparedKey<K,C>) -> Ordering {
+        return C::compare(&self.key, &other.key);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use comparator::{Comparator, CaseInsensitive, Descending, Numeric, range_contains};
+    use std::ops::Bound;
+
+    fn sorted<C: Comparator<String>>(keys: &[&str]) -> Vec<String> {
+        let mut keys: Vec<String> = keys.iter().map(|k| k.to_string()).collect();
+
+        keys.sort_by(C::compare);
+
+        return keys;
+    }
+
+    #[test]
+    fn orders_keys() {
+        assert_eq!(sorted::<Descending>(&["b", "c", "a"]), ["c", "b", "a"]);
+        assert_eq!(sorted::<CaseInsensitive>(&["b", "C", "a"]), ["a", "b", "C"]);
+        assert!(CaseInsensitive::compare(&String::from("KEY"), &String::from("key")) == ::std::cmp::Ordering::Equal);
+        assert_eq!(sorted::<Numeric>(&["item10", "item9", "item010", "item", "item9a", "10", "9"]),
+                   ["9", "10", "item", "item9", "item9a", "item010", "item10"]);
+    }
+
+    #[test]
+    fn ranges_use_comparator() {
+        let range = (Bound::Included(String::from("item9")), Bound::Excluded(String::from("item20")));
+
+        assert!(range_contains::<_, Numeric>(&range, &String::from("item10")));
+        assert!(!range_contains::<_, Numeric>(&range, &String::from("item20")));
+        assert!(range_contains::<_, Descending>(&(Bound::Excluded(5u32), Bound::Unbounded), &1));
+        assert!(!range_contains::<_, Descending>(&(Bound::Excluded(5u32), Bound::Unbounded), &5));
+    }
+}
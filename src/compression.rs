@@ -0,0 +1,135 @@
+use lz4;
+use snap;
+use zstd;
+
+use std::error::Error;
+use std::io::ErrorKind;
+use std::io::Error as IOError;
+
+const ZSTD_LEVEL: i32 = 3;
+
+/// The codecs a block in a tree file can be compressed with. Each block records
+/// the codec it was written with in its first byte, so files (and levels within
+/// a file) written with different codecs can be read back the same way.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Compression {
+    None,
+    Lz4,
+    Snappy,
+    Zstd,
+}
+
+/// Counts of the bytes in the leaves & internal nodes of a tree file
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct CompressionStats {
+    pub raw_bytes: u64,     // the size of the blocks before compression
+    pub stored_bytes: u64,  // the size of the blocks as written to the file
+}
+
+impl CompressionStats {
+    /// Returns raw bytes / stored bytes, so larger is better & 1.0 means no savings
+    pub fn ratio(&self) -> f64 {
+        if self.stored_bytes == 0 {
+            return 1.0;
+        }
+
+        return self.raw_bytes as f64 / self.stored_bytes as f64;
+    }
+}
+
+impl Compression {
+    fn id(&self) -> u8 {
+        match *self {
+            Compression::None => 0,
+            Compression::Lz4 => 1,
+            Compression::Snappy => 2,
+            Compression::Zstd => 3,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Compression> {
+        match id {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Lz4),
+            2 => Some(Compression::Snappy),
+            3 => Some(Compression::Zstd),
+            _ => None
+        }
+    }
+
+    /// Compresses the block, prefixing it with the codec used. Blocks that don't
+    /// shrink are stored uncompressed.
+    pub fn compress(&self, buff: &[u8]) -> Result<Vec<u8>, Box<Error>> {
+        let compressed = match *self {
+            Compression::None => None,
+            Compression::Lz4 => Some(try!(lz4::block::compress(buff, None, true))),
+            Compression::Snappy => Some(try!(snap::raw::Encoder::new().compress_vec(buff))),
+            Compression::Zstd => Some(try!(zstd::encode_all(buff, ZSTD_LEVEL))),
+        };
+
+        let (codec, data) = match compressed {
+            Some(ref data) if data.len() < buff.len() => (*self, &data[..]),
+            _ => (Compression::None, buff)
+        };
+
+        let mut ret = Vec::with_capacity(1 + data.len());
+
+        ret.push(codec.id());
+        ret.extend_from_slice(data);
+
+        return Ok(ret);
+    }
+
+    /// Returns the codec a block was written with, and the data following the codec byte
+    pub fn split(block: &[u8]) -> Result<(Compression, &[u8]), Box<Error>> {
+        if block.is_empty() {
+            return Err(From::from(IOError::new(ErrorKind::InvalidData, "Block is missing its compression type")));
+        }
+
+        match Compression::from_id(block[0]) {
+            Some(codec) => Ok((codec, &block[1..])),
+            None => Err(From::from(IOError::new(ErrorKind::InvalidData, format!("Unknown block compression type {}", block[0]))))
+        }
+    }
+
+    /// Decompresses data written by compress, after the codec byte has been removed with split
+    pub fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, Box<Error>> {
+        match *self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Lz4 => Ok(try!(lz4::block::decompress(data, None))),
+            Compression::Snappy => Ok(try!(snap::raw::Decoder::new().decompress_vec(data))),
+            Compression::Zstd => Ok(try!(zstd::decode_all(data))),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use compression::Compression;
+
+    #[test]
+    fn round_trip_all_codecs() {
+        let buff: Vec<u8> = (0..4096u32).map(|i| (i % 7) as u8).collect();
+
+        for codec in &[Compression::None, Compression::Lz4, Compression::Snappy, Compression::Zstd] {
+            let block = codec.compress(&buff).unwrap();
+            let (used, data) = Compression::split(&block).unwrap();
+
+            assert_eq!(used, *codec);
+            assert_eq!(used.decompress(data).unwrap(), buff);
+
+            if *codec != Compression::None {
+                assert!(block.len() < buff.len() / 4, "{:?} only compressed to {}", codec, block.len());
+            }
+        }
+    }
+
+    #[test]
+    fn stores_incompressible_blocks_raw() {
+        let block = Compression::Zstd.compress(&[1, 2, 3]).unwrap();
+
+        assert_eq!(block, vec![0, 1, 2, 3]);
+        assert!(Compression::split(&[9, 1]).is_err());
+    }
+}
@@ -1,170 +1,1579 @@
-use wal_file::{RecordFile, RecordFileIterator, KeyValuePair};
+use wal_file::KeyValuePair;
+use bloom::{BloomFilter, BloomFilterStats};
+use block_cache::BlockCache;
+use compression::{Compression, CompressionStats};
+use leaf::{Leaf, LeafBuilder};
+use codec::{Codec, Bincode, encode_to_vec};
+use comparator::{Comparator, Ascending, compare_records, coalesce_records};
+use verify::{DamageKind, VerifyReport};
+use options::Options;
 
 use ::{KeyType, ValueType};
 
+use bincode::SizeLimit;
+use bincode::rustc_serialize::{encode, decode};
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+use crc::crc32;
+use itertools::Itertools;
+use memmap::{Mmap, MmapOptions};
+
+use std::cell::Cell;
+use std::cmp;
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
 use std::error::Error;
-// use std::iter::Filter;
+use std::fmt;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::{Write, ErrorKind};
+use std::io::Error as IOError;
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::os::unix::fs::FileExt;
+use std::sync::Arc;
+use std::vec;
 
-/*
 const NUM_CHILDREN: usize = 32;
 const FILE_HEADER: &'static str = "B+Tree\0";
-const CURRENT_VERSION: u8 = 0x01;
-
+const CURRENT_VERSION: u8 = 0x07;
+const HEADER_SIZE: u64 = 41;
+const COMPARATOR_SIZE: usize = 32;  // the comparator's name, padded with zeros
+const MULTI_MODE: u8 = 0;           // each key can have many values
+const MAP_MODE: u8 = 1;             // each key has a single value
+const FOOTER_SIZE: u64 = 60;  // a bincode Footer followed by its checksum
 
-#[derive(RustcEncodable, RustcDecodable, PartialEq)]
-enum Payload<K: KeyType, V: ValueType> {
-        Value(V),
-        Children([(K,u64); NUM_CHILDREN]),
-    }
+/// The entries of an internal node: the smallest key in each child, and the offset of the child
+type Children<K> = Vec<(K, u64)>;
 
-#[derive(RustcEncodable, RustcDecodable, PartialEq)]
-struct Node<K: KeyType, V: ValueType> {
-    key: K,
-    parent: u64,
-    payload: Payload<K,V>, // either children, or actual values
+/// Fixed size trailer of a tree file describing where everything is
+#[derive(RustcEncodable, RustcDecodable, PartialEq, Debug)]
+struct Footer {
+    root: u64,          // offset of the root internal node
+    height: u64,        // the number of levels of internal nodes above the leaves
+    leaf_end: u64,      // offset just past the last leaf
+    bloom: u64,         // offset of the bloom filter, 0 if there isn't one
+    record_count: u64,  // the number of records in the leaves
+    raw_bytes: u64,     // the size of the leaves & internal nodes before compression
+    stored_bytes: u64,  // the size of the leaves & internal nodes in the file
 }
 
-*/
-
-/// This struct represents an on-disk B+Tree. There are NUM_CHILDREN keys at each
-/// level in the tree. The on-disk format is as follows where VV is the version
-/// number:
+/// This struct represents an on-disk B+Tree. Each leaf holds records up to about
+/// Options::block_size bytes, and there are NUM_CHILDREN children in each internal
+/// node. Tree files are
+/// immutable, they are written once from sorted records by an OnDiskBTreeBuilder.
+/// An empty file is an empty tree. The on-disk format is as follows where VV is
+/// the version number:
 /// |-------------------------------------------|
 /// | 0x42 0x2b 0x54 0x72 | 0x65 0x65 0x00 0xVV |
 /// | B    +    T    r    | e    e    \0   0xVV |
 /// |-------------------------------------------|
-/// | smallest record in bincode format         |
+/// | name of the comparator the records are    |
+/// | sorted by, padded with zeros (32 bytes)   |
+/// |-------------------------------------------|
+/// | 0 if a key can have many values, 1 if it  |
+/// | has a single value (u8)                   |
+/// |-------------------------------------------|
+/// | leaf with the smallest records            |
 /// |-------------------------------------------|
 /// | ...                                       |
 /// |-------------------------------------------|
-/// | largest record in bincode format          |
+/// | leaf with the largest records             |
 /// |-------------------------------------------|
 /// | internal nodes ...                        |
 /// |-------------------------------------------|
 /// | root node                                 |
 /// |-------------------------------------------|
+/// | bloom filter of the keys                  |
+/// |-------------------------------------------|
+/// | footer                                    |
+/// |-------------------------------------------|
+///
+/// Leaves, internal nodes & the bloom filter are all blocks: a u32 (big endian)
+/// length, that many bytes, then a CRC-32C (u32, big endian) of those bytes which
+/// is checked whenever the block is read. Leaves & internal nodes start with a byte
+/// giving the Compression they were written with, followed by the (compressed)
+/// node. A leaf holds its records, encoded with the tree's codecs, with the keys
+/// prefix compressed as described in LeafBuilder. An internal node holds its
+/// Children as described in encode_children, and the footer is a Footer in bincode
+/// format followed by its CRC-32C.
+///
+/// Leaves & internal nodes are read through the block cache when there is one,
+/// unless the file is memory-mapped in which case they are read straight from the map.
+pub struct OnDiskBTree<K: KeyType, V: ValueType, KC: Codec<K>, VC: Codec<V>, C: Comparator<K>> {
+    fd: File,
+    file_len: u64,                   // tree files aren't changed once written, so no block goes past this
+    map: Option<Mmap>,               // the whole file when reading through a memory map
+    file_id: usize,                  // identifies our blocks in the cache
+    cache: Option<Arc<BlockCache>>,
+    footer: Option<Footer>,          // None for an empty tree
+    map_mode: Option<bool>,          // true if each key has a single value, None for an empty tree
+    bloom: Option<BloomFilter>,
+    bloom_stats: Cell<BloomFilterStats>,
+    _k_marker: PhantomData<K>,
+    _v_marker: PhantomData<V>,
+    _kc_marker: PhantomData<KC>,
+    _vc_marker: PhantomData<VC>,
+    _c_marker: PhantomData<C>
+}
+
+/// The uncompressed contents of a leaf or internal node, either shared with the block
+/// cache or borrowed from the memory map
+pub enum Block<'a> {
+    Shared(Arc<Vec<u8>>),
+    Mapped(&'a [u8]),
+}
+
+impl <'a> Deref for Block<'a> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match *self {
+            Block::Shared(ref buff) => buff,
+            Block::Mapped(buff) => buff
+        }
+    }
+}
+
+/// Bytes borrowed from a tree without copying them: from a node shared with the block
+/// cache, which is kept alive while the guard is, or from the memory map or in-memory
+/// BTree, which the guard's lifetime ties to the tree. A value made when it's read,
+/// by folding merge operands, is owned by its guard.
+pub struct SliceGuard<'a> {
+    data: GuardData<'a>,
+}
+
+enum GuardData<'a> {
+    Borrowed(&'a [u8]),
+    Shared(Arc<Vec<u8>>, usize, usize),  // the node, and the start & end of the bytes within it
+}
+
+impl <'a> SliceGuard<'a> {
+    /// Guards a slice of the given node
+    fn from_block(block: &Block<'a>, slice: &[u8]) -> SliceGuard<'a> {
+        let start = slice.as_ptr() as usize - block.as_ptr() as usize;
+        let end = start + slice.len();
+
+        match *block {
+            Block::Shared(ref buff) => SliceGuard{data: GuardData::Shared(buff.clone(), start, end)},
+            Block::Mapped(buff) => SliceGuard{data: GuardData::Borrowed(&buff[start..end])}
+        }
+    }
+}
+
+impl <'a> From<&'a [u8]> for SliceGuard<'a> {
+    fn from(slice: &'a [u8]) -> SliceGuard<'a> {
+        return SliceGuard{data: GuardData::Borrowed(slice)};
+    }
+}
+
+impl <'a> From<Vec<u8>> for SliceGuard<'a> {
+    fn from(buff: Vec<u8>) -> SliceGuard<'a> {
+        let end = buff.len();
+
+        return SliceGuard{data: GuardData::Shared(Arc::new(buff), 0, end)};
+    }
+}
+
+impl <'a> Deref for SliceGuard<'a> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self.data {
+            GuardData::Borrowed(slice) => slice,
+            GuardData::Shared(ref buff, start, end) => &buff[start..end]
+        }
+    }
+}
+
+impl <'a> AsRef<[u8]> for SliceGuard<'a> {
+    fn as_ref(&self) -> &[u8] {
+        return self;
+    }
+}
+
+impl <'a> fmt::Debug for SliceGuard<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return (**self).fmt(f);
+    }
+}
+
+/// The records read back from a damaged tree file by OnDiskBTree::salvage
+pub struct Salvage<K: KeyType, V: ValueType> {
+    pub records: Vec<KeyValuePair<K,V>>,  // in the order found in the file
+    pub blocks_lost: u64,                 // leaves that failed their checksum or couldn't be decoded
+    pub bytes_lost: u64,                  // bytes skipped over to find the next good leaf
+    pub expected_records: Option<u64>,    // the record count from the footer, if it could be read
+}
+
+/// What is carried from node to node while verifying a tree
+struct VerifyState<'a, K: KeyType, V: ValueType> {
+    file_name: &'a str,
+    report: &'a mut VerifyReport,
+    last: Option<(K, V)>,  // the last record seen, to check ordering across leaves
+    next_leaf: u64,        // where the next leaf should be if the leaves are contiguous
+    records: u64,
+}
+
+pub struct OnDiskBTreeIterator<'a, K: KeyType + 'a, V: ValueType + 'a, KC: Codec<K> + 'a, VC: Codec<V> + 'a, C: Comparator<K> + 'a> {
+    tree: &'a OnDiskBTree<K,V,KC,VC,C>,
+    next_leaf: u64,                            // offset of the next leaf to read
+    records: vec::IntoIter<KeyValuePair<K,V>>  // the remaining records of the current leaf
+}
+
+/// Writes a new tree file from records supplied in sorted order
+pub struct OnDiskBTreeBuilder<K: KeyType, V: ValueType, KC: Codec<K>, VC: Codec<V>, C: Comparator<K>> {
+    fd: File,
+    map: bool,                   // true if each key can only have a single value
+    bloom_bits_per_key: usize,
+    block_size: usize,
+    compression_per_level: Vec<Compression>,
+    offset: u64,                 // where the next block will be written
+    leaf: LeafBuilder,           // records of the leaf being filled
+    leaf_key: Option<K>,         // smallest key of the leaf being filled
+    leaves: Children<K>,         // the leaves written so far
+    last_key: Option<K>,         // the last key added, for finding distinct keys
+    last_value: Option<V>,       // the last value added, for checking records arrive in order
+    key_hashes: Vec<u64>,        // bloom filter hashes of the distinct keys
+    record_count: u64,
+    compression_stats: CompressionStats,
+    _kc_marker: PhantomData<KC>,
+    _vc_marker: PhantomData<VC>,
+    _c_marker: PhantomData<C>
+}
+
+
+/// Reads the block at the given offset, which must end by the end offset, returning its
+/// contents and the offset of the following block
+fn read_block(fd: &File, offset: u64, end: u64) -> Result<(Vec<u8>, u64), Box<Error>> {
+    let (buff, crc, next_offset) = try!(read_raw_block(fd, offset, end));
+
+    try!(check_block(&buff, crc, offset));
+
+    return Ok((buff, next_offset));
+}
+
+/// Reads the block at the given offset without checking its checksum, returning its
+/// contents, the checksum stored with it and the offset of the following block. The
+/// length stored with the block is checked against the end offset before anything is
+/// allocated for it, as a damaged one can be up to 4GB.
+fn read_raw_block(fd: &File, offset: u64, end: u64) -> Result<(Vec<u8>, u32, u64), Box<Error>> {
+    let mut len_buff = [0; 4];
+
+    try!(read_exact_at(fd, &mut len_buff, offset));
+
+    let len = BigEndian::read_u32(&len_buff) as usize;
+
+    if offset + 8 + len as u64 > end {
+        return Err(invalid_data(&format!("Block at offset {} has a length of {} bytes, running past the end at {}", offset, len, end)));
+    }
+
+    let mut buff = vec![0; len + 4];
 
+    try!(read_exact_at(fd, &mut buff, offset + 4));
 
-// total hack to get things going
-pub struct OnDiskBTree<K: KeyType, V: ValueType> {
-    file: RecordFile<K,V>
+    let crc = BigEndian::read_u32(&buff[len..]);
+
+    buff.truncate(len);
+
+    return Ok((buff, crc, offset + 8 + len as u64));
+}
+
+/// Reads & checks the footer at the end of a file of the given length
+fn read_footer(fd: &File, file_len: u64) -> Result<Footer, Box<Error>> {
+    if file_len < HEADER_SIZE + FOOTER_SIZE {
+        return Err(invalid_data("Tree file is truncated"));
+    }
+
+    let mut footer_buff = vec![0; FOOTER_SIZE as usize];
+
+    try!(read_exact_at(fd, &mut footer_buff, file_len - FOOTER_SIZE));
+
+    let (footer_buff, crc_buff) = footer_buff.split_at(FOOTER_SIZE as usize - 4);
+
+    try!(check_block(footer_buff, BigEndian::read_u32(crc_buff), file_len - FOOTER_SIZE));
+
+    return Ok(try!(decode(footer_buff)));
+}
+
+fn checksum(buff: &[u8]) -> u32 {
+    return crc32::checksum_castagnoli(buff);
+}
+
+fn check_block(buff: &[u8], crc: u32, offset: u64) -> Result<(), Box<Error>> {
+    if checksum(buff) != crc {
+        return Err(From::from(IOError::new(ErrorKind::InvalidData, format!("Block at offset {} failed its checksum", offset))));
+    }
+
+    Ok( () )
+}
+
+fn read_exact_at(fd: &File, mut buff: &mut [u8], mut offset: u64) -> Result<(), Box<Error>> {
+    while !buff.is_empty() {
+        match try!(fd.read_at(buff, offset)) {
+            0 => return Err(invalid_data("Tree file is truncated")),
+            n => {
+                let tmp = buff;
+                buff = &mut tmp[n..];
+                offset += n as u64;
+            }
+        }
+    }
+
+    Ok( () )
+}
+
+/// Writes the records, which must be in sorted order, to a new tree file at the given
+/// path, ready to be added to a tree with BTree::ingest. Duplicate records are dropped.
+/// If a record is out of order the file is removed and an error returned, otherwise
+/// the number of records written is returned. The keys & values are stored with
+/// bincode and sorted by their Ord; use BTree::write_tree_file for a tree with other
+/// codecs or comparator, or MapBTree::write_tree_file for a map.
+pub fn write_tree_file<K, V, I>(file_path: &String, records: I, options: &Options) -> Result<u64, Box<Error>>
+    where K: KeyType, V: ValueType, I: IntoIterator<Item=(K,V)>, Bincode: Codec<K> + Codec<V>
+{
+    return build_tree_file::<K,V,Bincode,Bincode,Ascending,I>(file_path, records, options, false);
+}
+
+/// Same as write_tree_file, storing the keys & values with the given codecs and
+/// sorting them with the given comparator. For a map only the last of the values
+/// given for a key is kept.
+pub fn build_tree_file<K, V, KC, VC, C, I>(file_path: &String, records: I, options: &Options, map: bool) -> Result<u64, Box<Error>>
+    where K: KeyType, V: ValueType, KC: Codec<K>, VC: Codec<V>, C: Comparator<K>, I: IntoIterator<Item=(K,V)>
+{
+    let mut builder = try!(OnDiskBTreeBuilder::<K,V,KC,VC,C>::new(file_path.to_owned(), options, map));
+    let mut result = Ok( () );
+    let mut count = 0;
+
+    let records = records.into_iter().coalesce(|a, b| coalesce_records::<K,V,C>(map, a, b));
+
+    for (key, value) in records {
+        result = builder.insert_record(&KeyValuePair{key: key, value: value});
+
+        if result.is_err() {
+            break;
+        }
+
+        count += 1;
+    }
+
+    if result.is_ok() {
+        result = builder.finish();
+    }
+
+    if let Err(e) = result {
+        let _ = fs::remove_file(file_path);
+        return Err(e);
+    }
+
+    return Ok(count);
 }
 
-pub struct OnDiskBTreeIterator<'a, K: KeyType + 'a, V: ValueType + 'a> {
-    record_iterator: RecordFileIterator<'a,K,V>
+/// Encodes the children of an internal node. Each child is the length of its key
+/// (u32, big endian), the key, then the offset of the child (u64, big endian).
+fn encode_children<K, KC: Codec<K>>(children: &[(K, u64)]) -> Result<Vec<u8>, Box<Error>> {
+    let mut buff = Vec::new();
+
+    for &(ref key, offset) in children {
+        let start = buff.len();
+
+        // the length is filled in once the key is encoded
+        try!(buff.write_u32::<BigEndian>(0));
+        try!(KC::encode(key, &mut buff));
+
+        let key_len = buff.len() - start - 4;
+
+        BigEndian::write_u32(&mut buff[start..start + 4], key_len as u32);
+        try!(buff.write_u64::<BigEndian>(offset));
+    }
+
+    return Ok(buff);
 }
 
+fn decode_children<K, KC: Codec<K>>(buff: &[u8]) -> Result<Children<K>, Box<Error>> {
+    let mut children = Vec::new();
 
-impl <K: KeyType, V: ValueType> OnDiskBTree<K,V> {
-    pub fn new(file_path: String, key_size: usize, value_size: usize) -> Result<OnDiskBTree<K,V>, Box<Error>> {
-        return Ok(OnDiskBTree{file: try!(RecordFile::new(&file_path, key_size, value_size))});
+    for (key_buff, offset) in try!(split_children(buff)) {
+        children.push((try!(KC::decode(key_buff)), offset));
+    }
+
+    return Ok(children);
+}
+
+/// Splits an internal node into the encoded key & offset of each child, without decoding the keys
+fn split_children(buff: &[u8]) -> Result<Vec<(&[u8], u64)>, Box<Error>> {
+    let mut children = Vec::new();
+    let mut offset = 0;
+
+    while offset < buff.len() {
+        if offset + 4 > buff.len() {
+            return Err(invalid_data("Internal node is corrupt"));
+        }
+
+        let key_end = offset + 4 + BigEndian::read_u32(&buff[offset..offset + 4]) as usize;
+
+        if key_end + 8 > buff.len() {
+            return Err(invalid_data("Internal node is corrupt"));
+        }
+
+        children.push((&buff[offset + 4..key_end], BigEndian::read_u64(&buff[key_end..key_end + 8])));
+
+        offset = key_end + 8;
+    }
+
+    return Ok(children);
+}
+
+fn invalid_data(msg: &str) -> Box<Error> {
+    return From::from(IOError::new(ErrorKind::InvalidData, msg));
+}
+
+/// Returns the compression used for nodes at the given level, where the leaves are
+/// level 0. Levels past the end of the list use the last entry.
+fn level_compression(compression_per_level: &Vec<Compression>, level: usize) -> Compression {
+    return compression_per_level.get(level).or(compression_per_level.last()).cloned().unwrap_or(Compression::None);
+}
+
+
+impl <K: KeyType, V: ValueType, KC: Codec<K>, VC: Codec<V>, C: Comparator<K>> OnDiskBTree<K,V,KC,VC,C> {
+    /// Opens the tree file at the given path, which must exist; an empty file is an empty tree
+    pub fn new(file_path: String, options: &Options) -> Result<OnDiskBTree<K,V,KC,VC,C>, Box<Error>> {
+        let fd = try!(OpenOptions::new().read(true).write(true).open(&file_path));
+
+        return OnDiskBTree::from_file(fd, options);
+    }
+
+    pub fn open_read_only(file_path: String, options: &Options) -> Result<OnDiskBTree<K,V,KC,VC,C>, Box<Error>> {
+        let fd = try!(OpenOptions::new().read(true).open(&file_path));
+
+        return OnDiskBTree::from_file(fd, options);
+    }
+
+    fn from_file(fd: File, options: &Options) -> Result<OnDiskBTree<K,V,KC,VC,C>, Box<Error>> {
+        let file_len = try!(fd.metadata()).len();
+
+        // an empty file can't be mapped, but there's nothing to read anyway
+        let map = if options.use_mmap && file_len > 0 {
+            Some(try!(unsafe { MmapOptions::new().map(&fd) }))
+        } else {
+            None
+        };
+
+        let mut tree = OnDiskBTree{fd: fd,
+                                   file_len: file_len,
+                                   map: map,
+                                   file_id: BlockCache::new_file_id(),
+                                   cache: options.block_cache.clone(),
+                                   footer: None,
+                                   map_mode: None,
+                                   bloom: None,
+                                   bloom_stats: Cell::new(BloomFilterStats::default()),
+                                   _k_marker: PhantomData,
+                                   _v_marker: PhantomData,
+                                   _kc_marker: PhantomData,
+                                   _vc_marker: PhantomData,
+                                   _c_marker: PhantomData};
+
+        if file_len == 0 {
+            return Ok(tree);
+        }
+
+        let mut header = vec![0; HEADER_SIZE as usize];
+
+        try!(read_exact_at(&tree.fd, &mut header, 0));
+
+        // make sure we've opened a proper file
+        if &header[0..FILE_HEADER.len()] != FILE_HEADER.as_bytes() || header[FILE_HEADER.len()] != CURRENT_VERSION {
+            return Err(invalid_data("Invalid BTree file or BTree version"));
+        }
+
+        let comparator_end = FILE_HEADER.len() + 1 + COMPARATOR_SIZE;
+        let comparator = String::from_utf8_lossy(&header[FILE_HEADER.len() + 1..comparator_end]).trim_right_matches('\0').to_string();
+
+        if comparator != C::name() {
+            return Err(From::from(IOError::new(ErrorKind::InvalidInput, format!("Tree file was sorted with the {} comparator", comparator))));
+        }
+
+        tree.map_mode = match header[comparator_end] {
+            MULTI_MODE => Some(false),
+            MAP_MODE => Some(true),
+            _ => return Err(invalid_data("Invalid BTree file mode"))
+        };
+
+        let footer = try!(read_footer(&tree.fd, file_len));
+
+        if footer.bloom != 0 {
+            let (bloom_buff, _) = try!(read_block(&tree.fd, footer.bloom, file_len));
+
+            tree.bloom = BloomFilter::from_bytes(&bloom_buff);
+        }
+
+        tree.footer = Some(footer);
+
+        return Ok(tree);
     }
 
     pub fn is_new(&self) -> Result<bool, Box<Error>> {
-        return self.file.is_new();
+        Ok(try!(self.fd.metadata()).len() == 0)
+    }
+
+    /// Returns true if each key in the file has a single value, None if the file is empty
+    pub fn is_map(&self) -> Option<bool> {
+        return self.map_mode;
     }
 
     /// Returns the number of records in the B+Tree
     pub fn count(&self) -> Result<u64, Box<Error>> {
-        return self.file.count();
+        Ok(self.footer.as_ref().map(|f| f.record_count).unwrap_or(0))
     }
 
-    pub fn insert_record(&mut self, kv: &KeyValuePair<K,V>) -> Result<(), Box<Error>> {
-        return self.file.insert_record(kv);
+    pub fn bloom_filter_stats(&self) -> BloomFilterStats {
+        return self.bloom_stats.get();
+    }
+
+    pub fn compression_stats(&self) -> CompressionStats {
+        match self.footer {
+            Some(ref footer) => CompressionStats{raw_bytes: footer.raw_bytes, stored_bytes: footer.stored_bytes},
+            None => CompressionStats::default()
+        }
+    }
+
+    /// Returns all of the values for the key, in order
+    pub fn get(&self, key: &K) -> Result<Vec<V>, Box<Error>> {
+        let mut values = Vec::new();
+
+        try!(self.find_values(key, |_, value_buff| {
+            values.push(try!(VC::decode(value_buff)));
+            Ok(true)
+        }));
+
+        return Ok(values);
+    }
+
+    /// Calls visit with the stored bytes of each of the key's values, in order, until
+    /// it returns false
+    pub fn visit_values<F>(&self, key: &K, mut visit: F) -> Result<(), Box<Error>>
+        where F: FnMut(&[u8]) -> Result<bool, Box<Error>>
+    {
+        return self.find_values(key, |_, value_buff| visit(value_buff));
+    }
+
+    /// Returns the stored bytes of all of the values for the key, in order, without
+    /// copying or decoding them
+    pub fn get_slices<'a>(&'a self, key: &K) -> Result<Vec<SliceGuard<'a>>, Box<Error>> {
+        let mut values = Vec::new();
+
+        try!(self.find_values(key, |block, value_buff| {
+            values.push(SliceGuard::from_block(block, value_buff));
+            Ok(true)
+        }));
+
+        return Ok(values);
+    }
+
+    /// Calls visit with the node holding each of the key's values and the value's bytes
+    /// within it, until it returns false
+    fn find_values<'a, F>(&'a self, key: &K, mut visit: F) -> Result<(), Box<Error>>
+        where F: FnMut(&Block<'a>, &[u8]) -> Result<bool, Box<Error>>
+    {
+        let mut found = 0;
+
+        let footer = match self.footer {
+            Some(ref footer) => footer,
+            None => return Ok( () )
+        };
+
+        let key_buff = try!(encode_to_vec::<K,KC>(key));
+
+        // check the bloom filter before touching the tree
+        if let Some(ref bloom) = self.bloom {
+            let mut stats = self.bloom_stats.get();
+
+            stats.lookups += 1;
+
+            let may_contain = bloom.may_contain(&key_buff);
+
+            if !may_contain {
+                stats.negatives += 1;
+            }
+
+            self.bloom_stats.set(stats);
+
+            if !may_contain {
+                return Ok( () );
+            }
+        }
+
+        // descend to the left-most leaf that could hold the key
+        let mut offset = footer.root;
+
+        for _ in 0..footer.height {
+            let (buff, _) = try!(self.read_node(offset));
+            let children = try!(split_children(&buff));
+
+            // a key's records can span leaves, so take the last child starting before the key
+            let mut next = children.len();
+
+            for (i, &(child_key, _)) in children.iter().enumerate() {
+                if try!(OnDiskBTree::<K,V,KC,VC,C>::compare_key(child_key, &key_buff, key)) != Ordering::Less {
+                    next = i;
+                    break;
+                }
+            }
+
+            offset = children[if next == 0 { 0 } else { next - 1 }].1;
+        }
+
+        // scan forward through the leaves until we pass the key, skipping to the
+        // closest restart point in the first leaf
+        let mut first_leaf = true;
+
+        'leaves: while offset < footer.leaf_end {
+            let (buff, next_leaf) = try!(self.read_node(offset));
+            let leaf = try!(Leaf::new(&buff));
+            let start = if first_leaf { try!(OnDiskBTree::<K,V,KC,VC,C>::find_restart(&leaf, &key_buff, key)) } else { 0 };
+
+            for record in leaf.iter_from(start) {
+                let (record_key, value_buff) = try!(record);
+
+                match try!(OnDiskBTree::<K,V,KC,VC,C>::compare_key(&record_key, &key_buff, key)) {
+                    Ordering::Greater => break 'leaves,
+                    Ordering::Equal => {
+                        found += 1;
+
+                        if !try!(visit(&buff, value_buff)) {
+                            break 'leaves;
+                        }
+                    },
+                    Ordering::Less => ()
+                }
+            }
+
+            first_leaf = false;
+            offset = next_leaf;
+        }
+
+        if self.bloom.is_some() && found == 0 {
+            let mut stats = self.bloom_stats.get();
+            stats.false_positives += 1;
+            self.bloom_stats.set(stats);
+        }
+
+        Ok( () )
+    }
+
+    pub fn contains_key(&self, key: &K) -> Result<bool, Box<Error>> {
+        let mut found = false;
+
+        try!(self.find_values(key, |_, _| {
+            found = true;
+            Ok(false)
+        }));
+
+        return Ok(found);
+    }
+
+    /// Reads & decompresses a leaf or internal node, and the offset of the following block
+    fn read_node<'a>(&'a self, offset: u64) -> Result<(Block<'a>, u64), Box<Error>> {
+        if let Some(ref map) = self.map {
+            let (buff, crc, next_offset) = try!(OnDiskBTree::<K,V,KC,VC,C>::read_mapped_block(map, offset));
+
+            try!(check_block(buff, crc, offset));
+
+            let (codec, data) = try!(Compression::split(buff));
+
+            // uncompressed nodes can be used in place
+            if codec == Compression::None {
+                return Ok((Block::Mapped(data), next_offset));
+            }
+
+            return Ok((Block::Shared(Arc::new(try!(codec.decompress(data)))), next_offset));
+        }
+
+        if let Some(ref cache) = self.cache {
+            if let Some((block, stored_len)) = cache.get(self.file_id, offset) {
+                return Ok((Block::Shared(block), offset + stored_len));
+            }
+        }
+
+        let (buff, next_offset) = try!(read_block(&self.fd, offset, self.file_len));
+        let (codec, data) = try!(Compression::split(&buff));
+        let block = Arc::new(try!(codec.decompress(data)));
+
+        if let Some(ref cache) = self.cache {
+            cache.insert(self.file_id, offset, block.clone(), next_offset - offset);
+        }
+
+        return Ok((Block::Shared(block), next_offset));
+    }
+
+    /// Borrows a block straight out of the memory map, without checking it
+    fn read_mapped_block<'a>(map: &'a Mmap, offset: u64) -> Result<(&'a [u8], u32, u64), Box<Error>> {
+        let start = offset as usize + 4;
+
+        if start > map.len() {
+            return Err(invalid_data("Tree file is truncated"));
+        }
+
+        let end = start + BigEndian::read_u32(&map[start-4..start]) as usize;
+
+        if end + 4 > map.len() {
+            return Err(invalid_data("Tree file is truncated"));
+        }
+
+        return Ok((&map[start..end], BigEndian::read_u32(&map[end..end+4]), end as u64 + 4));
+    }
+
+    /// Reads a block from the file, bypassing the cache & without checking it
+    fn read_raw_node<'a>(&'a self, offset: u64) -> Result<(Block<'a>, u32, u64), Box<Error>> {
+        if let Some(ref map) = self.map {
+            let (buff, crc, next_offset) = try!(OnDiskBTree::<K,V,KC,VC,C>::read_mapped_block(map, offset));
+
+            return Ok((Block::Mapped(buff), crc, next_offset));
+        }
+
+        let (buff, crc, next_offset) = try!(read_raw_block(&self.fd, offset, self.file_len));
+
+        return Ok((Block::Shared(Arc::new(buff)), crc, next_offset));
+    }
+
+    /// Reads every leaf of a possibly damaged file that passes its checksum and decodes,
+    /// without trusting the header. The footer is used to find where the leaves end;
+    /// without it the leaves are taken to end at the first good block that isn't a leaf.
+    /// After a bad leaf, salvage carries on from the next leaf listed in the internal
+    /// nodes. If some of them can't be read, the file is scanned for the next block with
+    /// a valid checksum and the length of a leaf.
+    pub fn salvage(file_path: &String) -> Result<Salvage<K,V>, Box<Error>> {
+        let fd = try!(OpenOptions::new().read(true).open(file_path));
+        let file_len = try!(fd.metadata()).len();
+
+        let mut salvage = Salvage{records: Vec::new(), blocks_lost: 0, bytes_lost: 0, expected_records: None};
+
+        if file_len == 0 {
+            salvage.expected_records = Some(0);
+            return Ok(salvage);
+        }
+
+        let footer = read_footer(&fd, file_len).ok();
+        let leaf_end = footer.as_ref().map(|f| f.leaf_end).unwrap_or(file_len);
+
+        salvage.expected_records = footer.as_ref().map(|f| f.record_count);
+
+        let (leaf_offsets, all_leaves_known) = match footer {
+            Some(ref footer) => OnDiskBTree::<K,V,KC,VC,C>::salvage_leaf_offsets(&fd, footer, file_len),
+            None => (BTreeSet::new(), false)
+        };
+
+        // a leaf is written once it passes the block size, so the probe skips any block
+        // much longer than the leaves read so far without reading it
+        let mut max_leaf_len = Options::default().block_size as u64;
+        let mut offset = HEADER_SIZE;
+
+        while offset < leaf_end {
+            match OnDiskBTree::<K,V,KC,VC,C>::salvage_leaf(&fd, offset, leaf_end) {
+                Ok((records, next_offset)) => {
+                    salvage.records.extend(records);
+                    max_leaf_len = cmp::max(max_leaf_len, next_offset - offset);
+                    offset = next_offset;
+                },
+                Err(_) if footer.is_none() && OnDiskBTree::<K,V,KC,VC,C>::is_good_block(&fd, offset, leaf_end) => break,
+                Err(_) => {
+                    let next_offset = if all_leaves_known {
+                        leaf_offsets.range(offset + 1..).next().cloned().unwrap_or(leaf_end)
+                    } else {
+                        (offset + 1..leaf_end).find(|o| OnDiskBTree::<K,V,KC,VC,C>::is_good_block(&fd, *o, cmp::min(leaf_end, *o + 2 * max_leaf_len))).unwrap_or(leaf_end)
+                    };
+
+                    salvage.blocks_lost += 1;
+                    salvage.bytes_lost += next_offset - offset;
+                    offset = next_offset;
+                }
+            }
+        }
+
+        return Ok(salvage);
+    }
+
+    /// Returns the offsets of the leaves listed in the internal nodes of a possibly
+    /// damaged file, and whether every internal node could be read
+    fn salvage_leaf_offsets(fd: &File, footer: &Footer, file_len: u64) -> (BTreeSet<u64>, bool) {
+        let mut leaf_offsets = BTreeSet::new();
+        let mut all_read = true;
+        let mut nodes = vec![(footer.root, footer.height)];
+
+        while let Some((offset, height)) = nodes.pop() {
+            // children are written before their parent, and leaves before every internal node
+            match OnDiskBTree::<K,V,KC,VC,C>::salvage_children(fd, offset, file_len - FOOTER_SIZE) {
+                Ok(ref children) if height > 1 && children.iter().all(|c| *c >= footer.leaf_end && *c < offset) => {
+                    nodes.extend(children.iter().map(|c| (*c, height - 1)));
+                },
+                Ok(ref children) if height == 1 && children.iter().all(|c| *c >= HEADER_SIZE && *c < footer.leaf_end) => {
+                    leaf_offsets.extend(children);
+                },
+                _ => all_read = false
+            }
+        }
+
+        return (leaf_offsets, all_read);
+    }
+
+    /// Reads the offsets of the children of the internal node at the given offset
+    fn salvage_children(fd: &File, offset: u64, end: u64) -> Result<Vec<u64>, Box<Error>> {
+        let (buff, _) = try!(read_block(fd, offset, end));
+        let (codec, data) = try!(Compression::split(&buff));
+        let buff = try!(codec.decompress(data));
+
+        return Ok(try!(split_children(&buff)).into_iter().map(|(_, child)| child).collect());
+    }
+
+    /// Reads & decodes every record of the leaf at the given offset
+    fn salvage_leaf(fd: &File, offset: u64, leaf_end: u64) -> Result<(Vec<KeyValuePair<K,V>>, u64), Box<Error>> {
+        let (buff, next_offset) = try!(read_block(fd, offset, leaf_end));
+        let (codec, data) = try!(Compression::split(&buff));
+        let buff = try!(codec.decompress(data));
+        let leaf = try!(Leaf::new(&buff));
+        let mut records = Vec::new();
+
+        for record in leaf.iter_from(0) {
+            let (key_buff, value_buff) = try!(record);
+
+            records.push(KeyValuePair{key: try!(KC::decode(&key_buff)), value: try!(VC::decode(value_buff))});
+        }
+
+        return Ok((records, next_offset));
+    }
+
+    /// Returns true if a block within the end offset with a valid checksum starts at the offset
+    fn is_good_block(fd: &File, offset: u64, end: u64) -> bool {
+        return read_block(fd, offset, end).is_ok();
+    }
+
+    /// Walks every leaf & internal node, straight from the file, checking checksums,
+    /// ordering, the key bounds given by parents, the bloom filter & the record count.
+    /// Any damage found is added to the report.
+    pub fn verify(&self, file_name: &str, report: &mut VerifyReport) {
+        report.files += 1;
+
+        let footer = match self.footer {
+            Some(ref footer) => footer,
+            None => return
+        };
+
+        let mut state = VerifyState{file_name: file_name, report: report, last: None, next_leaf: HEADER_SIZE, records: 0};
+
+        self.verify_node(&mut state, footer.root, footer.height, None, None);
+
+        if state.next_leaf != footer.leaf_end {
+            state.report.add_damage(file_name, 0, DamageKind::Structure,
+                format!("The leaves reached from the root end at {} but the file's leaves end at {}", state.next_leaf, footer.leaf_end));
+        }
+
+        if state.records != footer.record_count {
+            state.report.add_damage(file_name, 0, DamageKind::RecordCount,
+                format!("Found {} records but the footer records {}", state.records, footer.record_count));
+        }
+    }
+
+    /// Verifies the node at the given offset and everything below it. Every key in
+    /// the node must be within lower..=upper, and it must start with lower.
+    fn verify_node(&self, state: &mut VerifyState<K,V>, offset: u64, height: u64, lower: Option<&K>, upper: Option<&K>) {
+        let less = |a: &K, b: &K| C::compare(a, b) == Ordering::Less;
+
+        state.report.blocks += 1;
+
+        let (raw, crc, next_offset) = match self.read_raw_node(offset) {
+            Ok(r) => r,
+            Err(e) => return state.report.add_damage(state.file_name, offset, DamageKind::Corrupt, e.to_string())
+        };
+
+        if height == 0 {
+            if offset != state.next_leaf {
+                state.report.add_damage(state.file_name, offset, DamageKind::Structure, format!("Expected the next leaf at {}", state.next_leaf));
+            }
+
+            state.next_leaf = next_offset;
+        }
+
+        if checksum(&raw) != crc {
+            return state.report.add_damage(state.file_name, offset, DamageKind::Checksum,
+                format!("Stored checksum is {:08x} but the block hashes to {:08x}", crc, checksum(&raw)));
+        }
+
+        let buff = match Compression::split(&raw).and_then(|(codec, data)| codec.decompress(data)) {
+            Ok(buff) => buff,
+            Err(e) => return state.report.add_damage(state.file_name, offset, DamageKind::Corrupt, e.to_string())
+        };
+
+        if height == 0 {
+            return self.verify_leaf(state, offset, &buff, lower, upper);
+        }
+
+        let children = match decode_children::<K,KC>(&buff) {
+            Ok(children) => children,
+            Err(e) => return state.report.add_damage(state.file_name, offset, DamageKind::Corrupt, e.to_string())
+        };
+
+        if children.is_empty() {
+            return state.report.add_damage(state.file_name, offset, DamageKind::Corrupt, String::from("Internal node has no children"));
+        }
+
+        for (i, &(ref key, child)) in children.iter().enumerate() {
+            if i == 0 && lower.is_some() && Some(key) != lower {
+                state.report.add_damage(state.file_name, offset, DamageKind::KeyOutOfBounds, String::from("Node doesn't start with its parent's key for it"));
+            } else if lower.map_or(false, |l| less(key, l)) || upper.map_or(false, |u| less(u, key)) {
+                state.report.add_damage(state.file_name, offset, DamageKind::KeyOutOfBounds, format!("Child {} is outside the bounds given by the parent", i));
+            }
+
+            if i > 0 && less(key, &children[i-1].0) {
+                state.report.add_damage(state.file_name, offset, DamageKind::OutOfOrder, format!("Child {} is smaller than the child before it", i));
+            }
+
+            // records for a key can span leaves, so a child's upper bound is inclusive
+            let child_upper = children.get(i + 1).map(|c| &c.0).or(upper);
+
+            self.verify_node(state, child, height - 1, Some(key), child_upper);
+        }
+    }
+
+    fn verify_leaf(&self, state: &mut VerifyState<K,V>, offset: u64, buff: &[u8], lower: Option<&K>, upper: Option<&K>) {
+        let leaf = match Leaf::new(buff) {
+            Ok(leaf) => leaf,
+            Err(e) => return state.report.add_damage(state.file_name, offset, DamageKind::Corrupt, e.to_string())
+        };
+
+        for (i, record) in leaf.iter_from(0).enumerate() {
+            let (key_buff, value_buff) = match record {
+                Ok(record) => record,
+                Err(e) => return state.report.add_damage(state.file_name, offset, DamageKind::Corrupt, e.to_string())
+            };
+
+            let (key, value) = match (KC::decode(&key_buff), VC::decode(value_buff)) {
+                (Ok(key), Ok(value)) => (key, value),
+                _ => return state.report.add_damage(state.file_name, offset, DamageKind::Corrupt, format!("Record {} can't be decoded", i))
+            };
+
+            state.records += 1;
+            state.report.records += 1;
+
+            if i == 0 && lower.is_some() && Some(&key) != lower {
+                state.report.add_damage(state.file_name, offset, DamageKind::KeyOutOfBounds, String::from("Leaf doesn't start with its parent's key for it"));
+            } else if lower.map_or(false, |l| C::compare(&key, l) == Ordering::Less) || upper.map_or(false, |u| C::compare(&key, u) == Ordering::Greater) {
+                state.report.add_damage(state.file_name, offset, DamageKind::KeyOutOfBounds, format!("Record {} is outside the bounds given by the parent", i));
+            }
+
+            if let Some(ref bloom) = self.bloom {
+                if !bloom.may_contain(&key_buff) {
+                    state.report.add_damage(state.file_name, offset, DamageKind::MissingFromBloom, format!("Record {} is ruled out by the bloom filter", i));
+                }
+            }
+
+            if let Some((ref last_key, ref last_value)) = state.last {
+                if compare_records::<K,V,C>((&key, &value), (last_key, last_value)) == Ordering::Less {
+                    state.report.add_damage(state.file_name, offset, DamageKind::OutOfOrder, format!("Record {} is smaller than the record before it", i));
+                }
+            }
+
+            state.last = Some((key, value));
+        }
+    }
+
+    /// Compares a key read from the file with the key being looked up, given both
+    /// decoded & encoded. Keys stored with an order preserving codec, and ordered by
+    /// their Ord, are compared as bytes; anything else is decoded and compared with
+    /// the comparator.
+    fn compare_key(stored: &[u8], key_buff: &[u8], key: &K) -> Result<Ordering, Box<Error>> {
+        if KC::preserves_order() && C::orders_like_ord() {
+            return Ok(stored.cmp(key_buff));
+        }
+
+        return Ok(C::compare(&try!(KC::decode(stored)), key));
     }
 
-/*
-    fn get(&self, key: &K) -> bool { //Box<Filter<RecordFileIterator<K,V>, fn(KeyValuePair<K,V>) -> bool>> {
-        // return Box::new(self.into_iter().filter(|rec| &rec.key == key));
-        return true;
+    /// Binary searches the restart points of a leaf for the last one before the key,
+    /// as records for the key may start before a restart point holding the key
+    fn find_restart(leaf: &Leaf, key_buff: &[u8], key: &K) -> Result<usize, Box<Error>> {
+        let mut low = 0;
+        let mut high = leaf.num_restarts();
+
+        while low < high {
+            let mid = (low + high) / 2;
+
+            if try!(OnDiskBTree::<K,V,KC,VC,C>::compare_key(&try!(leaf.restart_key(mid)), key_buff, key)) == Ordering::Less {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+
+        return Ok(if low == 0 { 0 } else { low - 1 });
     }
-*/
 
-    pub fn contains_key(&self, key: &K) -> bool {
-        return true;
+    /// Reads the records of the leaf at the given offset, and the offset of the following leaf
+    fn read_leaf(&self, offset: u64) -> Result<(Vec<KeyValuePair<K,V>>, u64), Box<Error>> {
+        let (buff, next_leaf) = try!(self.read_node(offset));
+        let leaf = try!(Leaf::new(&buff));
+        let mut records = Vec::new();
+
+        for record in leaf.iter_from(0) {
+            let (key_buff, value_buff) = try!(record);
+
+            records.push(KeyValuePair{key: try!(KC::decode(&key_buff)), value: try!(VC::decode(value_buff))});
+        }
+
+        return Ok((records, next_leaf));
     }
 }
 
-impl <'a, K: KeyType, V: ValueType> IntoIterator for &'a mut OnDiskBTree<K,V> {
-    type Item = KeyValuePair<K,V>;
-    type IntoIter = OnDiskBTreeIterator<'a, K,V>;
+impl <'a, K: KeyType, V: ValueType, KC: Codec<K>, VC: Codec<V>, C: Comparator<K>> IntoIterator for &'a OnDiskBTree<K,V,KC,VC,C> {
+    type Item = Result<KeyValuePair<K,V>, Box<Error>>;
+    type IntoIter = OnDiskBTreeIterator<'a,K,V,KC,VC,C>;
 
     fn into_iter(self) -> Self::IntoIter {
-        OnDiskBTreeIterator{record_iterator: self.file.into_iter()}
+        OnDiskBTreeIterator{tree: self, next_leaf: HEADER_SIZE, records: Vec::new().into_iter()}
     }
 }
 
-impl <'a, K: KeyType, V: ValueType> Iterator for OnDiskBTreeIterator<'a,K,V> {
-    type Item = KeyValuePair<K,V>;
+/// Iterates over the records in order, reading a leaf at a time. A leaf that can't be
+/// read or decoded is an error, ending the iteration.
+impl <'a, K: KeyType, V: ValueType, KC: Codec<K>, VC: Codec<V>, C: Comparator<K>> Iterator for OnDiskBTreeIterator<'a,K,V,KC,VC,C> {
+    type Item = Result<KeyValuePair<K,V>, Box<Error>>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        return self.record_iterator.next();
+        loop {
+            if let Some(kv) = self.records.next() {
+                return Some(Ok(kv));
+            }
+
+            let leaf_end = match self.tree.footer {
+                Some(ref footer) => footer.leaf_end,
+                None => return None
+            };
+
+            if self.next_leaf >= leaf_end {
+                return None;
+            }
+
+            match self.tree.read_leaf(self.next_leaf) {
+                Ok((records, next_leaf)) => {
+                    self.records = records.into_iter();
+                    self.next_leaf = next_leaf;
+                },
+                Err(e) => {
+                    self.next_leaf = leaf_end;
+                    return Some(Err(e));
+                }
+            }
+        }
     }
 }
 
 
+impl <K: KeyType, V: ValueType, KC: Codec<K>, VC: Codec<V>, C: Comparator<K>> OnDiskBTreeBuilder<K,V,KC,VC,C> {
+    /// Creates a new tree file at the given path, replacing any existing file. If map
+    /// is true the file is marked as holding a single value for each key, and a second
+    /// value for a key is refused.
+    pub fn new(file_path: String, options: &Options, map: bool) -> Result<OnDiskBTreeBuilder<K,V,KC,VC,C>, Box<Error>> {
+        if C::name().len() > COMPARATOR_SIZE {
+            return Err(From::from(IOError::new(ErrorKind::InvalidInput, format!("Comparator name {} is longer than {} bytes", C::name(), COMPARATOR_SIZE))));
+        }
+
+        let mut fd = try!(OpenOptions::new().write(true).create(true).truncate(true).open(&file_path));
+        let mut comparator = C::name().as_bytes().to_vec();
+
+        comparator.resize(COMPARATOR_SIZE, 0);
+
+        try!(fd.write_all(FILE_HEADER.as_bytes()));
+        try!(fd.write_all(&[CURRENT_VERSION]));
+        try!(fd.write_all(&comparator));
+        try!(fd.write_all(&[if map { MAP_MODE } else { MULTI_MODE }]));
+
+        // a key can only be found in the bloom filter by its exact encoding
+        let bloom_bits_per_key = if C::equal_only_if_identical() { options.bloom_bits_per_key } else { 0 };
+
+        return Ok(OnDiskBTreeBuilder{fd: fd,
+                                     map: map,
+                                     bloom_bits_per_key: bloom_bits_per_key,
+                                     block_size: options.block_size,
+                                     compression_per_level: options.compression_per_level.clone(),
+                                     offset: HEADER_SIZE,
+                                     leaf: LeafBuilder::new(),
+                                     leaf_key: None,
+                                     leaves: Vec::new(),
+                                     last_key: None,
+                                     last_value: None,
+                                     key_hashes: Vec::new(),
+                                     record_count: 0,
+                                     compression_stats: CompressionStats::default(),
+                                     _kc_marker: PhantomData,
+                                     _vc_marker: PhantomData,
+                                     _c_marker: PhantomData});
+    }
+
+    /// Adds the next record, which must not be smaller than the previous one. A record
+    /// out of order is refused, leaving the file unfinished.
+    pub fn insert_record(&mut self, kv: &KeyValuePair<K,V>) -> Result<(), Box<Error>> {
+        if let (Some(last_key), Some(last_value)) = (self.last_key.as_ref(), self.last_value.as_ref()) {
+            if compare_records::<K,V,C>((&kv.key, &kv.value), (last_key, last_value)) == Ordering::Less {
+                return Err(From::from(IOError::new(ErrorKind::InvalidInput, format!("Record {} is out of order", self.record_count + 1))));
+            }
+
+            if self.map && C::compare(&kv.key, last_key) == Ordering::Equal {
+                return Err(From::from(IOError::new(ErrorKind::InvalidInput, format!("Record {} is a second value for a key in a map", self.record_count + 1))));
+            }
+        }
+
+        let key_buff = try!(encode_to_vec::<K,KC>(&kv.key));
+
+        self.leaf.add(&key_buff, &try!(encode_to_vec::<V,VC>(&kv.value)));
+
+        if self.leaf_key.is_none() {
+            self.leaf_key = Some(kv.key.clone());
+        }
+
+        if self.last_key.as_ref().map_or(true, |last_key| C::compare(last_key, &kv.key) != Ordering::Equal) {
+            if self.bloom_bits_per_key > 0 {
+                self.key_hashes.push(BloomFilter::hash(&key_buff));
+            }
+
+            self.last_key = Some(kv.key.clone());
+        }
+
+        self.last_value = Some(kv.value.clone());
+        self.record_count += 1;
+
+        if self.leaf.size() >= self.block_size {
+            try!(self.write_leaf());
+        }
+
+        Ok( () )
+    }
+
+    /// Writes the internal nodes, bloom filter & footer and syncs the file. A tree
+    /// without any records is left as an empty file.
+    pub fn finish(mut self) -> Result<(), Box<Error>> {
+        if self.record_count == 0 {
+            try!(self.fd.set_len(0));
+            return Ok(try!(self.fd.sync_all()));
+        }
+
+        try!(self.write_leaf());
 
+        let leaf_end = self.offset;
 
-/*
+        // build the internal levels from the bottom up until there is a single root
+        let mut level = ::std::mem::replace(&mut self.leaves, Vec::new());
+        let mut height = 0;
 
-        // check to see if this is a new file
-        if metadata.len() == 0 {
-            // write out our header
-            try!(tree_file.write(FILE_HEADER.as_bytes()));
+        while level.len() > 1 || height == 0 {
+            let mut parents = Vec::new();
 
-            // write out our version
-            try!(tree_file.write(&[CURRENT_VERSION]));
+            for children in level.chunks(NUM_CHILDREN) {
+                let buff = try!(encode_children::<K,KC>(children));
 
-            // construct and return our BTree object
-            Ok(BTree{tree_file_path: tree_file_path,
-                     tree_file: tree_file,
-                     wal_file: wal_file,
-                     root: None,
-                     max_key_size: max_key_size,
-                     max_value_size: max_value_size,
-                     mem_tree: mem_tree
-            })
+                parents.push((children[0].0.clone(), try!(self.write_node(&buff, height + 1))));
+            }
+
+            level = parents;
+            height += 1;
+        }
+
+        let bloom = if self.bloom_bits_per_key > 0 {
+            let filter = BloomFilter::from_hashes(&self.key_hashes, self.bloom_bits_per_key);
+
+            try!(self.write_block(&filter.to_bytes()))
         } else {
-            let mut version_string = vec![0; 8];
+            0
+        };
+
+        let footer = Footer{root: level[0].1,
+                            height: height as u64,
+                            leaf_end: leaf_end,
+                            bloom: bloom,
+                            record_count: self.record_count,
+                            raw_bytes: self.compression_stats.raw_bytes,
+                            stored_bytes: self.compression_stats.stored_bytes};
+
+        let footer_buff = try!(encode(&footer, SizeLimit::Bounded(FOOTER_SIZE - 4)));
+
+        try!(self.fd.write_all(&footer_buff));
+        try!(self.fd.write_u32::<BigEndian>(checksum(&footer_buff)));
+
+        Ok(try!(self.fd.sync_all()))
+    }
+
+    fn write_leaf(&mut self) -> Result<(), Box<Error>> {
+        if self.leaf.is_empty() {
+            return Ok( () );
+        }
+
+        let buff = self.leaf.finish();
+        let offset = try!(self.write_node(&buff, 0));
+
+        self.leaves.push((self.leaf_key.take().unwrap(), offset));
+
+        Ok( () )
+    }
 
-            try!(tree_file.read_exact(&mut version_string));
+    /// Compresses & writes a node at the given level, returning its offset
+    fn write_node(&mut self, buff: &[u8], level: usize) -> Result<u64, Box<Error>> {
+        let block = try!(level_compression(&self.compression_per_level, level).compress(buff));
 
-            // make sure we've opened a proper file
-            if try!(str::from_utf8(&version_string[0..FILE_HEADER.len()])) != FILE_HEADER ||
-               version_string[FILE_HEADER.len()] != CURRENT_VERSION {
-                return Err(From::from(std::io::Error::new(ErrorKind::InvalidData, "Invalid BTree file or BTree version")));
+        self.compression_stats.raw_bytes += buff.len() as u64;
+        self.compression_stats.stored_bytes += block.len() as u64;
+
+        return self.write_block(&block);
+    }
+
+    /// Writes a block, returning its offset
+    fn write_block(&mut self, buff: &[u8]) -> Result<u64, Box<Error>> {
+        let offset = self.offset;
+
+        try!(self.fd.write_u32::<BigEndian>(buff.len() as u32));
+        try!(self.fd.write_all(buff));
+        try!(self.fd.write_u32::<BigEndian>(checksum(buff)));
+
+        self.offset += 8 + buff.len() as u64;
+
+        return Ok(offset);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use tests::gen_temp_name;
+    use std::fs;
+    use wal_file::KeyValuePair;
+    use disk_btree::{OnDiskBTree, OnDiskBTreeBuilder, HEADER_SIZE, build_tree_file};
+    use codec::Bincode;
+    use ordered::Ordered;
+    use comparator::Ascending;
+    use block_cache::BlockCache;
+    use compression::Compression;
+    use options::Options;
+    use verify::{DamageKind, VerifyReport};
+    use std::fs::OpenOptions;
+    use std::io::{Seek, SeekFrom, Write};
+    use std::sync::Arc;
+
+    fn uncached() -> Options {
+        Options{block_cache: None, ..Options::default()}
+    }
+
+    fn build_tree(file_path: &String, num_keys: u32, bloom_bits_per_key: usize, block_size: usize) -> OnDiskBTree<u32, u32, Bincode, Bincode, Ascending> {
+        let options = Options{bloom_bits_per_key: bloom_bits_per_key, block_size: block_size, ..Options::default()};
+        let mut builder = OnDiskBTreeBuilder::<u32, u32, Bincode, Bincode, Ascending>::new(file_path.to_owned(), &options, false).unwrap();
+
+        // every even key has 3 values so some keys span leaves
+        for key in 0..num_keys {
+            for value in 0..3 {
+                builder.insert_record(&KeyValuePair{key: key * 2, value: value}).unwrap();
             }
+        }
 
-            let mut buff = vec![0; node_size];
+        builder.finish().unwrap();
 
-            // make sure we have a root node to read
-            if metadata.len() < (version_string.len() + node_size) as u64 {
-                // if we don't have a root node yet, just return
-                return Ok(BTree{tree_file_path: tree_file_path,
-                                tree_file: tree_file,
-                                wal_file: wal_file,
-                                root: None,
-                                max_key_size: max_key_size,
-                                max_value_size: max_value_size,
-                                mem_tree: mem_tree
-                });
+        return OnDiskBTree::new(file_path.to_owned(), &uncached()).unwrap();
+    }
+
+    #[test]
+    fn get_descends_tree() {
+        let file_path = gen_temp_name();
+        let tree = build_tree(&file_path, 2000, 10, 64);
+
+        assert!(tree.count().unwrap() == 6000);
+
+        for key in 0..2000 {
+            assert_eq!(tree.get(&(key * 2)).unwrap(), vec![0, 1, 2]);
+            assert!(!tree.contains_key(&(key * 2 + 1)).unwrap());
+        }
+
+        // almost every missing key should have been ruled out by the bloom filter
+        let stats = tree.bloom_filter_stats();
+
+        assert_eq!(stats.lookups, 4000);
+        assert!(stats.negatives > 1900);
+        assert_eq!(stats.negatives + stats.false_positives, 2000);
+
+        fs::remove_file(&file_path).unwrap();
+    }
+
+    #[test]
+    fn get_compares_ordered_keys() {
+        let file_path = gen_temp_name();
+        let options = Options{bloom_bits_per_key: 0, block_size: 64, ..Options::default()};
+        let mut builder = OnDiskBTreeBuilder::<(String, i64), u32, Ordered, Bincode, Ascending>::new(file_path.to_owned(), &options, false).unwrap();
+
+        // negative numbers & keys that prefix each other would be misordered as bincode bytes
+        for name in &["a", "a\0", "ab", "b"] {
+            for num in -300..300 {
+                builder.insert_record(&KeyValuePair{key: (name.to_string(), num), value: 1}).unwrap();
             }
+        }
 
-            // seek node_size in from the end of the file to read the root node
-            try!(tree_file.seek(SeekFrom::End((node_size as isize * -1) as i64)));
-            try!(tree_file.read_exact(&mut buff));
+        builder.finish().unwrap();
 
-            let root_node: Node<K,V> = try!(decode(&buff[..]));
+        let tree = OnDiskBTree::<(String, i64), u32, Ordered, Bincode, Ascending>::new(file_path.to_owned(), &uncached()).unwrap();
 
-            Ok(BTree{tree_file_path: tree_file_path,
-                     tree_file: tree_file,
-                     wal_file: wal_file,
-                     root: Some(root_node),
-                     max_key_size: max_key_size,
-                     max_value_size: max_value_size,
-                     mem_tree: mem_tree
-            })
+        for name in &["a", "a\0", "ab", "b"] {
+            for num in -300..300 {
+                assert_eq!(tree.get(&(name.to_string(), num)).unwrap(), vec![1]);
+            }
         }
 
+        assert!(tree.get(&(String::from("a"), 300)).unwrap().is_empty());
+        assert!(tree.get(&(String::from("aa"), 0)).unwrap().is_empty());
+        assert_eq!(tree.into_iter().next().unwrap().unwrap().key, (String::from("a"), -300));
+
+        fs::remove_file(&file_path).unwrap();
+    }
+
+    #[test]
+    fn iterate_in_order() {
+        let file_path = gen_temp_name();
+        let tree = build_tree(&file_path, 100, 0, 64);
 
-*/
+        let records: Vec<(u32, u32)> = tree.into_iter().map(|kv| kv.map(|kv| (kv.key, kv.value))).collect::<Result<_, _>>().unwrap();
+
+        assert_eq!(records.len(), 300);
+        assert_eq!(records[0], (0, 0));
+        assert_eq!(records[299], (198, 2));
+
+        assert!(tree.get(&7).unwrap().is_empty());
+        assert_eq!(tree.bloom_filter_stats().lookups, 0);
+
+        fs::remove_file(&file_path).unwrap();
+    }
+
+    #[test]
+    fn empty_tree() {
+        let file_path = gen_temp_name();
+
+        OnDiskBTreeBuilder::<u32, u32, Bincode, Bincode, Ascending>::new(file_path.to_owned(), &Options::default(), false).unwrap().finish().unwrap();
+
+        let tree = OnDiskBTree::<u32, u32, Bincode, Bincode, Ascending>::new(file_path.to_owned(), &uncached()).unwrap();
+
+        assert!(tree.is_new().unwrap());
+        assert!(tree.count().unwrap() == 0);
+        assert!(tree.get(&1).unwrap().is_empty());
+        assert!(tree.into_iter().next().is_none());
+
+        fs::remove_file(&file_path).unwrap();
+    }
+
+    #[test]
+    fn reads_through_cache() {
+        let file_path = gen_temp_name();
+
+        build_tree(&file_path, 100, 10, 4096);
+
+        let cache = Arc::new(BlockCache::new(1 << 20));
+        let options = Options{block_cache: Some(cache.clone()), ..Options::default()};
+        let tree = OnDiskBTree::<u32, u32, Bincode, Bincode, Ascending>::new(file_path.to_owned(), &options).unwrap();
+
+        // the root & one leaf are read from disk, then served from the cache
+        assert_eq!(tree.get(&10).unwrap(), vec![0, 1, 2]);
+
+        let stats = cache.stats();
+
+        assert_eq!(stats.hits, 0);
+        assert_eq!(stats.misses, 2);
+
+        assert_eq!(tree.get(&10).unwrap(), vec![0, 1, 2]);
+
+        let stats = cache.stats();
+
+        assert_eq!(stats.hits, 2);
+        assert_eq!(stats.misses, 2);
+        assert_eq!(stats.blocks, 2);
+
+        fs::remove_file(&file_path).unwrap();
+    }
+
+    #[test]
+    fn reads_through_mmap() {
+        let file_path = gen_temp_name();
+
+        build_tree(&file_path, 100, 10, 64);
+
+        let cache = Arc::new(BlockCache::new(1 << 20));
+        let options = Options{block_cache: Some(cache.clone()), use_mmap: true, ..Options::default()};
+        let tree = OnDiskBTree::<u32, u32, Bincode, Bincode, Ascending>::new(file_path.to_owned(), &options).unwrap();
+
+        assert_eq!(tree.get(&10).unwrap(), vec![0, 1, 2]);
+        assert_eq!(tree.into_iter().count(), 300);
+
+        // the cache is bypassed entirely
+        assert_eq!(cache.stats().misses, 0);
+
+        fs::remove_file(&file_path).unwrap();
+    }
+
+    #[test]
+    fn compresses_leaves() {
+        let file_path = gen_temp_name();
+        let options = Options{compression_per_level: vec![Compression::Zstd, Compression::Snappy], ..uncached()};
+        let mut builder = OnDiskBTreeBuilder::<String, String, Bincode, Bincode, Ascending>::new(file_path.to_owned(), &options, false).unwrap();
+
+        for key in 0..1000 {
+            builder.insert_record(&KeyValuePair{key: format!("tenant-0001/users/{:08}", key), value: String::from("active")}).unwrap();
+        }
+
+        builder.finish().unwrap();
+
+        let tree = OnDiskBTree::<String, String, Bincode, Bincode, Ascending>::new(file_path.to_owned(), &options).unwrap();
+
+        // large leaves are searched from their restart points
+        for key in (0..1000).filter(|k| k % 7 == 0) {
+            assert_eq!(tree.get(&format!("tenant-0001/users/{:08}", key)).unwrap(), vec![String::from("active")]);
+        }
+
+        assert!(tree.get(&String::from("tenant-0001/users/")).unwrap().is_empty());
+        assert_eq!(tree.into_iter().count(), 1000);
+
+        let stats = tree.compression_stats();
+
+        assert!(stats.ratio() > 3.0, "compression ratio {}", stats.ratio());
+        assert!(fs::metadata(&file_path).unwrap().len() < stats.raw_bytes / 3);
+
+        fs::remove_file(&file_path).unwrap();
+    }
+
+    #[test]
+    fn verify_clean_tree() {
+        let file_path = gen_temp_name();
+        let tree = build_tree(&file_path, 2000, 10, 64);
+        let mut report = VerifyReport::default();
+
+        tree.verify("tree", &mut report);
+
+        assert!(report.is_ok(), "{:?}", report.damage);
+        assert_eq!(report.files, 1);
+        assert_eq!(report.records, 6000);
+        assert!(report.blocks > 100);
+
+        fs::remove_file(&file_path).unwrap();
+    }
+
+    #[test]
+    fn detects_flipped_bit() {
+        let file_path = gen_temp_name();
+
+        build_tree(&file_path, 100, 10, 64);
+
+        // flip a bit inside the first leaf
+        {
+            let mut fd = OpenOptions::new().read(true).write(true).open(&file_path).unwrap();
+
+            fd.seek(SeekFrom::Start(HEADER_SIZE + 6)).unwrap();
+            fd.write_all(&[0xff]).unwrap();
+        }
+
+        let tree = OnDiskBTree::<u32, u32, Bincode, Bincode, Ascending>::new(file_path.to_owned(), &uncached()).unwrap();
+
+        assert!(tree.get(&0).is_err());
+        assert_eq!(tree.get(&198).unwrap(), vec![0, 1, 2]);
+
+        let mut report = VerifyReport::default();
+
+        tree.verify("tree", &mut report);
+
+        assert_eq!(report.damage.len(), 2);
+        assert_eq!(report.damage[0].kind, DamageKind::Checksum);
+        assert_eq!(report.damage[0].offset, HEADER_SIZE);
+        assert_eq!(report.damage[1].kind, DamageKind::RecordCount);
+
+        fs::remove_file(&file_path).unwrap();
+    }
+
+    #[test]
+    fn detects_bad_block_length() {
+        let file_path = gen_temp_name();
+
+        build_tree(&file_path, 100, 10, 64);
+
+        // give the first leaf a length far past the end of the file
+        {
+            let mut fd = OpenOptions::new().read(true).write(true).open(&file_path).unwrap();
+
+            fd.seek(SeekFrom::Start(HEADER_SIZE)).unwrap();
+            fd.write_all(&[0xff; 4]).unwrap();
+        }
+
+        let tree = OnDiskBTree::<u32, u32, Bincode, Bincode, Ascending>::new(file_path.to_owned(), &uncached()).unwrap();
+
+        assert!(tree.get(&0).unwrap_err().to_string().contains("running past the end"));
+
+        let mut report = VerifyReport::default();
+
+        tree.verify("tree", &mut report);
+
+        assert_eq!(report.damage[0].kind, DamageKind::Corrupt);
+        assert_eq!(report.damage[0].offset, HEADER_SIZE);
+
+        fs::remove_file(&file_path).unwrap();
+    }
+
+    #[test]
+    fn salvage_skips_bad_leaves() {
+        let file_path = gen_temp_name();
+
+        build_tree(&file_path, 100, 10, 64);
+
+        // clobber the length of the second leaf so its end can't be found
+        let (second_leaf, third_leaf, root) = {
+            let tree = OnDiskBTree::<u32, u32, Bincode, Bincode, Ascending>::new(file_path.to_owned(), &uncached()).unwrap();
+            let (_, second_leaf) = tree.read_leaf(HEADER_SIZE).unwrap();
+            let (_, third_leaf) = tree.read_leaf(second_leaf).unwrap();
+
+            (second_leaf, third_leaf, tree.footer.as_ref().unwrap().root)
+        };
+
+        let mut fd = OpenOptions::new().read(true).write(true).open(&file_path).unwrap();
+
+        fd.seek(SeekFrom::Start(second_leaf)).unwrap();
+        fd.write_all(&[0xff, 0xff]).unwrap();
+
+        // the next leaf is found in the internal nodes
+        let salvage = OnDiskBTree::<u32, u32, Bincode, Bincode, Ascending>::salvage(&file_path).unwrap();
+
+        assert_eq!(salvage.blocks_lost, 1);
+        assert_eq!(salvage.bytes_lost, third_leaf - second_leaf);
+        assert_eq!(salvage.expected_records, Some(300));
+        assert!(salvage.records.len() > 280 && salvage.records.len() < 300);
+        assert!(salvage.records.windows(2).all(|w| w[0] <= w[1]));
+
+        // or by scanning for it, without the root
+        fd.seek(SeekFrom::Start(root + 8)).unwrap();
+        fd.write_all(&[0xff, 0xff]).unwrap();
+
+        let scanned = OnDiskBTree::<u32, u32, Bincode, Bincode, Ascending>::salvage(&file_path).unwrap();
+
+        assert_eq!(scanned.blocks_lost, 1);
+        assert_eq!(scanned.bytes_lost, third_leaf - second_leaf);
+        assert!(scanned.records == salvage.records);
+
+        fs::remove_file(&file_path).unwrap();
+    }
+
+    #[test]
+    fn map_keeps_one_value_per_key() {
+        let file_path = gen_temp_name();
+
+        {
+            let mut builder = OnDiskBTreeBuilder::<u32, u32, Bincode, Bincode, Ascending>::new(file_path.to_owned(), &uncached(), true).unwrap();
+
+            builder.insert_record(&KeyValuePair{key: 1, value: 1}).unwrap();
+            assert!(builder.insert_record(&KeyValuePair{key: 1, value: 2}).is_err());
+        }
+
+        // the last value given for a key is kept
+        assert_eq!(build_tree_file::<u32, u32, Bincode, Bincode, Ascending, _>(&file_path, vec![(1, 5), (1, 7), (2, 3)], &uncached(), true).unwrap(), 2);
+
+        let tree = OnDiskBTree::<u32, u32, Bincode, Bincode, Ascending>::new(file_path.to_owned(), &uncached()).unwrap();
+
+        assert_eq!(tree.is_map(), Some(true));
+        assert_eq!(tree.get(&1).unwrap(), [7]);
+
+        fs::remove_file(&file_path).unwrap();
+    }
+}
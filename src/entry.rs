@@ -0,0 +1,179 @@
+use ::{BTree, KeyType, ValueType, Codec, Comparator};
+
+use std::collections::BTreeSet;
+use std::error::Error;
+
+/// A key's values, read from the tree to be changed & written back, like the Entry of
+/// a BTreeMap. The tree is borrowed until the entry is written, so no other change can
+/// come between the read & the write; other processes are kept out by the tree's lock.
+/// Changes are only made to the entry's values until or_insert, or_insert_with or
+/// commit logs them to the WAL, with a single write that is seen whole or not at all.
+#[must_use]
+pub struct Entry<'a, K: KeyType, V: ValueType, KC: Codec<K>, VC: Codec<V>, C: Comparator<K>> {
+    tree: &'a mut BTree<K,V,KC,VC,C>,
+    key: K,
+    stored: BTreeSet<V>,  // the values in the tree when the entry was read
+    values: BTreeSet<V>,  // the values to write
+}
+
+impl <'a, K: KeyType, V: ValueType, KC: Codec<K>, VC: Codec<V>, C: Comparator<K>> Entry<'a,K,V,KC,VC,C> {
+    pub fn new(tree: &'a mut BTree<K,V,KC,VC,C>, key: K) -> Result<Entry<'a,K,V,KC,VC,C>, Box<Error>> {
+        let stored: BTreeSet<V> = try!(tree.get(&key)).collect();
+
+        return Ok(Entry{tree: tree, key: key, values: stored.clone(), stored: stored});
+    }
+
+    pub fn key(&self) -> &K {
+        return &self.key;
+    }
+
+    /// Returns true if the key had values in the tree when the entry was read
+    pub fn is_occupied(&self) -> bool {
+        return !self.stored.is_empty();
+    }
+
+    /// Returns the values to be written, with any changes made so far
+    pub fn values(&self) -> &BTreeSet<V> {
+        return &self.values;
+    }
+
+    pub fn values_mut(&mut self) -> &mut BTreeSet<V> {
+        return &mut self.values;
+    }
+
+    /// Adds a value to the key, returning false if it was already there
+    pub fn insert_value(&mut self, value: V) -> bool {
+        return self.values.insert(value);
+    }
+
+    /// Removes a value of the key, returning false if it wasn't there
+    pub fn remove_value(&mut self, value: &V) -> bool {
+        return self.values.remove(value);
+    }
+
+    /// Changes the values with the given function if the key had values
+    pub fn and_modify<F: FnOnce(&mut BTreeSet<V>)>(mut self, f: F) -> Entry<'a,K,V,KC,VC,C> {
+        if self.is_occupied() {
+            f(&mut self.values);
+        }
+
+        return self;
+    }
+
+    /// Inserts the value if the key had no values, then writes the entry, returning the
+    /// key's values
+    pub fn or_insert(self, value: V) -> Result<BTreeSet<V>, Box<Error>> {
+        return self.or_insert_with(|| value);
+    }
+
+    /// Same as or_insert, but the value is only made if it is needed
+    pub fn or_insert_with<F: FnOnce() -> V>(mut self, f: F) -> Result<BTreeSet<V>, Box<Error>> {
+        if !self.is_occupied() {
+            self.values.insert(f());
+        }
+
+        return self.commit();
+    }
+
+    /// Writes the changes made to the entry, returning the key's values. A single insert
+    /// or delete is logged as it is; anything more replaces the key's values, so the
+    /// change is one group of records in the WAL.
+    pub fn commit(self) -> Result<BTreeSet<V>, Box<Error>> {
+        let added: Vec<V> = self.values.difference(&self.stored).cloned().collect();
+        let removed: Vec<V> = self.stored.difference(&self.values).cloned().collect();
+
+        if added.is_empty() && removed.is_empty() {
+            return Ok(self.values);
+        }
+
+        // in a map an insert replaces the key's value, but a map can't take a second one
+        let single_insert = if self.tree.is_map() { self.values.len() == 1 } else { added.len() == 1 && removed.is_empty() };
+
+        if single_insert {
+            try!(self.tree.insert(self.key, added[0].clone()));
+        } else if added.is_empty() && removed.len() == 1 {
+            try!(self.tree.delete(self.key, removed[0].clone()));
+        } else {
+            try!(self.tree.replace(self.key, self.values.iter().cloned()));
+        }
+
+        return Ok(self.values);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use tests::gen_temp_name;
+    use ::{BTree, WalRecord};
+    use map::MapBTree;
+    use std::fs;
+
+    #[test]
+    fn read_modify_write() {
+        let dir_path = gen_temp_name();
+
+        {
+            let mut btree = BTree::<u32, u32>::open(&dir_path, 4, 4).unwrap();
+
+            btree.insert(1, 1).unwrap();
+            btree.compact().unwrap();
+
+            // the values on disk are changed through the entry like those in memory
+            assert_eq!(btree.entry(1).unwrap().and_modify(|values| { values.insert(2); }).or_insert(7).unwrap().into_iter().collect::<Vec<_>>(), [1, 2]);
+            assert_eq!(btree.entry(2).unwrap().and_modify(|values| { values.insert(2); }).or_insert(7).unwrap().into_iter().collect::<Vec<_>>(), [7]);
+
+            {
+                let mut entry = btree.entry(1).unwrap();
+
+                assert!(entry.is_occupied());
+                assert!(entry.remove_value(&1));
+                assert!(!entry.remove_value(&1));
+                assert!(entry.insert_value(3));
+                entry.values_mut().insert(4);
+                entry.commit().unwrap();
+            }
+
+            assert_eq!(btree.get(&1).unwrap().collect::<Vec<_>>(), [2, 3, 4]);
+
+            // writing an unchanged entry logs nothing
+            let last_sequence = btree.last_sequence();
+
+            btree.entry(1).unwrap().commit().unwrap();
+
+            assert_eq!(btree.last_sequence(), last_sequence);
+
+            // a change to several values is logged as one replace
+            let records = btree.wal_records().unwrap();
+
+            match records[records.len() - 4].1 {
+                WalRecord::Replace(1, 3) => (),
+                ref record => panic!("Expected a replace, found {:?}", record)
+            }
+        }
+
+        {
+            let btree = BTree::<u32, u32>::open(&dir_path, 4, 4).unwrap();
+
+            assert_eq!(btree.get(&1).unwrap().collect::<Vec<_>>(), [2, 3, 4]);
+            assert_eq!(btree.get(&2).unwrap().collect::<Vec<_>>(), [7]);
+        }
+
+        fs::remove_dir_all(&dir_path).unwrap();
+
+        // in a map the entry holds the key's single value
+        {
+            let mut map = MapBTree::<u32, u32>::open(&dir_path, 4, 4).unwrap();
+
+            for _ in 0..3 {
+                map.tree_mut().entry(5).unwrap().and_modify(|values| { let count = values.iter().next().unwrap() + 1; values.clear(); values.insert(count); }).or_insert(1).unwrap();
+            }
+
+            assert_eq!(map.get(&5).unwrap(), Some(3));
+            assert!(map.tree_mut().entry(5).unwrap().and_modify(|values| { values.insert(9); }).or_insert(1).is_err());
+            assert_eq!(map.get(&5).unwrap(), Some(3));
+        }
+
+        fs::remove_dir_all(&dir_path).unwrap();
+    }
+}
@@ -0,0 +1,272 @@
+use rustc_serialize::{Encodable, Decodable};
+use rustc_serialize::json::{self, Json};
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::io::{BufRead, ErrorKind, Lines, Write};
+use std::io::Error as IOError;
+use std::iter;
+use std::marker::PhantomData;
+use std::mem;
+
+use ::{KeyType, ValueType};
+
+/// The text formats records can be exported to & imported from
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ExportFormat {
+    JsonLines,  // one {"key": ..., "value": ...} object per line, keys & values encoded as JSON
+    Csv,        // a key,value header then one row per record; strings are written as is, anything else as JSON
+}
+
+fn to_json<T: Encodable>(t: &T) -> Result<Json, Box<Error>> {
+    return Ok(try!(Json::from_str(&try!(json::encode(t)))));
+}
+
+fn from_json<T: Decodable>(j: Json) -> Result<T, Box<Error>> {
+    let mut decoder = json::Decoder::new(j);
+
+    return Ok(try!(Decodable::decode(&mut decoder)));
+}
+
+fn invalid_line(line: usize, msg: &str) -> Box<Error> {
+    return From::from(IOError::new(ErrorKind::InvalidData, format!("line {}: {}", line, msg)));
+}
+
+/// Quotes a CSV field if it contains a separator, quote or line break
+fn csv_field(j: Json) -> String {
+    let s = match j {
+        Json::String(s) => s,
+        j => j.to_string()
+    };
+
+    if s.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        return format!("\"{}\"", s.replace("\"", "\"\""));
+    }
+
+    return s;
+}
+
+/// Splits a CSV record into its fields, returning None if a quoted field continues on the next line
+fn parse_csv(record: &str) -> Option<Vec<String>> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = record.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if quoted {
+            if c != '"' {
+                field.push(c);
+            } else if chars.peek() == Some(&'"') {
+                field.push('"');
+                chars.next();
+            } else {
+                quoted = false;
+            }
+        } else if c == '"' {
+            quoted = true;
+        } else if c == ',' {
+            fields.push(mem::replace(&mut field, String::new()));
+        } else {
+            field.push(c);
+        }
+    }
+
+    if quoted {
+        return None;
+    }
+
+    fields.push(field);
+
+    return Some(fields);
+}
+
+/// A CSV field is taken as a string if the type decodes from one, otherwise as JSON
+fn decode_csv_field<T: Decodable>(field: &str) -> Result<T, Box<Error>> {
+    if let Ok(t) = from_json(Json::String(field.to_owned())) {
+        return Ok(t);
+    }
+
+    return from_json(try!(Json::from_str(field)));
+}
+
+/// Writes the records to the writer, returning the number written. Fails at the first
+/// record that is an error.
+pub fn write_records<K, V, W, I>(mut writer: W, format: ExportFormat, records: I) -> Result<u64, Box<Error>>
+    where K: KeyType + Encodable, V: ValueType + Encodable, W: Write, I: Iterator<Item=Result<(K,V), Box<Error>>>
+{
+    let mut count = 0;
+
+    if format == ExportFormat::Csv {
+        try!(writeln!(writer, "key,value"));
+    }
+
+    for record in records {
+        let (key, value) = try!(record);
+
+        match format {
+            ExportFormat::JsonLines => {
+                let mut obj = BTreeMap::new();
+
+                obj.insert(String::from("key"), try!(to_json(&key)));
+                obj.insert(String::from("value"), try!(to_json(&value)));
+
+                try!(writeln!(writer, "{}", Json::Object(obj)));
+            },
+            ExportFormat::Csv => {
+                try!(writeln!(writer, "{},{}", csv_field(try!(to_json(&key))), csv_field(try!(to_json(&value)))));
+            }
+        }
+
+        count += 1;
+    }
+
+    try!(writer.flush());
+
+    return Ok(count);
+}
+
+/// The records read back by read_records, in the order they were written. Reading
+/// stops at the first record that can't be decoded, which is returned as an error.
+pub struct Records<K, V, R: BufRead> {
+    lines: iter::Enumerate<Lines<R>>,
+    format: ExportFormat,
+    record: String,     // the lines of the record being read
+    first_line: usize,  // the line the record being read starts on, from 1
+    done: bool,
+    _marker: PhantomData<(K, V)>,
+}
+
+/// Reads the records written by write_records as they're needed
+pub fn read_records<K, V, R>(reader: R, format: ExportFormat) -> Records<K,V,R>
+    where K: KeyType + Decodable, V: ValueType + Decodable, R: BufRead
+{
+    return Records{lines: reader.lines().enumerate(), format: format, record: String::new(), first_line: 0, done: false, _marker: PhantomData};
+}
+
+impl <K: KeyType + Decodable, V: ValueType + Decodable, R: BufRead> Records<K,V,R> {
+    /// Decodes the record read so far, returning None if it's blank, the CSV header, or
+    /// a CSV record continuing on the next line
+    fn decode_record(&mut self) -> Result<Option<(K,V)>, Box<Error>> {
+        let first_line = self.first_line;
+
+        if self.record.trim().is_empty() {
+            self.record.clear();
+            return Ok(None);
+        }
+
+        let record = match self.format {
+            ExportFormat::JsonLines => {
+                let mut obj = match try!(Json::from_str(&self.record).map_err(|e| invalid_line(first_line, &e.to_string()))) {
+                    Json::Object(obj) => obj,
+                    _ => return Err(invalid_line(first_line, "expected an object"))
+                };
+
+                let key = try!(obj.remove("key").ok_or_else(|| invalid_line(first_line, "missing key")));
+                let value = try!(obj.remove("value").ok_or_else(|| invalid_line(first_line, "missing value")));
+
+                (try!(from_json(key).map_err(|e| invalid_line(first_line, &e.to_string()))),
+                 try!(from_json(value).map_err(|e| invalid_line(first_line, &e.to_string()))))
+            },
+            ExportFormat::Csv => {
+                let fields = match parse_csv(&self.record) {
+                    Some(fields) => fields,
+                    None => return Ok(None)
+                };
+
+                if fields.len() != 2 {
+                    return Err(invalid_line(first_line, &format!("expected 2 fields, found {}", fields.len())));
+                }
+
+                if first_line == 1 && fields[0] == "key" && fields[1] == "value" {
+                    self.record.clear();
+                    return Ok(None);
+                }
+
+                (try!(decode_csv_field(&fields[0]).map_err(|e| invalid_line(first_line, &e.to_string()))),
+                 try!(decode_csv_field(&fields[1]).map_err(|e| invalid_line(first_line, &e.to_string()))))
+            }
+        };
+
+        self.record.clear();
+
+        return Ok(Some(record));
+    }
+}
+
+impl <K: KeyType + Decodable, V: ValueType + Decodable, R: BufRead> Iterator for Records<K,V,R> {
+    type Item = Result<(K,V), Box<Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        while let Some((i, line)) = self.lines.next() {
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(From::from(e)));
+                }
+            };
+
+            // a CSV record continues over line breaks inside quotes
+            if self.record.is_empty() {
+                self.first_line = i + 1;
+                self.record = line;
+            } else {
+                self.record.push('\n');
+                self.record.push_str(&line);
+            }
+
+            match self.decode_record() {
+                Ok(Some(record)) => return Some(Ok(record)),
+                Ok(None) => continue,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+
+        self.done = true;
+
+        if !self.record.is_empty() {
+            return Some(Err(invalid_line(self.first_line, "unterminated quoted field")));
+        }
+
+        return None;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use export::{ExportFormat, read_records, write_records};
+
+    #[test]
+    fn csv_round_trip() {
+        let records = vec![(String::from("plain"), vec![1u8, 2]),
+                           (String::from("comma, \"quote\"\nand newline"), vec![])];
+
+        let mut buff = Vec::new();
+
+        assert_eq!(write_records(&mut buff, ExportFormat::Csv, records.clone().into_iter().map(Ok)).unwrap(), 2);
+        assert_eq!(String::from_utf8(buff.clone()).unwrap(),
+                   "key,value\nplain,\"[1,2]\"\n\"comma, \"\"quote\"\"\nand newline\",[]\n");
+
+        let read = read_records::<String, Vec<u8>, _>(&buff[..], ExportFormat::Csv).collect::<Result<Vec<_>, _>>().unwrap();
+
+        assert_eq!(read, records);
+
+        assert!(read_records::<String, Vec<u8>, _>(&b"key,value\nkey,\"[1"[..], ExportFormat::Csv).collect::<Result<Vec<_>, _>>().is_err());
+
+        // reading stops at the first bad record
+        let mut read = read_records::<String, u64, _>(&b"key,value\nkey,1\nkey,not a number\nkey,2\n"[..], ExportFormat::Csv);
+
+        assert_eq!(read.next().unwrap().unwrap(), (String::from("key"), 1));
+        assert!(read.next().unwrap().is_err());
+        assert!(read.next().is_none());
+    }
+}
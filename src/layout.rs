@@ -0,0 +1,165 @@
+use fs2::{FileExt, lock_contended_error};
+
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::ErrorKind;
+use std::io::Error as IOError;
+use std::path::{Path, PathBuf};
+
+/// Describes where the files making up a tree live on disk
+#[derive(Clone)]
+pub enum FileLayout {
+    /// Every file is a sibling of a single tree file path: <path>, <path>.wal,
+    /// <path>.manifest, <path>.lock, and compacted tree files <path>.1, <path>.2, ...
+    SingleFile(PathBuf, String),
+    /// Every file lives in one directory: LOCK, MANIFEST, WAL, and tree files
+    /// 000000.btr, 000001.btr, ...
+    Directory(PathBuf),
+}
+
+impl FileLayout {
+    pub fn single_file(tree_file_path: &String) -> Result<FileLayout, Box<Error>> {
+        let path = Path::new(tree_file_path);
+
+        let base_name = match path.file_name().and_then(|n| n.to_str()) {
+            Some(name) => name.to_owned(),
+            None => return Err(From::from(IOError::new(ErrorKind::InvalidInput, "Tree file path must end in a file name")))
+        };
+
+        let dir = match path.parent() {
+            Some(dir) if dir != Path::new("") => dir.to_path_buf(),
+            _ => PathBuf::from(".")
+        };
+
+        return Ok(FileLayout::SingleFile(dir, base_name));
+    }
+
+    pub fn directory(dir_path: &String) -> FileLayout {
+        return FileLayout::Directory(PathBuf::from(dir_path));
+    }
+
+    /// The directory holding all of the files
+    pub fn dir(&self) -> &Path {
+        match *self {
+            FileLayout::SingleFile(ref dir, _) => dir,
+            FileLayout::Directory(ref dir) => dir
+        }
+    }
+
+    /// Returns the full path of a file in the layout's directory
+    pub fn path(&self, file_name: &str) -> String {
+        return self.dir().join(file_name).to_string_lossy().into_owned();
+    }
+
+    pub fn wal_path(&self) -> String {
+        match *self {
+            FileLayout::SingleFile(_, ref base_name) => self.path(&(base_name.to_owned() + ".wal")),
+            FileLayout::Directory(_) => self.path("WAL")
+        }
+    }
+
+    pub fn manifest_path(&self) -> String {
+        match *self {
+            FileLayout::SingleFile(_, ref base_name) => self.path(&(base_name.to_owned() + ".manifest")),
+            FileLayout::Directory(_) => self.path("MANIFEST")
+        }
+    }
+
+    pub fn lock_path(&self) -> String {
+        match *self {
+            FileLayout::SingleFile(_, ref base_name) => self.path(&(base_name.to_owned() + ".lock")),
+            FileLayout::Directory(_) => self.path("LOCK")
+        }
+    }
+
+    /// The directory holding the WALs of past compactions, when archiving is enabled
+    pub fn archive_dir(&self) -> String {
+        match *self {
+            FileLayout::SingleFile(_, ref base_name) => self.path(&(base_name.to_owned() + ".archive")),
+            FileLayout::Directory(_) => self.path("archive")
+        }
+    }
+
+    /// Returns the name of the tree file with the given number, 0 being the first tree file
+    pub fn tree_file_name(&self, number: u64) -> String {
+        match *self {
+            FileLayout::SingleFile(_, ref base_name) if number == 0 => base_name.to_owned(),
+            FileLayout::SingleFile(_, ref base_name) => format!("{}.{}", base_name, number),
+            FileLayout::Directory(_) => format!("{:06}.btr", number)
+        }
+    }
+
+    /// Returns true if the name is one this layout would give a tree file
+    pub fn is_tree_file_name(&self, file_name: &str) -> bool {
+        match *self {
+            FileLayout::SingleFile(_, ref base_name) => {
+                let prefix = base_name.to_owned() + ".";
+
+                if !file_name.starts_with(&prefix) {
+                    return false;
+                }
+
+                // older versions of compact used .new
+                let suffix = &file_name[prefix.len()..];
+
+                return suffix == "new" || FileLayout::is_number(suffix);
+            },
+            FileLayout::Directory(_) => file_name.ends_with(".btr") && FileLayout::is_number(&file_name[..file_name.len()-4])
+        }
+    }
+
+    fn is_number(s: &str) -> bool {
+        return !s.is_empty() && s.chars().all(|c| c.is_digit(10));
+    }
+
+    /// Takes an exclusive advisory lock on the layout's lock file, which is held
+    /// until the returned file is closed
+    pub fn lock(&self) -> Result<File, Box<Error>> {
+        let lock_path = self.lock_path();
+        let lock_file = try!(OpenOptions::new().read(true).write(true).create(true).open(&lock_path));
+
+        match lock_file.try_lock_exclusive() {
+            Ok(_) => Ok(lock_file),
+            Err(ref e) if e.raw_os_error() == lock_contended_error().raw_os_error() => {
+                Err(From::from(IOError::new(ErrorKind::WouldBlock, format!("{} is locked by another process", lock_path))))
+            },
+            Err(e) => Err(From::from(e))
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use layout::FileLayout;
+
+    #[test]
+    fn single_file_names() {
+        let layout = FileLayout::single_file(&String::from("/tmp/data/a.btr")).unwrap();
+
+        assert_eq!(layout.path("x"), "/tmp/data/x");
+        assert_eq!(layout.wal_path(), "/tmp/data/a.btr.wal");
+        assert_eq!(layout.archive_dir(), "/tmp/data/a.btr.archive");
+        assert_eq!(layout.tree_file_name(0), "a.btr");
+        assert_eq!(layout.tree_file_name(3), "a.btr.3");
+
+        assert!(layout.is_tree_file_name("a.btr.3"));
+        assert!(layout.is_tree_file_name("a.btr.new"));
+        assert!(!layout.is_tree_file_name("a.btr.wal"));
+        assert!(!layout.is_tree_file_name("a.btr.archive"));
+        assert!(!layout.is_tree_file_name("b.btr.3"));
+    }
+
+    #[test]
+    fn directory_names() {
+        let layout = FileLayout::directory(&String::from("/tmp/data"));
+
+        assert_eq!(layout.manifest_path(), "/tmp/data/MANIFEST");
+        assert_eq!(layout.lock_path(), "/tmp/data/LOCK");
+        assert_eq!(layout.tree_file_name(12), "000012.btr");
+
+        assert!(layout.is_tree_file_name("000012.btr"));
+        assert!(!layout.is_tree_file_name("WAL"));
+        assert!(!layout.is_tree_file_name(".btr"));
+    }
+}
@@ -0,0 +1,257 @@
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+
+use std::cmp;
+use std::error::Error;
+use std::io::ErrorKind;
+use std::io::Error as IOError;
+
+// the number of records between restart points
+const RESTART_INTERVAL: usize = 16;
+
+/// Builds the contents of a leaf. Keys are delta encoded against the previous key,
+/// storing only the suffix that differs, except at restart points every
+/// RESTART_INTERVAL records where the whole key is stored so a reader can binary
+/// search the restart points and decode forward from there. The format is:
+/// |-------------------------------------------|
+/// | shared key bytes (varint)                 |
+/// | unshared key bytes (varint)               |
+/// | value bytes (varint)                      |
+/// | unshared key suffix | value               |
+/// |-------------------------------------------|
+/// | ... one entry per record                  |
+/// |-------------------------------------------|
+/// | offset of each restart (u32, big endian)  |
+/// |-------------------------------------------|
+/// | number of restarts (u32, big endian)      |
+/// |-------------------------------------------|
+pub struct LeafBuilder {
+    buff: Vec<u8>,
+    restarts: Vec<u32>,
+    last_key: Vec<u8>,
+    count: usize,        // records since the last restart
+}
+
+/// A read-only view of a leaf written by a LeafBuilder
+pub struct Leaf<'a> {
+    entries: &'a [u8],
+    restarts: &'a [u8],
+}
+
+/// Iterates over the records of a leaf, yielding each key & value
+pub struct LeafIter<'a> {
+    entries: &'a [u8],
+    offset: usize,
+    key: Vec<u8>,
+}
+
+fn invalid_leaf() -> Box<Error> {
+    return From::from(IOError::new(ErrorKind::InvalidData, "Leaf is corrupt"));
+}
+
+fn put_varint(buff: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        buff.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+
+    buff.push(value as u8);
+}
+
+/// Reads a varint at the offset, advancing the offset past it
+fn get_varint(buff: &[u8], offset: &mut usize) -> Result<usize, Box<Error>> {
+    let mut value = 0;
+    let mut shift = 0;
+
+    loop {
+        if *offset >= buff.len() || shift > 63 {
+            return Err(invalid_leaf());
+        }
+
+        let b = buff[*offset];
+
+        *offset += 1;
+        value |= ((b & 0x7f) as usize) << shift;
+        shift += 7;
+
+        if b & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+}
+
+impl LeafBuilder {
+    pub fn new() -> LeafBuilder {
+        LeafBuilder{buff: Vec::new(), restarts: Vec::new(), last_key: Vec::new(), count: 0}
+    }
+
+    /// Adds a record; keys must be added in sorted order
+    pub fn add(&mut self, key: &[u8], value: &[u8]) {
+        let shared = if self.count % RESTART_INTERVAL == 0 {
+            self.restarts.push(self.buff.len() as u32);
+            0
+        } else {
+            let max = cmp::min(key.len(), self.last_key.len());
+
+            (0..max).position(|i| key[i] != self.last_key[i]).unwrap_or(max)
+        };
+
+        put_varint(&mut self.buff, shared);
+        put_varint(&mut self.buff, key.len() - shared);
+        put_varint(&mut self.buff, value.len());
+
+        self.buff.extend_from_slice(&key[shared..]);
+        self.buff.extend_from_slice(value);
+
+        self.last_key.clear();
+        self.last_key.extend_from_slice(key);
+        self.count += 1;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.restarts.is_empty();
+    }
+
+    /// The size of the leaf if it were finished now
+    pub fn size(&self) -> usize {
+        return self.buff.len() + 4 * (self.restarts.len() + 1);
+    }
+
+    /// Returns the finished leaf, leaving the builder empty for the next one
+    pub fn finish(&mut self) -> Vec<u8> {
+        let mut buff = ::std::mem::replace(&mut self.buff, Vec::new());
+
+        for restart in &self.restarts {
+            buff.write_u32::<BigEndian>(*restart).unwrap();
+        }
+
+        buff.write_u32::<BigEndian>(self.restarts.len() as u32).unwrap();
+
+        self.restarts.clear();
+        self.last_key.clear();
+        self.count = 0;
+
+        return buff;
+    }
+}
+
+impl <'a> Leaf<'a> {
+    pub fn new(buff: &'a [u8]) -> Result<Leaf<'a>, Box<Error>> {
+        if buff.len() < 4 {
+            return Err(invalid_leaf());
+        }
+
+        let num_restarts = BigEndian::read_u32(&buff[buff.len() - 4..]) as usize;
+
+        if num_restarts == 0 || num_restarts > (buff.len() - 4) / 4 {
+            return Err(invalid_leaf());
+        }
+
+        let restarts_start = buff.len() - 4 - 4 * num_restarts;
+
+        return Ok(Leaf{entries: &buff[..restarts_start], restarts: &buff[restarts_start..buff.len() - 4]});
+    }
+
+    pub fn num_restarts(&self) -> usize {
+        return self.restarts.len() / 4;
+    }
+
+    /// Returns the whole key stored at the given restart point
+    pub fn restart_key(&self, restart: usize) -> Result<Vec<u8>, Box<Error>> {
+        match self.iter_from(restart).next() {
+            Some(Ok((key, _))) => Ok(key),
+            Some(Err(e)) => Err(e),
+            None => Err(invalid_leaf())
+        }
+    }
+
+    /// Iterates over the records starting at the given restart point
+    pub fn iter_from(&self, restart: usize) -> LeafIter<'a> {
+        let offset = BigEndian::read_u32(&self.restarts[restart * 4..restart * 4 + 4]) as usize;
+
+        return LeafIter{entries: self.entries, offset: offset, key: Vec::new()};
+    }
+}
+
+impl <'a> Iterator for LeafIter<'a> {
+    type Item = Result<(Vec<u8>, &'a [u8]), Box<Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offset >= self.entries.len() {
+            return None;
+        }
+
+        let mut offset = self.offset;
+
+        let shared = match get_varint(self.entries, &mut offset) { Ok(n) => n, Err(e) => return Some(Err(e)) };
+        let unshared = match get_varint(self.entries, &mut offset) { Ok(n) => n, Err(e) => return Some(Err(e)) };
+        let value_len = match get_varint(self.entries, &mut offset) { Ok(n) => n, Err(e) => return Some(Err(e)) };
+
+        if shared > self.key.len() || offset + unshared + value_len > self.entries.len() {
+            self.offset = self.entries.len();
+            return Some(Err(invalid_leaf()));
+        }
+
+        self.key.truncate(shared);
+        self.key.extend_from_slice(&self.entries[offset..offset + unshared]);
+
+        let value = &self.entries[offset + unshared..offset + unshared + value_len];
+
+        self.offset = offset + unshared + value_len;
+
+        return Some(Ok((self.key.clone(), value)));
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use leaf::{Leaf, LeafBuilder, RESTART_INTERVAL};
+
+    #[test]
+    fn round_trip_with_restarts() {
+        let mut builder = LeafBuilder::new();
+
+        for i in 0..100 {
+            builder.add(format!("tenant-0001/users/{:04}", i).as_bytes(), &[i as u8]);
+        }
+
+        let buff = builder.finish();
+
+        assert!(builder.is_empty());
+
+        // every key shares a long prefix, so the leaf is much smaller than the keys
+        assert!(buff.len() < 100 * 22 / 2, "leaf is {} bytes", buff.len());
+
+        let leaf = Leaf::new(&buff).unwrap();
+
+        assert_eq!(leaf.num_restarts(), (100 + RESTART_INTERVAL - 1) / RESTART_INTERVAL);
+        assert_eq!(leaf.restart_key(1).unwrap(), format!("tenant-0001/users/{:04}", RESTART_INTERVAL).into_bytes());
+
+        let records: Vec<(Vec<u8>, Vec<u8>)> = leaf.iter_from(0).map(|r| r.map(|(k, v)| (k, v.to_vec())).unwrap()).collect();
+
+        assert_eq!(records.len(), 100);
+
+        for (i, &(ref key, ref value)) in records.iter().enumerate() {
+            assert_eq!(key, &format!("tenant-0001/users/{:04}", i).into_bytes());
+            assert_eq!(value, &vec![i as u8]);
+        }
+
+        assert_eq!(leaf.iter_from(2).count(), 100 - 2 * RESTART_INTERVAL);
+    }
+
+    #[test]
+    fn rejects_corrupt_leaf() {
+        assert!(Leaf::new(&[0, 0, 0, 0]).is_err());
+        assert!(Leaf::new(&[0, 0, 0, 9]).is_err());
+
+        let mut builder = LeafBuilder::new();
+
+        builder.add(b"key", b"value");
+
+        let mut buff = builder.finish();
+
+        buff[1] = 100;  // the key is now longer than the leaf
+
+        assert!(Leaf::new(&buff).unwrap().iter_from(0).next().unwrap().is_err());
+    }
+}
@@ -2,235 +2,2136 @@ extern crate bincode;
 extern crate rustc_serialize;
 extern crate rand;
 extern crate itertools;
+extern crate byteorder;
+extern crate fs2;
+extern crate memmap;
+extern crate lz4;
+extern crate snap;
+extern crate zstd;
+extern crate crc;
+extern crate serde;
+extern crate bincode_serde;
+
+#[cfg(test)]
+#[macro_use]
+extern crate serde_derive;
 
 mod wal_file;
 mod multi_map;
 mod disk_btree;
+mod manifest;
+mod layout;
+mod bloom;
+mod options;
+mod block_cache;
+mod compression;
+mod leaf;
+mod verify;
+mod repair;
+mod stats;
+mod export;
+mod backup;
+mod codec;
+mod ordered;
+mod comparator;
+mod raw;
+mod tombstones;
+mod map;
+mod entry;
+mod merge;
 
 use wal_file::{KeyValuePair, RecordFile};
 use multi_map::MultiMap;
+use tombstones::Tombstones;
+use merge::Operands;
 use disk_btree::OnDiskBTree;
+use manifest::{Manifest, ManifestRecord};
+use layout::FileLayout;
+use comparator::compare_records;
+
+pub use bloom::BloomFilterStats;
+pub use options::Options;
+pub use block_cache::{BlockCache, BlockCacheStats};
+pub use compression::{Compression, CompressionStats};
+pub use verify::{VerifyReport, Damage, DamageKind};
+pub use repair::{repair, RepairReport};
+pub use stats::TreeStats;
+pub use wal_file::WalRecord;
+pub use export::ExportFormat;
+pub use disk_btree::{write_tree_file, SliceGuard};
+pub use backup::{BackupInfo, restore};
+pub use codec::{Codec, Bincode, SerdeBincode, Raw};
+pub use ordered::{Ordered, OrderedKey};
+pub use comparator::{Comparator, Ascending, Descending, CaseInsensitive, Numeric};
+pub use raw::RawBTree;
+pub use map::MapBTree;
+pub use entry::Entry;
+pub use merge::{MergeOperator, Sum, Max, Min, Union};
 
 use rustc_serialize::{Encodable, Decodable};
 
+use std::cell::RefCell;
+use std::cmp;
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, btree_set};
 use std::error::Error;
-use itertools::merge;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufRead, ErrorKind, Read, Write};
+use std::io::Error as IOError;
+use std::ops::{Bound, RangeBounds};
+use std::path::Path;
+use std::rc::Rc;
+use itertools::Itertools;
 
 const MAX_MEMORY_ITEMS: usize = 1000;
+const MAX_OPEN_ATTEMPTS: usize = 10;
 
-// specify the types for the keys & values
-pub trait KeyType: Ord + Encodable + Decodable + Clone {}
-pub trait ValueType: Ord + Encodable + Decodable + Clone  {}
+// specify the types for the keys & values; how they are stored is up to the tree's codecs
+pub trait KeyType: Ord + Clone {}
+pub trait ValueType: Ord + Clone  {}
 
 // provide generic implementations
 
-impl<T> KeyType for T where T: Ord + Encodable + Decodable + Clone {}
-impl<T> ValueType for T where T: Ord + Encodable + Decodable + Clone {}
-
-/// This struct holds all the pieces of the BTree mechanism
-pub struct BTree<K: KeyType, V: ValueType> {
-    tree_file_path: String,       // the path to the tree file
-    key_size: usize,              // the size of the key in bytes
-    value_size: usize,            // the size of the value in bytes
-    wal_file: RecordFile<K,V>,    // write-ahead log for in-memory items
-    mem_tree: MultiMap<K,V>,      // in-memory multi-map that gets merged with the on-disk BTree
-    tree_file: OnDiskBTree<K,V>,  // the file backing the whole thing
+impl<T> KeyType for T where T: Ord + Clone {}
+impl<T> ValueType for T where T: Ord + Clone {}
+
+/// This struct holds all the pieces of the BTree mechanism. Keys & values are stored
+/// using the KC & VC codecs, bincode by default, and keys are ordered by the C
+/// comparator, their Ord by default.
+pub struct BTree<K: KeyType, V: ValueType, KC: Codec<K> = Bincode, VC: Codec<V> = Bincode, C: Comparator<K> = Ascending> {
+    layout: FileLayout,           // where the tree's files live
+    _lock_file: Option<File>,     // holds the lock preventing other processes from opening the tree, None when read-only
+    read_only: bool,              // true when opened with open_read_only
+    replay_wal: bool,             // when read-only, if the WAL is replayed into our private mem_tree
+    options: Options,             // tuning options for the files we write
+    map: bool,                    // true if each key has a single value, which an insert replaces
+    wal_file: RecordFile<K,V,KC,VC>,  // write-ahead log for in-memory items
+    mem_tree: MultiMap<K,V,C>,    // in-memory multi-map that gets merged with the on-disk BTree
+    deleted: Tombstones<K,V,C>,   // records deleted since the last compaction, which may still be in the tree file
+    operands: Operands<K,V,C>,    // operands merged into keys since the last compaction, folded into their values when read
+    merge_operator: Option<Box<MergeOperator<K,V>>>,  // folds the operands, set with set_merge_operator
+    tree_file: OnDiskBTree<K,V,KC,VC,C>,  // the file backing the whole thing
+    tree_file_name: String,       // the name of the live tree file, relative to the layout's directory
+    manifest: Manifest,           // the record of which files make up the tree
+    last_sequence: u64,           // the sequence number of the last insert or delete
 }
 
-impl <K: KeyType, V: ValueType> BTree<K, V> {
-    pub fn new(tree_file_path: &String, key_size: usize, value_size: usize) -> Result<BTree<K,V>, Box<Error>> {
+impl <K: KeyType, V: ValueType, KC: Codec<K>, VC: Codec<V>, C: Comparator<K>> BTree<K,V,KC,VC,C> {
+    /// Opens the tree at the given file path, creating it if needed. The WAL, manifest,
+    /// lock & compacted tree files are created alongside it.
+    pub fn new(tree_file_path: &String, key_size: usize, value_size: usize) -> Result<BTree<K,V,KC,VC,C>, Box<Error>> {
+        let layout = try!(FileLayout::single_file(tree_file_path));
+
+        return BTree::open_layout(layout, key_size, value_size, Options::default(), false);
+    }
+
+    /// Opens the tree stored in the given directory, creating it if needed. Fails if
+    /// another process has the tree open.
+    pub fn open(dir_path: &String, key_size: usize, value_size: usize) -> Result<BTree<K,V,KC,VC,C>, Box<Error>> {
+        return BTree::open_with_options(dir_path, key_size, value_size, Options::default());
+    }
+
+    /// Same as open, but with the given options instead of the defaults
+    pub fn open_with_options(dir_path: &String, key_size: usize, value_size: usize, options: Options) -> Result<BTree<K,V,KC,VC,C>, Box<Error>> {
+        try!(fs::create_dir_all(dir_path));
+
+        return BTree::open_layout(FileLayout::directory(dir_path), key_size, value_size, options, false);
+    }
+
+    /// Opens the tree, creating it as a map if map is true. An existing tree keeps the
+    /// mode it was created with.
+    fn open_layout(layout: FileLayout, key_size: usize, value_size: usize, options: Options, map: bool) -> Result<BTree<K,V,KC,VC,C>, Box<Error>> {
+        // nothing else is touched until we know we're the only ones using the files
+        let lock_file = try!(layout.lock());
+
         // create our in-memory multi-map
-        let mut mem_tree = MultiMap::<K,V>::new();
+        let mut mem_tree = MultiMap::<K,V,C>::new();
+        let mut deleted = Tombstones::<K,V,C>::new();
+        let mut operands = Operands::<K,V,C>::new();
+
+        // a new tree starts with an empty tree file, or one given for the single file layout
+        let new_tree = fs::metadata(layout.manifest_path()).map(|m| m.len() == 0).unwrap_or(true);
+
+        if new_tree {
+            try!(OpenOptions::new().write(true).create(true).open(layout.path(&layout.tree_file_name(0))));
+        }
+
+        // the manifest tells us which tree file is live
+        let manifest = try!(Manifest::new(&layout.manifest_path(), key_size, value_size, (KC::name(), VC::name()), C::name(), map, &layout.tree_file_name(0)));
+        let map = manifest.is_map();
+
+        if manifest.live_files().len() != 1 {
+            return Err(From::from(IOError::new(ErrorKind::InvalidData, "Manifest does not list exactly one live tree file")));
+        }
+
+        let tree_file_name = manifest.live_files()[0].to_owned();
+
+        // an existing tree's file is never made afresh, or the records it held would be lost
+        if fs::metadata(layout.path(&tree_file_name)).is_err() {
+            return Err(From::from(IOError::new(ErrorKind::NotFound, format!("Tree file {} listed in the manifest is missing", tree_file_name))));
+        }
 
         // construct the path to the WAL file for the in-memory multi-map
-        let wal_file_path = tree_file_path.to_owned() + ".wal";
+        let wal_file_path = layout.wal_path();
 
         // construct our WAL file
-        let mut wal_file = try!(RecordFile::<K,V>::new(&wal_file_path, key_size, value_size));
+        let mut wal_file = try!(RecordFile::<K,V,KC,VC>::new(&wal_file_path, key_size, value_size));
+
+        // everything in the WAL is newer than what is in the tree file
+        let mut last_sequence = manifest.last_sequence();
 
         // if we have a WAL file, replay it into the mem_tree
-        if try!(wal_file.is_new()) {
-            for kv in &mut wal_file {
-                mem_tree.insert(kv.key, kv.value);
+        if ! try!(wal_file.is_new()) {
+            let records = try!(wal_file.read_changes());
+            let complete = records.len() as u64;
+
+            for record in records {
+                Self::apply(map, &mut mem_tree, &mut deleted, &mut operands, record);
+                last_sequence += 1;
             }
+
+            // drop any change that was only partly written, so new ones aren't appended to it
+            try!(wal_file.truncate_to(complete));
         }
 
         // open the data file
-        let tree_file = try!(OnDiskBTree::<K,V>::new(tree_file_path.to_owned(), key_size, value_size));
+        let tree_file = try!(OnDiskBTree::<K,V,KC,VC,C>::new(layout.path(&tree_file_name), &options));
+
+        try!(Self::check_mode(&tree_file, map));
+
+        let btree = BTree{layout: layout,
+                          _lock_file: Some(lock_file),
+                          read_only: false,
+                          replay_wal: true,
+                          options: options,
+                          map: map,
+                          tree_file: tree_file,
+                          wal_file: wal_file,
+                          mem_tree: mem_tree,
+                          deleted: deleted,
+                          operands: operands,
+                          merge_operator: None,
+                          tree_file_name: tree_file_name,
+                          manifest: manifest,
+                          last_sequence: last_sequence};
+
+        try!(btree.remove_orphaned_files());
+
+        return Ok(btree);
+    }
 
-        return Ok(BTree{tree_file_path: tree_file_path.clone(),
-                        key_size: key_size,
-                        value_size: value_size,
-                        tree_file: tree_file,
-                        wal_file: wal_file,
-                        mem_tree: mem_tree});
+    /// Opens the tree stored in the given directory for reading only, while another
+    /// process may have it open for writing. The lock, manifest, WAL & tree files are
+    /// never written to. If replay_wal is true, records in the WAL that have not yet
+    /// been compacted are read into a private in-memory BTree.
+    pub fn open_read_only(dir_path: &String, replay_wal: bool) -> Result<BTree<K,V,KC,VC,C>, Box<Error>> {
+        return BTree::open_layout_read_only(FileLayout::directory(dir_path), replay_wal, Options::default());
     }
 
-    /// Inserts a key into the BTree
-    pub fn insert(&mut self, key: K, value: V) -> Result<(), Box<Error>> {
-        let record = KeyValuePair{key: key, value: value};
+    fn open_layout_read_only(layout: FileLayout, replay_wal: bool, options: Options) -> Result<BTree<K,V,KC,VC,C>, Box<Error>> {
+        // a writer can compact between us reading the manifest and opening the files
+        // it lists, so retry until we see the same manifest before and after
+        for _ in 0..MAX_OPEN_ATTEMPTS {
+            match BTree::load_read_only(&layout, replay_wal, &options) {
+                Ok(Some(btree)) => return Ok(btree),
+                Ok(None) => continue,
+                Err(e) => {
+                    match e.downcast_ref::<IOError>() {
+                        Some(io_err) if io_err.kind() == ErrorKind::NotFound => continue,
+                        _ => return Err(e)
+                    }
+                }
+            }
+        }
+
+        return Err(From::from(IOError::new(ErrorKind::Other, "Tree kept changing while being opened read-only")));
+    }
+
+    /// Attempts to open the tree read-only, returning None if the manifest changed while doing so
+    fn load_read_only(layout: &FileLayout, replay_wal: bool, options: &Options) -> Result<Option<BTree<K,V,KC,VC,C>>, Box<Error>> {
+        let manifest = try!(Manifest::open_read_only(&layout.manifest_path()));
+
+        try!(manifest.check_codecs((KC::name(), VC::name())));
+        try!(manifest.check_comparator(C::name()));
+
+        if manifest.live_files().len() != 1 {
+            return Err(From::from(IOError::new(ErrorKind::InvalidData, "Manifest does not list exactly one live tree file")));
+        }
+
+        let key_size = manifest.key_size();
+        let value_size = manifest.value_size();
+        let tree_file_name = manifest.live_files()[0].to_owned();
+
+        let tree_file = try!(OnDiskBTree::<K,V,KC,VC,C>::open_read_only(layout.path(&tree_file_name), &options));
+        let map = manifest.is_map();
+
+        try!(Self::check_mode(&tree_file, map));
+
+        let mut wal_file = try!(RecordFile::<K,V,KC,VC>::open_read_only(&layout.wal_path(), key_size, value_size));
+
+        let mut mem_tree = MultiMap::<K,V,C>::new();
+        let mut deleted = Tombstones::<K,V,C>::new();
+        let mut operands = Operands::<K,V,C>::new();
+        let mut last_sequence = manifest.last_sequence();
+
+        if replay_wal {
+            // a change being written as we read is left for the next refresh
+            for record in try!(wal_file.read_changes()) {
+                Self::apply(map, &mut mem_tree, &mut deleted, &mut operands, record);
+                last_sequence += 1;
+            }
+        }
+
+        // if a compaction happened the WAL we read might not go with the tree file we opened
+        let current = try!(Manifest::open_read_only(&layout.manifest_path()));
+
+        if current.live_files() != manifest.live_files() || current.last_sequence() != manifest.last_sequence() {
+            return Ok(None);
+        }
+
+        return Ok(Some(BTree{layout: layout.clone(),
+                             _lock_file: None,
+                             read_only: true,
+                             replay_wal: replay_wal,
+                             options: options.clone(),
+                             map: map,
+                             tree_file: tree_file,
+                             wal_file: wal_file,
+                             mem_tree: mem_tree,
+                             deleted: deleted,
+                             operands: operands,
+                             merge_operator: None,
+                             tree_file_name: tree_file_name,
+                             manifest: manifest,
+                             last_sequence: last_sequence}));
+    }
+
+    /// Picks up any changes made by the writer since a read-only tree was opened, such
+    /// as newly compacted tree files and, if replaying the WAL, new inserts
+    pub fn refresh(&mut self) -> Result<(), Box<Error>> {
+        if !self.read_only {
+            return Ok( () ); // we're the writer so we're always up to date
+        }
+
+        // keep using the same block cache & merge operator
+        let merge_operator = self.merge_operator.take();
+
+        *self = try!(BTree::open_layout_read_only(self.layout.clone(), self.replay_wal, self.options.clone()));
+        self.merge_operator = merge_operator;
+
+        Ok( () )
+    }
+
+    /// Returns an error if the tree was opened read-only
+    fn check_writable(&self) -> Result<(), Box<Error>> {
+        if self.read_only {
+            Err(From::from(IOError::new(ErrorKind::PermissionDenied, "Tree was opened read-only")))
+        } else {
+            Ok( () )
+        }
+    }
+
+    /// Fails if the tree file doesn't agree with the manifest on whether the tree is a map
+    fn check_mode(tree_file: &OnDiskBTree<K,V,KC,VC,C>, map: bool) -> Result<(), Box<Error>> {
+        if tree_file.is_map().map_or(false, |file_map| file_map != map) {
+            return Err(From::from(IOError::new(ErrorKind::InvalidData, "Tree file and manifest disagree on whether the tree is a map")));
+        }
+
+        Ok( () )
+    }
+
+    /// Applies a change from the WAL to the in-memory BTree. In a map an insert replaces
+    /// the key's value, and any operands merged into it. The value in memory hides those
+    /// of the key in the tree file, so it needs no tombstone to do it.
+    fn apply(map: bool, mem_tree: &mut MultiMap<K,V,C>, deleted: &mut Tombstones<K,V,C>, operands: &mut Operands<K,V,C>, record: WalRecord<K,V>) {
+        match record {
+            WalRecord::Insert(key, value) => {
+                if map {
+                    mem_tree.remove_key(&key);
+                    operands.remove_key(&key);
+                }
+
+                deleted.undelete_value(key.clone(), value.clone());
+                mem_tree.insert(key, value);
+            },
+            // deleting a map's value in memory deletes the key, or its older values would show
+            WalRecord::Delete(key, value) => {
+                if map && mem_tree.contains(&key, &value) {
+                    mem_tree.remove_key(&key);
+                    deleted.delete_key(key);
+                } else {
+                    mem_tree.delete(key.clone(), value.clone());
+                    deleted.delete_value(key, value);
+                }
+            },
+            // a replace's Inserts follow as records of their own
+            WalRecord::DeleteKey(key) | WalRecord::Replace(key, _) => {
+                mem_tree.remove_key(&key);
+                operands.remove_key(&key);
+                deleted.delete_key(key);
+            },
+            WalRecord::DeleteRange(start, end) => {
+                let range = (start, end);
+
+                mem_tree.remove_range(&range);
+                operands.remove_range(&range);
+                deleted.delete_range(range);
+            },
+            WalRecord::Merge(key, operand) => operands.push(key, operand)
+        }
+    }
+
+    /// Logs a change to the WAL then applies it, compacting if memory is full
+    fn write(&mut self, record: WalRecord<K,V>) -> Result<(), Box<Error>> {
+        return self.write_batch(vec![record]);
+    }
+
+    /// Logs changes to the WAL with a single write then applies them, compacting if
+    /// memory is full only once they've all been applied
+    fn write_batch(&mut self, records: Vec<WalRecord<K,V>>) -> Result<(), Box<Error>> {
+        try!(self.check_writable());
 
         // should wrap this in a read-write lock
-        try!(self.wal_file.insert_record(&record));
+        try!(self.wal_file.insert_records(&records));
+
+        for record in records {
+            Self::apply(self.map, &mut self.mem_tree, &mut self.deleted, &mut self.operands, record);
 
-        let KeyValuePair{key, value} = record;
+            self.last_sequence += 1;
+        }
 
-        let size = self.mem_tree.insert(key, value);
+        // operands can't be folded without a merge operator, so they wait in the WAL for one
+        let can_fold = self.operands.is_empty() || self.merge_operator.is_some();
 
-        if size > MAX_MEMORY_ITEMS {
+        if can_fold && self.mem_tree.size() + self.deleted.size() + self.operands.size() > MAX_MEMORY_ITEMS {
             try!(self.compact());
         }
 
         return Ok( () );
     }
 
+    /// Inserts a key into the BTree. In a map, the value replaces the key's value.
+    pub fn insert(&mut self, key: K, value: V) -> Result<(), Box<Error>> {
+        return self.write(WalRecord::Insert(key, value));
+    }
+
+    /// Removes a single value of a key. The value is removed from the tree file by
+    /// the next compaction.
+    pub fn delete(&mut self, key: K, value: V) -> Result<(), Box<Error>> {
+        // the value of a key with operands is only known once they're folded
+        if self.operands.contains_key(&key) {
+            if try!(self.get(&key)).any(|v| v == value) {
+                return self.write(WalRecord::DeleteKey(key));
+            }
+
+            return Ok( () );
+        }
 
-    pub fn get(&self, key: &K) -> Option<std::collections::btree_set::Iter<V>> {
-        self.mem_tree.get(key).map(|btree| btree)
+        return self.write(WalRecord::Delete(key, value));
     }
 
-    /// Merges the records on disk with the records in memory
-    fn compact(&mut self) -> Result<(), Box<Error>>{
-        // create a new on-disk BTree
-        let mut new_tree_file = try!(OnDiskBTree::<K,V>::new(self.tree_file_path.to_owned() + ".new", self.key_size, self.value_size));
+    /// Removes every value of a key with a single delete, however many values it has.
+    /// The values are removed from the tree file by the next compaction.
+    pub fn delete_key(&mut self, key: K) -> Result<(), Box<Error>> {
+        return self.write(WalRecord::DeleteKey(key));
+    }
 
-        // get an iterator for the in-memory items
-        let mem_iter = self.mem_tree.into_iter();
+    /// Removes every value of every key within the range, ordering keys by the tree's
+    /// comparator, with a single delete however many keys it covers. The keys are
+    /// removed from the tree file by the next compaction, which drops the delete too.
+    /// The range's start & end keys are logged in the space of a key & value, so both
+    /// must fit in it.
+    pub fn delete_range<R: RangeBounds<K>>(&mut self, range: R) -> Result<(), Box<Error>> {
+        return self.write(WalRecord::DeleteRange(cloned_bound(range.start_bound()), cloned_bound(range.end_bound())));
+    }
 
-        // get an iterator to the on-disk items
-        let disk_iter = self.tree_file.into_iter();
+    /// Replaces every value of a key with the given values. The change is logged to the
+    /// WAL with a single write, so after a crash either all of it or none of it is seen.
+    pub fn replace<I: IntoIterator<Item=V>>(&mut self, key: K, values: I) -> Result<(), Box<Error>> {
+        let values: BTreeSet<V> = values.into_iter().collect();
 
-        for kv in merge(mem_iter, disk_iter) {
-            try!(new_tree_file.insert_record(&kv));
+        if self.map && values.len() > 1 {
+            return Err(From::from(IOError::new(ErrorKind::InvalidInput, "A key in a map can only have a single value")));
         }
+        let mut records = vec![WalRecord::Replace(key.clone(), values.len() as u32)];
 
-        Ok( () )
+        records.extend(values.into_iter().map(|value| WalRecord::Insert(key.clone(), value)));
+
+        return self.write_batch(records);
     }
-}
 
+    /// Reads the key's values into an Entry, to be changed & written back as one change
+    pub fn entry<'a>(&'a mut self, key: K) -> Result<Entry<'a,K,V,KC,VC,C>, Box<Error>> {
+        return Entry::new(self, key);
+    }
 
-#[cfg(test)]
-#[allow(unused_must_use)]
-mod tests {
-    use std::fs;
-    use std::fs::OpenOptions;
-    use ::BTree;
-    use rand::{thread_rng, Rng};
-    use std::collections::BTreeSet;
+    /// Merges an operand into the value of a key in a map. Only the operand is logged;
+    /// the tree's merge operator folds it into the key's value when the key is read,
+    /// and into the tree file when the tree is compacted. A merge operator must be set.
+    pub fn merge(&mut self, key: K, operand: V) -> Result<(), Box<Error>> {
+        if !self.map {
+            return Err(From::from(IOError::new(ErrorKind::InvalidInput, "Only a key in a map has a single value to merge into")));
+        }
 
-    pub fn gen_temp_name() -> String {
-        let file_name: String = thread_rng().gen_ascii_chars().take(10).collect();
+        if self.merge_operator.is_none() {
+            return Err(From::from(IOError::new(ErrorKind::InvalidInput, "No merge operator is set to fold the operand")));
+        }
 
-        return String::from("/tmp/") + &file_name + &String::from(".btr");
+        return self.write(WalRecord::Merge(key, operand));
     }
 
-    fn remove_files(file_path: String) {
-        fs::remove_file(&file_path);
-        fs::remove_file(file_path + ".wal");
+    /// Sets the operator folding the operands given to merge into their keys' values.
+    /// It isn't stored with the tree, so it must be set every time the tree is opened
+    /// before any key with operands is read or the tree is compacted.
+    pub fn set_merge_operator<M: MergeOperator<K,V> + 'static>(&mut self, merge_operator: M) {
+        self.merge_operator = Some(Box::new(merge_operator));
     }
 
-    #[test]
-    fn new_blank_file() {
-        let file_path = gen_temp_name();
+    /// Returns the number of merge operands not yet folded into the tree file. Operands
+    /// replayed from the WAL when the tree is opened wait for set_merge_operator, and
+    /// until it's called the tree isn't compacted as it fills.
+    pub fn merge_operand_count(&self) -> usize {
+        return self.operands.size();
+    }
 
-        let btree = BTree::<u8, u8>::new(&file_path, 1, 1).unwrap();
+    /// Applies the operands to the value, in the order they were merged
+    fn fold(&self, key: &K, value: Option<V>, operands: &[V]) -> Result<V, Box<Error>> {
+        let merge_operator = match self.merge_operator {
+            Some(ref merge_operator) => merge_operator,
+            None => return Err(From::from(IOError::new(ErrorKind::InvalidInput, "Key has merge operands but no merge operator is set")))
+        };
 
-        // make sure our two files were created
-        let btf = OpenOptions::new().read(true).write(false).create(false).open(&file_path).unwrap();
-        assert!(btf.metadata().unwrap().len() == 0);
+        let mut value = value;
 
-        let wal = OpenOptions::new().read(true).write(false).create(false).open(file_path.to_owned() + ".wal").unwrap();
-        assert!(wal.metadata().unwrap().len() == 0);
+        for operand in operands {
+            value = Some(merge_operator.merge(key, value, operand));
+        }
 
-        // make sure they think they're new too
-        assert!(btree.wal_file.is_new().unwrap());
-        assert!(btree.wal_file.count().unwrap() == 0);
+        return Ok(value.expect("a key with operands has at least one"));
+    }
 
-        assert!(btree.tree_file.is_new().unwrap());
-        assert!(btree.tree_file.count().unwrap() == 0);
+    /// Returns the value of each key with operands, with them folded in, in key order,
+    /// ready to be written to a tree file. The operands are kept, as is the WAL holding
+    /// them, until the tree file replaces it.
+    fn fold_operands(&self) -> Result<Vec<KeyValuePair<K,V>>, Box<Error>> {
+        let mut folded = Vec::new();
 
-        remove_files(file_path); // remove files assuming it all went well
+        for key in self.operands.keys() {
+            let value = try!(self.get(&key)).next_back().expect("a key with operands has a value");
+
+            folded.push(KeyValuePair{key: key, value: value});
+        }
+
+        return Ok(folded);
     }
 
-    #[test]
-    fn new_existing_file() {
-        let file_path = gen_temp_name();
+    /// Returns all of the values associated with the key, in order
+    pub fn get(&self, key: &K) -> Result<btree_set::IntoIter<V>, Box<Error>> {
+        let mut values = BTreeSet::new();
 
-        // scoped so it is cleaned up
-        { BTree::<u8, u8>::new(&file_path, 1, 1).unwrap(); }
+        if !self.disk_key_hidden(key) {
+            values.extend(try!(self.tree_file.get(key)).into_iter().filter(|v| !self.deleted.covers(key, v)));
+        }
 
-        let btree = BTree::<u8, u8>::new(&file_path, 1, 1).unwrap();
+        if let Some(mem_values) = self.mem_tree.get(key) {
+            values.extend(mem_values.cloned());
+        }
 
-        // check our file lengths from the struct
-        assert!(btree.tree_file.count().unwrap() == 0);
-        assert!(btree.wal_file.count().unwrap() == 0);
+        // in a map, the operands merged since the value was set are applied to it
+        if let Some(operands) = self.operands.get(key) {
+            let value = try!(self.fold(key, values.into_iter().next_back(), operands));
 
-        remove_files(file_path); // remove files assuming it all went well
+            values = BTreeSet::new();
+            values.insert(value);
+        }
+
+        return Ok(values.into_iter());
     }
 
-    #[test]
-    fn insert_new_u8() {
-        let file_path = gen_temp_name();
+    pub fn contains_key(&self, key: &K) -> Result<bool, Box<Error>> {
+        if self.mem_tree.contains_key(key) || self.operands.contains_key(key) {
+            return Ok(true);
+        }
 
-        let mut btree = BTree::<u8, u8>::new(&file_path, 1, 1).unwrap();
+        if self.deleted.contains_key(key) {
+            return Ok(try!(self.get(key)).len() > 0);
+        }
 
-        btree.insert(2, 3).unwrap(); // insert into a new file
+        return self.tree_file.contains_key(key);
+    }
 
-        assert!(btree.wal_file.count().unwrap() == 1);
-        assert!(btree.mem_tree.contains_key(&2));
+    /// Returns the number of values associated with the key. Values on disk are only
+    /// decoded when some of the key's values were inserted or deleted since the last compaction.
+    pub fn value_count(&self, key: &K) -> Result<usize, Box<Error>> {
+        if self.operands.contains_key(key) {
+            return Ok(1);
+        }
 
-        remove_files(file_path); // remove files assuming it all went well
+        let mem_values = self.mem_tree.values(key);
+        let deleted = self.deleted.values(key);
+        let mut count = mem_values.map_or(0, |values| values.len());
+
+        try!(self.visit_disk_values(key, |value_buff| {
+            if mem_values.is_none() && deleted.is_none() {
+                count += 1;
+                return Ok(true);
+            }
+
+            let value = try!(VC::decode(value_buff));
+
+            // values in memory are already counted
+            if !mem_values.map_or(false, |values| values.contains(&value)) && !deleted.map_or(false, |values| values.contains(&value)) {
+                count += 1;
+            }
+
+            Ok(true)
+        }));
+
+        return Ok(count);
     }
 
-    #[test]
-    fn insert_new_str() {
-        let file_path = gen_temp_name();
+    /// Returns the smallest value associated with the key, reading no further on disk than it
+    pub fn first_value(&self, key: &K) -> Result<Option<V>, Box<Error>> {
+        if self.operands.contains_key(key) {
+            return Ok(try!(self.get(key)).next());
+        }
 
-        let mut btree = BTree::<String, String>::new(&file_path, 15, 15).unwrap();
+        let deleted = self.deleted.values(key);
+        let mut disk_value = None;
 
-        // insert into a new file
-        btree.insert("Hello".to_owned(), "World".to_owned()).unwrap();
+        try!(self.visit_disk_values(key, |value_buff| {
+            let value = try!(VC::decode(value_buff));
 
-        assert!(! btree.wal_file.is_new().unwrap());
-        assert!(btree.mem_tree.contains_key(&String::from("Hello")));
+            if deleted.map_or(false, |values| values.contains(&value)) {
+                return Ok(true);
+            }
 
-        remove_files(file_path); // remove files assuming it all went well
+            disk_value = Some(value);
+
+            Ok(false)
+        }));
+
+        let mem_value = self.mem_tree.values(key).and_then(|values| values.iter().next().cloned());
+
+        return Ok(match (disk_value, mem_value) {
+            (Some(disk_value), Some(mem_value)) => Some(cmp::min(disk_value, mem_value)),
+            (disk_value, mem_value) => disk_value.or(mem_value)
+        });
     }
 
-    #[test]
-    fn get_returns_an_iter() {
-        let file_path = gen_temp_name();
+    /// Returns the largest value associated with the key. Only that value is decoded,
+    /// unless some of the key's values were deleted since the last compaction.
+    pub fn last_value(&self, key: &K) -> Result<Option<V>, Box<Error>> {
+        if self.operands.contains_key(key) {
+            return Ok(try!(self.get(key)).next());
+        }
 
-        // setup tree
-        let mut btree = BTree::<String, String>::new(&file_path, 15, 15).unwrap();
+        let deleted = self.deleted.values(key);
+        let mut disk_value = None;
+        let mut last_buff = Vec::new();
+        let mut found = false;
+
+        try!(self.visit_disk_values(key, |value_buff| {
+            match deleted {
+                None => {
+                    last_buff.clear();
+                    last_buff.extend_from_slice(value_buff);
+                    found = true;
+                },
+                Some(deleted) => {
+                    let value = try!(VC::decode(value_buff));
+
+                    if !deleted.contains(&value) {
+                        disk_value = Some(value);
+                    }
+                }
+            }
 
-        // expected return set
-        let mut expected: BTreeSet<String> = BTreeSet::new();
-        expected.insert("World".to_string());
+            Ok(true)
+        }));
 
-        btree.insert("Hello".to_owned(), "World".to_owned());
+        if found {
+            disk_value = Some(try!(VC::decode(&last_buff)));
+        }
 
-        // get the set at the hello key
-        let set_at_hello: Vec<String> = btree.get(&"Hello".to_string()).unwrap().cloned().collect();
+        let mem_value = self.mem_tree.values(key).and_then(|values| values.iter().next_back().cloned());
 
-        assert_eq!(set_at_hello, ["World".to_string()]);
+        return Ok(match (disk_value, mem_value) {
+            (Some(disk_value), Some(mem_value)) => Some(cmp::max(disk_value, mem_value)),
+            (disk_value, mem_value) => disk_value.or(mem_value)
+        });
+    }
 
-        remove_files(file_path); // remove files assuming it all went well
+    /// Returns the values associated with the key that are within the range, in order.
+    /// Values on disk past the end of the range aren't read.
+    pub fn values_in_range<R: RangeBounds<V>>(&self, key: &K, range: R) -> Result<btree_set::IntoIter<V>, Box<Error>> {
+        if self.operands.contains_key(key) {
+            return Ok(try!(self.get(key)).filter(|v| range.contains(v)).collect::<BTreeSet<V>>().into_iter());
+        }
+
+        let deleted = self.deleted.values(key);
+        let mut values = BTreeSet::new();
+
+        try!(self.visit_disk_values(key, |value_buff| {
+            let value = try!(VC::decode(value_buff));
+
+            if past_range_end(&range, &value) {
+                return Ok(false);
+            }
+
+            if range.contains(&value) && !deleted.map_or(false, |values| values.contains(&value)) {
+                values.insert(value);
+            }
+
+            Ok(true)
+        }));
+
+        if let Some(mem_values) = self.mem_tree.values(key) {
+            values.extend(mem_values.iter().skip_while(|v| !range.contains(v)).take_while(|v| !past_range_end(&range, v)).cloned());
+        }
+
+        return Ok(values.into_iter());
     }
 
-    #[test]
-    fn insert_multiple() {
-        let file_path = gen_temp_name();
+    /// Returns true if the key's values in the tree file no longer count: the whole key
+    /// was deleted since they were written or, in a map, it has a newer value in memory
+    fn disk_key_hidden(&self, key: &K) -> bool {
+        return self.deleted.covers_key(key) || (self.map && self.mem_tree.contains_key(key));
+    }
 
-        let mut btree = BTree::<String, String>::new(&file_path, 15, 15).unwrap();
+    /// Calls visit with the stored bytes of each of the key's values in the tree file,
+    /// unless they are hidden by newer changes
+    fn visit_disk_values<F>(&self, key: &K, visit: F) -> Result<(), Box<Error>>
+        where F: FnMut(&[u8]) -> Result<bool, Box<Error>>
+    {
+        if self.disk_key_hidden(key) {
+            return Ok( () );
+        }
 
-        // insert into a new file
-        btree.insert("Hello".to_owned(), "World".to_owned()).unwrap();
-        assert!(! btree.wal_file.is_new().unwrap());
+        return self.tree_file.visit_values(key, visit);
+    }
 
-        btree.insert("Hello".to_owned(), "Everyone".to_owned()).unwrap();
-        assert!(! btree.wal_file.is_new().unwrap());
+    /// Returns an iterator over every (key, value) pair in the tree, in order. A leaf of
+    /// the tree file that can't be read, as when it fails its checksum, is an error that
+    /// ends the iteration. The operands merged into a map's keys are folded before
+    /// iterating, so this fails if they can't be, as when no merge operator is set.
+    pub fn iter<'a>(&'a self) -> Result<Box<Iterator<Item=Result<(K,V), Box<Error>>> + 'a>, Box<Error>> {
+        let error = Rc::new(RefCell::new(None));
+        let disk_iter = until_error(self.tree_file.into_iter(), error.clone());
+        let disk_iter = disk_iter.filter(move |kv| !self.disk_key_hidden(&kv.key) && !self.deleted.covers(&kv.key, &kv.value));
 
-        remove_files(file_path); // remove files assuming it all went well
+        let merged = self.mem_tree.into_iter().merge_by(disk_iter, |a, b| compare_records::<K,V,C>((&a.key, &a.value), (&b.key, &b.value)) != Ordering::Greater);
+
+        let records = merged.coalesce(|a, b| {
+            if compare_records::<K,V,C>((&a.key, &a.value), (&b.key, &b.value)) == Ordering::Equal { Ok(a) } else { Err((a, b)) }
+        });
+
+        let records = records.map(|kv| (kv.key, kv.value));
+
+        if self.operands.is_empty() {
+            return Ok(Box::new(WithError{records: records, error: error, done: false}));
+        }
+
+        let mut folded = Vec::new();
+
+        for key in self.operands.keys() {
+            let value = try!(self.get(&key)).next_back().expect("a key with operands has a value");
+
+            folded.push((key, value, true));
+        }
+
+        // the folded values, marked, are merged in after & replace their keys' values
+        let records = records.map(|(k, v)| (k, v, false))
+                             .merge_by(folded, |a, b| C::compare(&a.0, &b.0) != Ordering::Greater)
+                             .coalesce(|a, b| if b.2 && C::compare(&a.0, &b.0) == Ordering::Equal { Ok(b) } else { Err((a, b)) })
+                             .map(|(k, v, _)| (k, v));
+
+        return Ok(Box::new(WithError{records: records, error: error, done: false}));
+    }
+
+    /// Returns the changes in the WAL that haven't been compacted into the tree
+    /// file yet, with their sequence numbers
+    pub fn wal_records(&self) -> Result<Vec<(u64, WalRecord<K,V>)>, Box<Error>> {
+        let mut wal_file = try!(RecordFile::<K,V,KC,VC>::open_read_only(&self.layout.wal_path(), self.key_size(), self.value_size()));
+        let first_sequence = self.manifest.last_sequence() + 1;
+
+        return Ok((&mut wal_file).into_iter().enumerate().map(|(i, record)| (first_sequence + i as u64, record)).collect());
+    }
+
+    /// Returns true if each key has a single value, which an insert replaces; see MapBTree
+    pub fn is_map(&self) -> bool {
+        return self.map;
+    }
+
+    /// Returns the sequence number of the last insert or delete
+    pub fn last_sequence(&self) -> u64 {
+        return self.last_sequence;
+    }
+
+    pub fn key_size(&self) -> usize {
+        return self.manifest.key_size();
+    }
+
+    pub fn value_size(&self) -> usize {
+        return self.manifest.value_size();
+    }
+
+    /// Returns the sizes of the tree's files & in-memory BTree along with the stats of
+    /// its bloom filter, compression & block cache
+    pub fn stats(&self) -> Result<TreeStats, Box<Error>> {
+        let disk_bytes = try!(fs::metadata(self.layout.path(&self.tree_file_name))).len();
+
+        // a read-only tree may not have a WAL yet
+        let wal_bytes = fs::metadata(self.layout.wal_path()).map(|m| m.len()).unwrap_or(0);
+
+        return Ok(TreeStats{key_size: self.key_size(),
+                            value_size: self.value_size(),
+                            last_sequence: self.last_sequence,
+                            tree_files: vec![self.tree_file_name.to_owned()],
+                            disk_records: try!(self.tree_file.count()),
+                            disk_bytes: disk_bytes,
+                            memory_records: self.mem_tree.size(),
+                            memory_deletes: self.deleted.size(),
+                            wal_bytes: wal_bytes,
+                            bloom_filter: self.bloom_filter_stats(),
+                            compression: self.compression_stats(),
+                            block_cache: self.block_cache_stats()});
+    }
+
+    /// Returns how well the bloom filter of the tree file is ruling out lookups of absent keys
+    pub fn bloom_filter_stats(&self) -> BloomFilterStats {
+        return self.tree_file.bloom_filter_stats();
+    }
+
+    /// Walks every block of the tree file checking checksums & the structure of the
+    /// tree, returning a report of any damage found
+    pub fn verify(&self) -> Result<VerifyReport, Box<Error>> {
+        let mut report = VerifyReport::default();
+
+        self.tree_file.verify(&self.tree_file_name, &mut report);
+
+        return Ok(report);
+    }
+
+    /// Returns how much the nodes of the tree file were shrunk by compression
+    pub fn compression_stats(&self) -> CompressionStats {
+        return self.tree_file.compression_stats();
+    }
+
+    /// Returns the hit & miss counts of the block cache, None if caching is disabled
+    pub fn block_cache_stats(&self) -> Option<BlockCacheStats> {
+        return self.options.block_cache.as_ref().map(|cache| cache.stats());
+    }
+
+    /// Writes every record in the tree to the writer as JSON Lines or CSV, returning
+    /// the number of records written. Fails if a record can't be read, leaving the
+    /// output short.
+    pub fn export<W: Write>(&self, writer: W, format: ExportFormat) -> Result<u64, Box<Error>>
+        where K: Encodable, V: Encodable
+    {
+        return export::write_records(writer, format, try!(self.iter()));
+    }
+
+    /// Reads records written by export and adds them to the tree, returning the number
+    /// of records read. Records in the comparator's order, as export writes them, are
+    /// bulk loaded as they're read. From the first record out of order on, the rest are
+    /// inserted in batches through the WAL instead. A record that can't be read fails
+    /// the import; the tree is left unchanged unless records were out of order, as the
+    /// batches inserted before it are kept.
+    pub fn import<R: BufRead>(&mut self, reader: R, format: ExportFormat) -> Result<u64, Box<Error>>
+        where K: Decodable, V: Decodable
+    {
+        try!(self.check_writable());
+
+        let mut records = export::read_records::<K,V,R>(reader, format);
+        let mut last: Option<(K,V)> = None;
+        let mut out_of_order = None;
+        let mut count = 0;
+
+        {
+            // a map's records are merged by key alone, so only their keys need to be in order
+            let map = self.map;
+            let in_order = records.by_ref().scan((), |_, record| {
+                if let Ok(ref record) = record {
+                    let order = match last {
+                        Some(ref last) if map => C::compare(&record.0, &last.0),
+                        Some(ref last) => compare_records::<K,V,C>((&record.0, &record.1), (&last.0, &last.1)),
+                        None => Ordering::Greater
+                    };
+
+                    if order == Ordering::Less {
+                        out_of_order = Some(record.clone());
+                        return None;
+                    }
+
+                    last = Some(record.clone());
+                    count += 1;
+                }
+
+                Some(record)
+            }).fuse();
+
+            try!(self.compact_with(in_order));
+        }
+
+        if let Some((key, value)) = out_of_order {
+            let mut batch = vec![WalRecord::Insert(key, value)];
+
+            count += 1;
+
+            for record in records {
+                let (key, value) = try!(record);
+
+                batch.push(WalRecord::Insert(key, value));
+                count += 1;
+
+                if batch.len() == MAX_MEMORY_ITEMS {
+                    try!(self.write_batch(std::mem::replace(&mut batch, Vec::new())));
+                }
+            }
+
+            try!(self.write_batch(batch));
+        }
+
+        return Ok(count);
+    }
+
+    /// Loads records supplied in sorted order straight into a new tree file, merged with
+    /// the rest of the tree, bypassing the WAL & in-memory BTree. If a record is out of
+    /// order an error is returned and the tree is left unchanged. Returns the number of
+    /// records loaded. In a map the records replace the values of their keys, and only
+    /// the last record given for a key is kept.
+    pub fn bulk_load<I: IntoIterator<Item=(K,V)>>(&mut self, records: I) -> Result<u64, Box<Error>> {
+        let mut count = 0;
+
+        try!(self.compact_with(records.into_iter().inspect(|_| count += 1).map(Ok)));
+
+        return Ok(count);
+    }
+
+    /// Same as the write_tree_file function, storing the keys & values with this
+    /// tree's codecs so the file can be ingested
+    pub fn write_tree_file<I: IntoIterator<Item=(K,V)>>(file_path: &String, records: I, options: &Options) -> Result<u64, Box<Error>> {
+        return disk_btree::build_tree_file::<K,V,KC,VC,C,I>(file_path, records, options, false);
+    }
+
+    /// Adds the records of a tree file built elsewhere, usually with write_tree_file,
+    /// returning the number of records added. The file is verified first. If the tree
+    /// is empty the file is linked, or copied, in as the tree file; otherwise its
+    /// records are merged in with a compaction. The file itself is left in place.
+    pub fn ingest(&mut self, tree_file_path: &String) -> Result<u64, Box<Error>> {
+        try!(self.check_writable());
+
+        let options = Options{block_cache: None, ..self.options.clone()};
+        let external = try!(OnDiskBTree::<K,V,KC,VC,C>::open_read_only(tree_file_path.to_owned(), &options));
+        let mut report = VerifyReport::default();
+
+        if external.is_map().map_or(false, |map| map != self.map) {
+            return Err(From::from(IOError::new(ErrorKind::InvalidInput, if self.map { "Can't ingest a multi-value tree file into a map" } else { "Can't ingest a map's tree file into a multi-value tree" })));
+        }
+
+        external.verify(tree_file_path, &mut report);
+
+        if let Some(damage) = report.damage.first() {
+            return Err(From::from(IOError::new(ErrorKind::InvalidData, format!("Can't ingest a damaged tree file: {}", damage))));
+        }
+
+        let count = try!(external.count());
+
+        if self.mem_tree.size() > 0 || self.deleted.size() > 0 || !self.operands.is_empty() || try!(self.tree_file.count()) > 0 {
+            try!(self.compact_with(external.into_iter().map(|kv| kv.map(|kv| (kv.key, kv.value)))));
+
+            return Ok(count);
+        }
+
+        let new_tree_file_name = self.layout.tree_file_name(self.manifest.new_file_number());
+        let new_tree_file_path = self.layout.path(&new_tree_file_name);
+
+        // tree files are never modified once written, so sharing the file is safe
+        if fs::hard_link(tree_file_path, &new_tree_file_path).is_err() {
+            try!(fs::copy(tree_file_path, &new_tree_file_path));
+        }
+
+        try!(self.install_tree_file(new_tree_file_name));
+
+        return Ok(count);
+    }
+
+    /// Writes a copy of the tree as of the last change to a new directory, which opens
+    /// with BTree::open, without compacting or closing the tree. The tree file is hard
+    /// linked into the directory, or copied if it can't be, along with the part of the
+    /// WAL holding the changes since the last compaction. Returns the sequence number
+    /// of the last change in the copy.
+    pub fn checkpoint(&self, dest_dir: &String) -> Result<u64, Box<Error>> {
+        let dest = FileLayout::directory(dest_dir);
+
+        try!(fs::create_dir_all(dest_dir));
+
+        if fs::metadata(dest.manifest_path()).is_ok() {
+            return Err(From::from(IOError::new(ErrorKind::AlreadyExists, format!("{} already holds a tree", dest_dir))));
+        }
+
+        let tree_file_name = dest.tree_file_name(1);
+        let tree_file_path = self.layout.path(&self.tree_file_name);
+
+        // tree files are never modified once written, so sharing the file is safe
+        if fs::hard_link(&tree_file_path, dest.path(&tree_file_name)).is_err() {
+            try!(fs::copy(&tree_file_path, dest.path(&tree_file_name)));
+        }
+
+        // only the WAL records we've applied, as a writer may be adding more
+        let wal_records = self.last_sequence - self.manifest.last_sequence();
+        let wal_len = self.wal_file.len_of(wal_records);
+        let mut dest_wal = try!(File::create(dest.wal_path()));
+
+        if wal_records > 0 {
+            let copied = try!(io::copy(&mut try!(File::open(self.layout.wal_path())).take(wal_len), &mut dest_wal));
+
+            if copied != wal_len {
+                return Err(From::from(IOError::new(ErrorKind::Other, "Tree was compacted during the checkpoint, refresh and try again")));
+            }
+        }
+
+        try!(dest_wal.sync_all());
+
+        // the checkpoint is complete once its manifest is written
+        let mut manifest = try!(Manifest::new(&dest.manifest_path(), self.key_size(), self.value_size(), (KC::name(), VC::name()), C::name(), self.map, &tree_file_name));
+
+        try!(manifest.log_edit(vec![ManifestRecord::NextFileNumber(2),
+                                    ManifestRecord::LastSequence(self.manifest.last_sequence())]));
+
+        return Ok(self.last_sequence);
+    }
+
+    /// Writes the changes made after the given sequence number, the inserts & deletes in
+    /// the order they were made, to an incremental backup file that can be applied on
+    /// top of an earlier backup with apply_backup or restore. Changes that have been
+    /// compacted are read from the archived WALs, so the tree must have been opened with
+    /// Options::archive_wal since that sequence number. Records added by bulk_load,
+    /// import or ingest that don't go through the WAL aren't included.
+    pub fn backup_since(&self, sequence: u64, dest_file: &String) -> Result<BackupInfo, Box<Error>> {
+        if sequence > self.last_sequence {
+            return Err(From::from(IOError::new(ErrorKind::InvalidInput, format!("Sequence {} is after the last change, {}", sequence, self.last_sequence))));
+        }
+
+        // the archived WALs followed by the WAL hold every change in order
+        let mut wals = try!(self.archived_wals());
+
+        wals.push((self.manifest.last_sequence() + 1, self.layout.wal_path()));
+
+        let mut records = Vec::new();
+        let mut next = sequence + 1;
+
+        for (first, wal_path) in wals {
+            if next > self.last_sequence || first > next {
+                break;
+            }
+
+            let mut wal_file = try!(RecordFile::<K,V,KC,VC>::open_read_only(&wal_path, self.key_size(), self.value_size()));
+
+            for (i, record) in (&mut wal_file).into_iter().enumerate() {
+                if first + i as u64 == next && next <= self.last_sequence {
+                    records.push(record);
+                    next += 1;
+                }
+            }
+        }
+
+        if next != self.last_sequence + 1 {
+            return Err(From::from(IOError::new(ErrorKind::NotFound, format!("The changes after sequence {} are no longer available", next - 1))));
+        }
+
+        let info = BackupInfo{key_size: self.key_size(),
+                              value_size: self.value_size(),
+                              since: sequence,
+                              last_sequence: self.last_sequence};
+
+        try!(backup::write_backup::<K,V,KC,VC>(dest_file, &info, &records));
+
+        return Ok(info);
+    }
+
+    /// Applies an incremental backup written by backup_since, returning the sequence
+    /// number of the last change applied. The backup can't start after the last change
+    /// in the tree, and any changes already in the tree are skipped.
+    pub fn apply_backup(&mut self, backup_file: &String) -> Result<u64, Box<Error>> {
+        try!(self.check_writable());
+
+        let (info, records) = try!(backup::read_backup::<K,V,KC,VC>(backup_file));
+
+        if info.key_size != self.key_size() || info.value_size != self.value_size() {
+            return Err(From::from(IOError::new(ErrorKind::InvalidInput,
+                format!("Backup has key size {} and value size {}", info.key_size, info.value_size))));
+        }
+
+        if info.since > self.last_sequence {
+            return Err(From::from(IOError::new(ErrorKind::InvalidInput,
+                format!("Backup starts after sequence {}, but the tree's last change is {}", info.since, self.last_sequence))));
+        }
+
+        let applied = (self.last_sequence - info.since) as usize;
+        let mut records = records.into_iter().skip(applied);
+
+        while let Some(record) = records.next() {
+            // a replace is logged together with its Inserts, as it was originally
+            let group = match record {
+                WalRecord::Replace(_, count) => count as usize,
+                _ => 0
+            };
+
+            let mut batch = vec![record];
+
+            batch.extend(records.by_ref().take(group));
+
+            try!(self.write_batch(batch));
+        }
+
+        return Ok(self.last_sequence);
+    }
+
+    /// Same as the restore function, for a tree using this tree's codecs
+    pub fn restore(full_backup_dir: &String, incremental_backups: &[String], dest_dir: &String) -> Result<u64, Box<Error>> {
+        return backup::restore_tree::<K,V,KC,VC,C>(full_backup_dir, incremental_backups, dest_dir);
+    }
+
+    /// Same as the repair function, for a tree using this tree's codecs
+    pub fn repair(path: &String) -> Result<RepairReport, Box<Error>> {
+        return repair::repair_tree::<K,V,KC,VC,C>(path);
+    }
+
+    /// Removes the archived WALs that only hold changes up to the given sequence number,
+    /// once they are no longer needed for incremental backups
+    pub fn purge_archive(&self, sequence: u64) -> Result<(), Box<Error>> {
+        try!(self.check_writable());
+
+        let wals = try!(self.archived_wals());
+
+        // each archived WAL ends where the next one, or the WAL, starts
+        let ends: Vec<u64> = wals.iter().skip(1).map(|&(first, _)| first).chain(Some(self.manifest.last_sequence() + 1)).collect();
+
+        for (&(_, ref wal_path), end) in wals.iter().zip(ends) {
+            if end - 1 <= sequence {
+                try!(fs::remove_file(wal_path));
+            }
+        }
+
+        Ok( () )
+    }
+
+    /// Returns the archived WALs, oldest first, as the sequence number of each one's
+    /// first record and its path
+    fn archived_wals(&self) -> Result<Vec<(u64, String)>, Box<Error>> {
+        let archive_dir = self.layout.archive_dir();
+        let mut wals = Vec::new();
+
+        if fs::metadata(&archive_dir).is_err() {
+            return Ok(wals);
+        }
+
+        for entry in try!(fs::read_dir(&archive_dir)) {
+            let entry = try!(entry);
+
+            let first = match entry.file_name().to_str().and_then(|name| name.split('.').next()).map(|first| first.parse::<u64>()) {
+                Some(Ok(first)) => first,
+                _ => continue
+            };
+
+            wals.push((first, entry.path().to_string_lossy().into_owned()));
+        }
+
+        wals.sort();
+
+        return Ok(wals);
+    }
+
+    /// Copies the WAL into the archive directory, named after the sequence number of its
+    /// first record, if archiving is enabled
+    fn archive_wal(&self) -> Result<(), Box<Error>> {
+        if !self.options.archive_wal || try!(self.wal_file.is_new()) {
+            return Ok( () );
+        }
+
+        let archive_dir = self.layout.archive_dir();
+
+        try!(fs::create_dir_all(&archive_dir));
+
+        let archive_path = Path::new(&archive_dir).join(format!("{:020}.wal", self.manifest.last_sequence() + 1));
+
+        try!(fs::copy(self.layout.wal_path(), &archive_path));
+        try!(try!(File::open(&archive_path)).sync_all());
+
+        Ok( () )
+    }
+
+    /// Merges the records on disk with the records in memory into a new tree file,
+    /// dropping deleted records, and empties the WAL
+    pub fn compact(&mut self) -> Result<(), Box<Error>>{
+        return self.compact_with(Vec::new().into_iter());
+    }
+
+    /// Compacts, merging in the given sorted records as well. Fails without changing
+    /// the tree if the records are out of order, or if a record given or in the tree
+    /// file can't be read.
+    fn compact_with<I: Iterator<Item=Result<(K,V), Box<Error>>>>(&mut self, records: I) -> Result<(), Box<Error>>{
+        try!(self.check_writable());
+
+        let folded = try!(self.fold_operands());
+        let new_tree_file_name = self.layout.tree_file_name(self.manifest.new_file_number());
+        let error = Rc::new(RefCell::new(None));
+
+        let result = {
+            // get an iterator for the in-memory items, the values of keys with operands folded in
+            let tree = &*self;
+            let mem_iter = tree.mem_tree.into_iter()
+                                        .filter(|kv| !tree.operands.contains_key(&kv.key))
+                                        .merge_by(folded, |a, b| compare_records::<K,V,C>((&a.key, &a.value), (&b.key, &b.value)) != Ordering::Greater);
+
+            // get an iterator to the on-disk items that haven't been deleted or replaced
+            let disk_iter = until_error(tree.tree_file.into_iter(), error.clone()).filter(|kv| !tree.disk_key_hidden(&kv.key) && !tree.operands.contains_key(&kv.key) && !tree.deleted.covers(&kv.key, &kv.value));
+
+            // an out of order record leaves the merged records out of order too. In a map the
+            // given records are merged by key alone, after the tree's value of the same key,
+            // so they replace it as the newest when the tree file is built.
+            let map = self.map;
+            let merged = mem_iter.merge_by(disk_iter, |a, b| compare_records::<K,V,C>((&a.key, &a.value), (&b.key, &b.value)) != Ordering::Greater)
+                                 .map(|kv| (kv.key, kv.value))
+                                 .merge_by(until_error(records, error.clone()), |a, b| {
+                                     let order = if map { C::compare(&a.0, &b.0) } else { compare_records::<K,V,C>((&a.0, &a.1), (&b.0, &b.1)) };
+
+                                     order != Ordering::Greater
+                                 });
+
+            disk_btree::build_tree_file::<K,V,KC,VC,C,_>(&self.layout.path(&new_tree_file_name), merged, &self.options, self.map)
+        };
+
+        // a record missing from the new file would be lost once it replaces the old one
+        let error = error.borrow_mut().take();
+
+        if let Some(e) = error.or(result.err()) {
+            let _ = fs::remove_file(self.layout.path(&new_tree_file_name));
+            return Err(e);
+        }
+
+        return self.install_tree_file(new_tree_file_name);
+    }
+
+    /// Replaces the tree file with the given one, which holds every record in the tree,
+    /// and empties the WAL
+    fn install_tree_file(&mut self, new_tree_file_name: String) -> Result<(), Box<Error>> {
+        let new_tree_file = try!(OnDiskBTree::<K,V,KC,VC,C>::new(self.layout.path(&new_tree_file_name), &self.options));
+
+        // the WAL is about to be emptied, so keep a copy for incremental backups
+        try!(self.archive_wal());
+
+        // once the manifest points at the new file the old file & WAL are no longer needed
+        try!(self.manifest.log_edit(vec![ManifestRecord::AddFile(new_tree_file_name.to_owned()),
+                                         ManifestRecord::RemoveFile(self.tree_file_name.to_owned()),
+                                         ManifestRecord::NextFileNumber(self.manifest.next_file_number()),
+                                         ManifestRecord::LastSequence(self.last_sequence)]));
+
+        let old_tree_file_name = std::mem::replace(&mut self.tree_file_name, new_tree_file_name);
+
+        self.tree_file = new_tree_file;
+
+        try!(fs::remove_file(self.layout.path(&old_tree_file_name)));
+
+        try!(self.wal_file.truncate());
+        self.mem_tree.clear();
+        self.deleted.clear();
+        self.operands.clear();
+
+        Ok( () )
+    }
+
+    /// Removes any tree files left behind by an interrupted compaction
+    fn remove_orphaned_files(&self) -> Result<(), Box<Error>> {
+        for entry in try!(fs::read_dir(self.layout.dir())) {
+            let file_name = match try!(entry).file_name().into_string() {
+                Ok(name) => name,
+                Err(_) => continue
+            };
+
+            if self.layout.is_tree_file_name(&file_name) && !self.manifest.live_files().contains(&file_name) {
+                try!(fs::remove_file(self.layout.path(&file_name)));
+            }
+        }
+
+        Ok( () )
+    }
+}
+
+fn cloned_bound<T: Clone>(bound: Bound<&T>) -> Bound<T> {
+    match bound {
+        Bound::Included(t) => Bound::Included(t.clone()),
+        Bound::Excluded(t) => Bound::Excluded(t.clone()),
+        Bound::Unbounded => Bound::Unbounded
+    }
+}
+
+/// Passes on the records until one is an error, which is kept for the caller to check
+/// once the records are used
+fn until_error<'a, T: 'a, I>(records: I, error: Rc<RefCell<Option<Box<Error>>>>) -> Box<Iterator<Item=T> + 'a>
+    where I: Iterator<Item=Result<T, Box<Error>>> + 'a
+{
+    return Box::new(records.scan((), move |_, record| {
+        match record {
+            Ok(record) => Some(record),
+            Err(e) => {
+                *error.borrow_mut() = Some(e);
+                None
+            }
+        }
+    }));
+}
+
+/// Passes on the records until the error kept by until_error is set, then yields it
+/// as the last item
+struct WithError<I> {
+    records: I,
+    error: Rc<RefCell<Option<Box<Error>>>>,
+    done: bool,
+}
+
+impl <T, I: Iterator<Item=T>> Iterator for WithError<I> {
+    type Item = Result<T, Box<Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        // the error is checked after reading, as reading is what finds it
+        let record = self.records.next();
+
+        if let Some(e) = self.error.borrow_mut().take() {
+            self.done = true;
+            return Some(Err(e));
+        }
+
+        return record.map(Ok);
+    }
+}
+
+/// True if the value comes after every value in the range
+fn past_range_end<V: Ord, R: RangeBounds<V>>(range: &R, value: &V) -> bool {
+    match range.end_bound() {
+        Bound::Included(end) => value > end,
+        Bound::Excluded(end) => value >= end,
+        Bound::Unbounded => false
+    }
+}
+
+
+#[cfg(test)]
+#[allow(unused_must_use)]
+mod tests {
+    use std::fs;
+    use std::fs::OpenOptions;
+    use std::io::{Seek, SeekFrom, Write};
+    use std::path::Path;
+    use ::{BTree, BlockCache, ExportFormat, Options, Bincode, Raw, SerdeBincode, write_tree_file};
+    use ::{CaseInsensitive, Descending};
+    use std::sync::Arc;
+    use rand::{thread_rng, Rng};
+    use std::collections::BTreeSet;
+
+    pub fn gen_temp_name() -> String {
+        let file_name: String = thread_rng().gen_ascii_chars().take(10).collect();
+
+        return String::from("/tmp/") + &file_name + &String::from(".btr");
+    }
+
+    fn remove_files(file_path: String) {
+        fs::remove_file(&file_path);
+        fs::remove_file(file_path.to_owned() + ".wal");
+        fs::remove_file(file_path.to_owned() + ".manifest");
+        fs::remove_file(file_path + ".lock");
+    }
+
+    #[test]
+    fn new_blank_file() {
+        let file_path = gen_temp_name();
+
+        let btree = BTree::<u8, u8>::new(&file_path, 1, 1).unwrap();
+
+        // make sure our two files were created
+        let btf = OpenOptions::new().read(true).write(false).create(false).open(&file_path).unwrap();
+        assert!(btf.metadata().unwrap().len() == 0);
+
+        // the WAL holds just its header
+        let wal = OpenOptions::new().read(true).write(false).create(false).open(file_path.to_owned() + ".wal").unwrap();
+        assert!(wal.metadata().unwrap().len() == 8);
+
+        // make sure they think they're new too
+        assert!(btree.wal_file.is_new().unwrap());
+        assert!(btree.wal_file.count().unwrap() == 0);
+
+        assert!(btree.tree_file.is_new().unwrap());
+        assert!(btree.tree_file.count().unwrap() == 0);
+
+        remove_files(file_path); // remove files assuming it all went well
+    }
+
+    #[test]
+    fn new_existing_file() {
+        let file_path = gen_temp_name();
+
+        // scoped so it is cleaned up
+        { BTree::<u8, u8>::new(&file_path, 1, 1).unwrap(); }
+
+        let btree = BTree::<u8, u8>::new(&file_path, 1, 1).unwrap();
+
+        // check our file lengths from the struct
+        assert!(btree.tree_file.count().unwrap() == 0);
+        assert!(btree.wal_file.count().unwrap() == 0);
+
+        remove_files(file_path); // remove files assuming it all went well
+    }
+
+    #[test]
+    fn insert_new_u8() {
+        let file_path = gen_temp_name();
+
+        let mut btree = BTree::<u8, u8>::new(&file_path, 1, 1).unwrap();
+
+        btree.insert(2, 3).unwrap(); // insert into a new file
+
+        assert!(btree.wal_file.count().unwrap() == 1);
+        assert!(btree.mem_tree.contains_key(&2));
+
+        remove_files(file_path); // remove files assuming it all went well
+    }
+
+    #[test]
+    fn insert_new_str() {
+        let file_path = gen_temp_name();
+
+        let mut btree = BTree::<String, String>::new(&file_path, 15, 15).unwrap();
+
+        // insert into a new file
+        btree.insert("Hello".to_owned(), "World".to_owned()).unwrap();
+
+        assert!(! btree.wal_file.is_new().unwrap());
+        assert!(btree.mem_tree.contains_key(&String::from("Hello")));
+
+        remove_files(file_path); // remove files assuming it all went well
+    }
+
+    #[test]
+    fn get_returns_an_iter() {
+        let file_path = gen_temp_name();
+
+        // setup tree
+        let mut btree = BTree::<String, String>::new(&file_path, 15, 15).unwrap();
+
+        // expected return set
+        let mut expected: BTreeSet<String> = BTreeSet::new();
+        expected.insert("World".to_string());
+
+        btree.insert("Hello".to_owned(), "World".to_owned());
+
+        // get the set at the hello key
+        let set_at_hello: Vec<String> = btree.get(&"Hello".to_string()).unwrap().collect();
+
+        assert_eq!(set_at_hello, ["World".to_string()]);
+
+        remove_files(file_path); // remove files assuming it all went well
+    }
+
+    #[test]
+    fn insert_multiple() {
+        let file_path = gen_temp_name();
+
+        let mut btree = BTree::<String, String>::new(&file_path, 15, 15).unwrap();
+
+        // insert into a new file
+        btree.insert("Hello".to_owned(), "World".to_owned()).unwrap();
+        assert!(! btree.wal_file.is_new().unwrap());
+
+        btree.insert("Hello".to_owned(), "Everyone".to_owned()).unwrap();
+        assert!(! btree.wal_file.is_new().unwrap());
+
+        remove_files(file_path); // remove files assuming it all went well
+    }
+
+    #[test]
+    fn compact_replaces_tree_file() {
+        let file_path = gen_temp_name();
+
+        {
+            let mut btree = BTree::<String, String>::new(&file_path, 15, 15).unwrap();
+
+            btree.insert("Hello".to_owned(), "World".to_owned()).unwrap();
+            btree.insert("Hello".to_owned(), "Everyone".to_owned()).unwrap();
+
+            btree.compact().unwrap();
+
+            // the WAL is emptied and the original tree file replaced by a numbered one
+            assert!(btree.wal_file.is_new().unwrap());
+            assert!(btree.tree_file.count().unwrap() == 2);
+            assert!(fs::metadata(&file_path).is_err());
+            assert_eq!(btree.manifest.live_files(), &vec![btree.tree_file_name.to_owned()]);
+        }
+
+        // leave behind a file from an interrupted compaction
+        fs::File::create(file_path.to_owned() + ".7").unwrap();
+
+        let btree = BTree::<String, String>::new(&file_path, 15, 15).unwrap();
+
+        assert!(btree.tree_file.count().unwrap() == 2);
+        assert_eq!(btree.last_sequence, 2);
+        assert!(fs::metadata(file_path.to_owned() + ".7").is_err());
+
+        let report = btree.verify().unwrap();
+
+        assert!(report.is_ok());
+        assert_eq!(report.records, 2);
+
+        fs::remove_file(file_path.to_owned() + ".1");
+        remove_files(file_path);
+    }
+
+    #[test]
+    fn reopen_replays_wal() {
+        let file_path = gen_temp_name();
+
+        {
+            let mut btree = BTree::<String, String>::new(&file_path, 15, 15).unwrap();
+            btree.insert("Hello".to_owned(), "World".to_owned()).unwrap();
+        }
+
+        let btree = BTree::<String, String>::new(&file_path, 15, 15).unwrap();
+
+        assert!(btree.mem_tree.contains_key(&String::from("Hello")));
+        assert_eq!(btree.last_sequence, 1);
+
+        remove_files(file_path);
+    }
+
+    #[test]
+    fn open_directory() {
+        let dir_path = gen_temp_name();
+
+        {
+            let mut btree = BTree::<String, String>::open(&dir_path, 15, 15).unwrap();
+            btree.insert("Hello".to_owned(), "World".to_owned()).unwrap();
+            btree.compact().unwrap();
+        }
+
+        let btree = BTree::<String, String>::open(&dir_path, 15, 15).unwrap();
+
+        assert!(btree.tree_file.count().unwrap() == 1);
+
+        let mut names: Vec<String> = fs::read_dir(&dir_path).unwrap().map(|e| e.unwrap().file_name().into_string().unwrap()).collect();
+        names.sort();
+
+        assert_eq!(names, ["000001.btr", "LOCK", "MANIFEST", "WAL"]);
+
+        drop(btree);
+
+        // a missing tree file isn't made afresh as an empty one
+        let tree_file_path = Path::new(&dir_path).join("000001.btr");
+
+        fs::remove_file(&tree_file_path).unwrap();
+
+        assert!(BTree::<String, String>::open(&dir_path, 15, 15).is_err());
+        assert!(fs::metadata(&tree_file_path).is_err());
+
+        fs::remove_dir_all(dir_path);
+    }
+
+    #[test]
+    fn open_locked_directory() {
+        let dir_path = gen_temp_name();
+
+        {
+            let _btree = BTree::<String, String>::open(&dir_path, 15, 15).unwrap();
+
+            let err = BTree::<String, String>::open(&dir_path, 15, 15).err().unwrap();
+            assert!(err.to_string().contains("locked by another process"));
+        }
+
+        // the lock is released when the tree is dropped
+        BTree::<String, String>::open(&dir_path, 15, 15).unwrap();
+
+        fs::remove_dir_all(dir_path);
+    }
+
+    #[test]
+    fn open_read_only_while_writing() {
+        let dir_path = gen_temp_name();
+
+        let mut writer = BTree::<String, String>::open(&dir_path, 15, 15).unwrap();
+        writer.insert("Hello".to_owned(), "World".to_owned()).unwrap();
+
+        let mut reader = BTree::<String, String>::open_read_only(&dir_path, true).unwrap();
+        let no_wal = BTree::<String, String>::open_read_only(&dir_path, false).unwrap();
+
+        assert!(reader.mem_tree.contains_key(&String::from("Hello")));
+        assert!(!no_wal.mem_tree.contains_key(&String::from("Hello")));
+        assert!(reader.insert("Foo".to_owned(), "Bar".to_owned()).is_err());
+
+        // pick up the writer's compaction
+        writer.compact().unwrap();
+        writer.insert("Foo".to_owned(), "Bar".to_owned()).unwrap();
+
+        reader.refresh().unwrap();
+
+        assert_eq!(reader.tree_file_name, writer.tree_file_name);
+        assert!(reader.tree_file.count().unwrap() == 1);
+        assert!(reader.mem_tree.contains_key(&String::from("Foo")));
+        assert!(!reader.mem_tree.contains_key(&String::from("Hello")));
+
+        fs::remove_dir_all(dir_path);
+    }
+
+    #[test]
+    fn get_merges_memory_and_disk() {
+        let dir_path = gen_temp_name();
+
+        let mut options = Options::default();
+        options.bloom_bits_per_key = 20;
+
+        let mut btree = BTree::<String, String>::open_with_options(&dir_path, 15, 15, options).unwrap();
+
+        btree.insert("Hello".to_owned(), "World".to_owned()).unwrap();
+        btree.compact().unwrap();
+        btree.insert("Hello".to_owned(), "Everyone".to_owned()).unwrap();
+
+        let values: Vec<String> = btree.get(&"Hello".to_string()).unwrap().collect();
+
+        assert_eq!(values, ["Everyone".to_string(), "World".to_string()]);
+        assert!(btree.contains_key(&"Hello".to_string()).unwrap());
+        assert!(!btree.contains_key(&"Goodbye".to_string()).unwrap());
+        assert_eq!(btree.get(&"Goodbye".to_string()).unwrap().count(), 0);
+
+        let stats = btree.bloom_filter_stats();
+
+        assert_eq!(stats.lookups, 3);
+        assert_eq!(stats.negatives + stats.false_positives, 2);
+
+        fs::remove_dir_all(dir_path);
+    }
+
+    #[test]
+    fn delete_hides_values() {
+        let dir_path = gen_temp_name();
+
+        {
+            let mut btree = BTree::<String, String>::open(&dir_path, 15, 15).unwrap();
+
+            btree.insert("Hello".to_owned(), "World".to_owned()).unwrap();
+            btree.insert("Hello".to_owned(), "Everyone".to_owned()).unwrap();
+            btree.compact().unwrap();
+
+            // delete a value in the tree file & one only in memory
+            btree.delete("Hello".to_owned(), "World".to_owned()).unwrap();
+            btree.insert("Hello".to_owned(), "There".to_owned()).unwrap();
+            btree.delete("Hello".to_owned(), "There".to_owned()).unwrap();
+
+            assert_eq!(btree.get(&"Hello".to_string()).unwrap().collect::<Vec<_>>(), ["Everyone".to_string()]);
+            assert_eq!(btree.last_sequence, 5);
+        }
+
+        // the deletes are replayed from the WAL
+        let mut btree = BTree::<String, String>::open(&dir_path, 15, 15).unwrap();
+
+        assert_eq!(btree.iter().unwrap().collect::<Result<Vec<_>, _>>().unwrap(), [("Hello".to_string(), "Everyone".to_string())]);
+        assert_eq!(btree.wal_records().unwrap().len(), 3);
+
+        btree.delete("Hello".to_owned(), "Everyone".to_owned()).unwrap();
+
+        assert!(!btree.contains_key(&"Hello".to_string()).unwrap());
+
+        btree.compact().unwrap();
+
+        let stats = btree.stats().unwrap();
+
+        assert_eq!(stats.disk_records, 0);
+        assert_eq!(stats.memory_deletes, 0);
+        assert_eq!(stats.last_sequence, 6);
+        assert!(btree.iter().unwrap().next().is_none());
+
+        fs::remove_dir_all(dir_path);
+    }
+
+    #[test]
+    fn export_import() {
+        let src_path = gen_temp_name();
+        let dst_path = gen_temp_name();
+
+        let mut src = BTree::<String, u64>::open(&src_path, 15, 10).unwrap();
+
+        for i in 0..10 {
+            src.insert(format!("key{}", i % 3), i).unwrap();
+        }
+
+        src.delete("key0".to_owned(), 0).unwrap();
+
+        for format in vec![ExportFormat::JsonLines, ExportFormat::Csv] {
+            let mut buff = Vec::new();
+
+            assert_eq!(src.export(&mut buff, format).unwrap(), 9);
+
+            // records already in the tree are merged with the imported ones
+            let mut dst = BTree::<String, u64>::open(&dst_path, 15, 10).unwrap();
+
+            dst.insert("key0".to_owned(), 0).unwrap();
+
+            assert_eq!(dst.import(&buff[..], format).unwrap(), 9);
+            assert!(dst.wal_file.is_new().unwrap());
+            assert_eq!(dst.iter().unwrap().count(), 10);
+            assert_eq!(dst.get(&"key1".to_string()).unwrap().collect::<Vec<_>>(), [1, 4, 7]);
+
+            drop(dst);
+            fs::remove_dir_all(&dst_path);
+        }
+
+        // from the first record out of order on, records are inserted through the WAL
+        {
+            let mut dst = BTree::<String, u64>::open(&dst_path, 15, 10).unwrap();
+            let buff = b"{\"key\":\"key2\",\"value\":2}\n{\"key\":\"key3\",\"value\":3}\n{\"key\":\"key1\",\"value\":1}\n{\"key\":\"key4\",\"value\":4}\n";
+
+            assert_eq!(dst.import(&buff[..], ExportFormat::JsonLines).unwrap(), 4);
+            assert_eq!(dst.stats().unwrap().disk_records, 2);
+            assert_eq!(dst.wal_file.count().unwrap(), 2);
+            assert_eq!(dst.iter().unwrap().map(|r| r.unwrap().1).collect::<Vec<_>>(), [1, 2, 3, 4]);
+
+            // a record that can't be read leaves the tree as it was
+            assert!(dst.import(&b"{\"key\":\"key5\",\"value\":5}\nnot json\n"[..], ExportFormat::JsonLines).is_err());
+            assert_eq!(dst.iter().unwrap().count(), 4);
+        }
+
+        fs::remove_dir_all(&dst_path);
+
+        let mut buff = Vec::new();
+
+        src.export(&mut buff, ExportFormat::JsonLines).unwrap();
+
+        assert_eq!(String::from_utf8(buff).unwrap().lines().next().unwrap(), "{\"key\":\"key0\",\"value\":3}");
+
+        fs::remove_dir_all(&src_path);
+    }
+
+    #[test]
+    fn bulk_load_and_ingest() {
+        let dir_path = gen_temp_name();
+        let file_path = gen_temp_name();
+
+        let mut btree = BTree::<u32, u32>::open(&dir_path, 4, 4).unwrap();
+
+        assert_eq!(btree.bulk_load((0..5000).map(|i| (i, i))).unwrap(), 5000);
+        assert!(btree.wal_file.is_new().unwrap());
+        assert_eq!(btree.stats().unwrap().disk_records, 5000);
+
+        // an out of order record leaves the tree as it was
+        let tree_file_name = btree.tree_file_name.to_owned();
+
+        assert!(btree.bulk_load(vec![(6000, 0), (5500, 0)]).is_err());
+        assert_eq!(btree.tree_file_name, tree_file_name);
+        assert_eq!(fs::read_dir(&dir_path).unwrap().count(), 4);
+
+        // a file built elsewhere is merged into a tree with records...
+        assert_eq!(write_tree_file(&file_path, (4000..6000).map(|i| (i, i + 1)), &Options::default()).unwrap(), 2000);
+        assert_eq!(btree.ingest(&file_path).unwrap(), 2000);
+        assert_eq!(btree.iter().unwrap().count(), 7000);
+        assert_eq!(btree.get(&4000).unwrap().collect::<Vec<_>>(), [4000, 4001]);
+
+        // ...and becomes the tree file of an empty tree
+        let empty_path = gen_temp_name();
+        let mut empty = BTree::<u32, u32>::open(&empty_path, 4, 4).unwrap();
+
+        assert_eq!(empty.ingest(&file_path).unwrap(), 2000);
+        assert!(empty.verify().unwrap().is_ok());
+        assert_eq!(empty.iter().unwrap().next().unwrap().unwrap(), (4000, 4001));
+
+        fs::remove_file(&file_path);
+        fs::remove_dir_all(&dir_path);
+        fs::remove_dir_all(&empty_path);
+    }
+
+    #[test]
+    fn checkpoint_is_consistent() {
+        let dir_path = gen_temp_name();
+        let checkpoint_path = gen_temp_name();
+
+        let mut btree = BTree::<u32, u32>::open(&dir_path, 4, 4).unwrap();
+
+        for i in 0..10 {
+            btree.insert(i, i).unwrap();
+        }
+
+        btree.compact().unwrap();
+        btree.insert(10, 10).unwrap();
+        btree.delete(0, 0).unwrap();
+
+        assert_eq!(btree.checkpoint(&checkpoint_path).unwrap(), 12);
+        assert!(btree.checkpoint(&checkpoint_path).is_err());
+
+        // changes after the checkpoint don't show up in it
+        btree.insert(11, 11).unwrap();
+        btree.compact().unwrap();
+
+        let copy = BTree::<u32, u32>::open(&checkpoint_path, 4, 4).unwrap();
+
+        assert_eq!(copy.last_sequence, 12);
+        assert_eq!(copy.iter().unwrap().collect::<Result<Vec<_>, _>>().unwrap(), (1..11).map(|i| (i, i)).collect::<Vec<_>>());
+        assert!(copy.verify().unwrap().is_ok());
+
+        fs::remove_dir_all(&dir_path);
+        fs::remove_dir_all(&checkpoint_path);
+    }
+
+    // only implements serde, so needs the SerdeBincode codec
+    #[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone, Debug)]
+    struct Reading {
+        sensor: String,
+        at: u64,
+    }
+
+    #[test]
+    fn custom_codecs() {
+        let dir_path = gen_temp_name();
+
+        {
+            let mut btree = BTree::<Reading, Vec<u8>, SerdeBincode, Raw>::open(&dir_path, 32, 8).unwrap();
+
+            for i in 0..1500 {
+                btree.insert(Reading{sensor: format!("s{}", i % 3), at: i}, vec![i as u8]).unwrap();
+            }
+
+            btree.delete(Reading{sensor: String::from("s0"), at: 0}, vec![0]).unwrap();
+
+            assert!(btree.verify().unwrap().is_ok());
+            assert_eq!(btree.get(&Reading{sensor: String::from("s1"), at: 1000}).unwrap().collect::<Vec<_>>(), [vec![1000u32 as u8]]);
+            assert_eq!(btree.iter().unwrap().next().unwrap().unwrap().0, Reading{sensor: String::from("s0"), at: 3});
+        }
+
+        // the codecs are recorded with the tree, so it won't open with others
+        assert!(BTree::<Reading, Vec<u8>, SerdeBincode, SerdeBincode>::open(&dir_path, 32, 8).is_err());
+        assert!(BTree::<Vec<u8>, Vec<u8>>::open_read_only(&dir_path, true).is_err());
+
+        let btree = BTree::<Reading, Vec<u8>, SerdeBincode, Raw>::open_read_only(&dir_path, true).unwrap();
+
+        assert_eq!(btree.iter().unwrap().count(), 1499);
+
+        fs::remove_dir_all(&dir_path);
+    }
+
+    #[test]
+    fn custom_comparators() {
+        let dir_path = gen_temp_name();
+
+        {
+            let mut btree = BTree::<u32, u32, Bincode, Bincode, Descending>::open(&dir_path, 4, 4).unwrap();
+
+            for i in 0..1500 {
+                btree.insert(i, i).unwrap();
+            }
+
+            btree.insert(0, 1).unwrap();
+
+            assert!(btree.verify().unwrap().is_ok());
+            assert_eq!(btree.iter().unwrap().take(3).collect::<Result<Vec<_>, _>>().unwrap(), [(1499, 1499), (1498, 1498), (1497, 1497)]);
+            assert_eq!(btree.iter().unwrap().last().unwrap().unwrap(), (0, 1));
+            assert_eq!(btree.get(&700).unwrap().collect::<Vec<_>>(), [700]);
+        }
+
+        // the comparator is recorded in the tree file, so it won't open with another
+        assert!(BTree::<u32, u32>::open(&dir_path, 4, 4).is_err());
+
+        fs::remove_dir_all(&dir_path);
+
+        // and in the manifest, for a tree with every record still in the WAL
+        {
+            let mut btree = BTree::<u32, u32>::open(&dir_path, 4, 4).unwrap();
+
+            btree.insert(1, 1).unwrap();
+            btree.insert(2, 2).unwrap();
+        }
+
+        assert!(BTree::<u32, u32, Bincode, Bincode, Descending>::open(&dir_path, 4, 4).is_err());
+        assert!(BTree::<u32, u32, Bincode, Bincode, Descending>::open_read_only(&dir_path, true).is_err());
+        assert_eq!(BTree::<u32, u32>::open(&dir_path, 4, 4).unwrap().iter().unwrap().count(), 2);
+
+        fs::remove_dir_all(&dir_path);
+
+        let mut btree = BTree::<String, u32, Bincode, Bincode, CaseInsensitive>::open(&dir_path, 20, 4).unwrap();
+
+        for i in 0..1200 {
+            btree.insert(format!("Key{:04}", i), i).unwrap();
+        }
+
+        btree.insert(String::from("KEY0005"), 1).unwrap();
+
+        assert!(btree.verify().unwrap().is_ok());
+        assert_eq!(btree.get(&String::from("key0005")).unwrap().collect::<Vec<_>>(), [1, 5]);
+        assert!(btree.contains_key(&String::from("KEY1100")).unwrap());
+
+        fs::remove_dir_all(&dir_path);
+    }
+
+    #[test]
+    fn value_set_operations() {
+        let dir_path = gen_temp_name();
+        let mut btree = BTree::<u32, u32>::open(&dir_path, 4, 4).unwrap();
+
+        for i in 0..1200 {
+            btree.insert(i % 3, i).unwrap();
+        }
+
+        btree.compact().unwrap();
+
+        // only on disk
+        assert_eq!(btree.value_count(&1).unwrap(), 400);
+        assert_eq!(btree.first_value(&1).unwrap(), Some(1));
+        assert_eq!(btree.last_value(&1).unwrap(), Some(1198));
+        assert_eq!(btree.values_in_range(&1, 10..20).unwrap().collect::<Vec<_>>(), [10, 13, 16, 19]);
+
+        // on disk & in memory, with the first & last values deleted
+        btree.insert(1, 2000).unwrap();
+        btree.insert(1, 0).unwrap();
+        btree.insert(1, 4).unwrap();
+        btree.delete(1, 1).unwrap();
+        btree.delete(1, 2000).unwrap();
+        btree.delete(1, 1198).unwrap();
+
+        assert_eq!(btree.value_count(&1).unwrap(), 399);
+        assert_eq!(btree.first_value(&1).unwrap(), Some(0));
+        assert_eq!(btree.last_value(&1).unwrap(), Some(1195));
+        assert_eq!(btree.values_in_range(&1, ..=7).unwrap().collect::<Vec<_>>(), [0, 4, 7]);
+        assert_eq!(btree.values_in_range(&1, 1195..).unwrap().collect::<Vec<_>>(), [1195]);
+
+        assert_eq!(btree.value_count(&5).unwrap(), 0);
+        assert_eq!(btree.first_value(&5).unwrap(), None);
+        assert_eq!(btree.last_value(&5).unwrap(), None);
+        assert_eq!(btree.values_in_range(&5, ..).unwrap().count(), 0);
+
+        fs::remove_dir_all(&dir_path);
+    }
+
+    #[test]
+    fn delete_key_and_replace() {
+        let dir_path = gen_temp_name();
+
+        {
+            let mut btree = BTree::<u32, u32>::open(&dir_path, 4, 4).unwrap();
+
+            for i in 0..30 {
+                btree.insert(i % 3, i).unwrap();
+            }
+
+            btree.compact().unwrap();
+            btree.insert(1, 100).unwrap();
+            btree.delete_key(1).unwrap();
+
+            assert_eq!(btree.get(&1).unwrap().count(), 0);
+            assert!(!btree.contains_key(&1).unwrap());
+            assert_eq!(btree.value_count(&1).unwrap(), 0);
+            assert_eq!(btree.first_value(&1).unwrap(), None);
+
+            // values inserted after the delete aren't hidden by it
+            btree.insert(1, 4).unwrap();
+            btree.replace(2, vec![8, 7, 8]).unwrap();
+
+            assert_eq!(btree.get(&1).unwrap().collect::<Vec<_>>(), [4]);
+            assert_eq!(btree.get(&2).unwrap().collect::<Vec<_>>(), [7, 8]);
+            assert_eq!(btree.iter().unwrap().map(|record| record.unwrap()).filter(|&(k, _)| k != 0).collect::<Vec<_>>(), [(1, 4), (2, 7), (2, 8)]);
+        }
+
+        // the deletes are replayed from the WAL, and compaction drops the records
+        let mut btree = BTree::<u32, u32>::open(&dir_path, 4, 4).unwrap();
+
+        assert_eq!(btree.get(&2).unwrap().collect::<Vec<_>>(), [7, 8]);
+
+        btree.compact().unwrap();
+
+        assert_eq!(btree.tree_file.count().unwrap(), 13);
+        assert_eq!(btree.get(&1).unwrap().collect::<Vec<_>>(), [4]);
+
+        // a replace missing its last Insert, as if it was interrupted, is dropped entirely
+        btree.replace(0, vec![1, 2]).unwrap();
+
+        let wal_path = btree.layout.wal_path();
+        let wal_len = fs::metadata(&wal_path).unwrap().len();
+        let record_size = btree.wal_file.record_size() as u64;
+
+        drop(btree);
+
+        OpenOptions::new().write(true).open(&wal_path).unwrap().set_len(wal_len - record_size).unwrap();
+
+        let mut btree = BTree::<u32, u32>::open(&dir_path, 4, 4).unwrap();
+
+        assert_eq!(btree.value_count(&0).unwrap(), 10);
+
+        btree.insert(0, 1000).unwrap();
+
+        drop(btree);
+
+        let btree = BTree::<u32, u32>::open(&dir_path, 4, 4).unwrap();
+
+        assert_eq!(btree.value_count(&0).unwrap(), 11);
+        assert_eq!(btree.last_sequence(), 37);
+
+        fs::remove_dir_all(&dir_path);
+    }
+
+    #[test]
+    fn damaged_tree_file_stops_compaction() {
+        let dir_path = gen_temp_name();
+        let mut btree = BTree::<u32, u32>::open(&dir_path, 4, 4).unwrap();
+
+        for i in 0..500 {
+            btree.insert(i, i).unwrap();
+        }
+
+        btree.compact().unwrap();
+        btree.insert(1000, 1).unwrap();
+
+        let tree_file_path = btree.layout.path(&btree.tree_file_name);
+
+        // damage the first leaf, which starts after the 41 byte header
+        {
+            let mut fd = OpenOptions::new().write(true).open(&tree_file_path).unwrap();
+
+            fd.seek(SeekFrom::Start(50)).unwrap();
+            fd.write_all(&[0xff; 4]).unwrap();
+        }
+
+        // the records after the leaf would be lost, so neither the file nor the WAL is replaced
+        assert!(btree.compact().is_err());
+        assert!(btree.bulk_load(vec![(2000, 1)]).is_err());
+
+        let tree_files = fs::read_dir(&dir_path).unwrap().filter(|entry| btree.layout.is_tree_file_name(&entry.as_ref().unwrap().file_name().into_string().unwrap())).count();
+
+        assert!(fs::metadata(&tree_file_path).is_ok());
+        assert_eq!(tree_files, 1);
+        assert_eq!(btree.wal_file.count().unwrap(), 1);
+        assert_eq!(btree.get(&1000).unwrap().collect::<Vec<_>>(), [1]);
+
+        // reading every record fails too, rather than coming up short
+        assert!(btree.iter().unwrap().last().unwrap().is_err());
+        assert!(btree.export(Vec::new(), ExportFormat::JsonLines).is_err());
+
+        fs::remove_dir_all(&dir_path).unwrap();
+    }
+
+    #[test]
+    fn corrupt_wal_is_left_for_repair() {
+        let dir_path = gen_temp_name();
+
+        let (wal_path, record_size) = {
+            let mut btree = BTree::<u32, u32>::open(&dir_path, 4, 4).unwrap();
+
+            for i in 0..5 {
+                btree.insert(i, i).unwrap();
+            }
+
+            (btree.layout.wal_path(), btree.wal_file.record_size() as u64)
+        };
+
+        let wal_len = fs::metadata(&wal_path).unwrap().len();
+
+        // corrupt the key length of the third record
+        {
+            let mut fd = OpenOptions::new().write(true).open(&wal_path).unwrap();
+
+            fd.seek(SeekFrom::Start(wal_len - 3 * record_size + 1)).unwrap();
+            fd.write_all(&[0xff; 4]).unwrap();
+        }
+
+        // the records after it aren't dropped by opening the tree
+        assert!(BTree::<u32, u32>::open(&dir_path, 4, 4).is_err());
+        assert!(BTree::<u32, u32>::open_read_only(&dir_path, true).is_err());
+        assert_eq!(fs::metadata(&wal_path).unwrap().len(), wal_len);
+
+        let report = BTree::<u32, u32>::repair(&dir_path).unwrap();
+
+        assert_eq!(report.wal_records_recovered, 4);
+        assert_eq!(report.wal_records_lost, 1);
+
+        let btree = BTree::<u32, u32>::open(&dir_path, 4, 4).unwrap();
+
+        assert_eq!(btree.iter().unwrap().collect::<Result<Vec<_>, _>>().unwrap(), [(0, 0), (1, 1), (3, 3), (4, 4)]);
+
+        fs::remove_dir_all(&dir_path);
+    }
+
+    #[test]
+    fn delete_range() {
+        let dir_path = gen_temp_name();
+
+        {
+            let mut btree = BTree::<u32, u32>::open(&dir_path, 4, 4).unwrap();
+
+            for i in 0..900 {
+                btree.insert(i / 3, i).unwrap();
+            }
+
+            btree.compact().unwrap();
+
+            for i in 250..350 {
+                btree.insert(i, i).unwrap();
+            }
+
+            btree.delete_range(100..=280).unwrap();
+            btree.insert(150, 1).unwrap();
+
+            // keys on disk & in memory are hidden, but not those inserted afterwards
+            assert!(!btree.contains_key(&100).unwrap());
+            assert!(btree.contains_key(&99).unwrap());
+            assert_eq!(btree.get(&280).unwrap().count(), 0);
+            assert_eq!(btree.get(&281).unwrap().collect::<Vec<_>>(), [281, 843, 844, 845]);
+            assert_eq!(btree.get(&150).unwrap().collect::<Vec<_>>(), [1]);
+            assert_eq!(btree.iter().unwrap().count(), 427);
+        }
+
+        let mut btree = BTree::<u32, u32>::open(&dir_path, 4, 4).unwrap();
+
+        assert_eq!(btree.iter().unwrap().count(), 427);
+
+        // compaction drops the covered records & the range itself
+        btree.compact().unwrap();
+
+        assert_eq!(btree.tree_file.count().unwrap(), 427);
+        assert_eq!(btree.deleted.size(), 0);
+
+        btree.delete_range(..).unwrap();
+
+        assert_eq!(btree.iter().unwrap().count(), 0);
+
+        fs::remove_dir_all(&dir_path);
+    }
+
+    #[test]
+    fn shared_block_cache() {
+        let dir_path1 = gen_temp_name();
+        let dir_path2 = gen_temp_name();
+
+        let mut options = Options::default();
+        options.block_cache = Some(Arc::new(BlockCache::new(1 << 20)));
+
+        let mut btree1 = BTree::<String, String>::open_with_options(&dir_path1, 15, 15, options.clone()).unwrap();
+        let mut btree2 = BTree::<String, String>::open_with_options(&dir_path2, 15, 15, options).unwrap();
+
+        for btree in vec![&mut btree1, &mut btree2] {
+            btree.insert("Hello".to_owned(), "World".to_owned()).unwrap();
+            btree.compact().unwrap();
+            btree.get(&"Hello".to_string()).unwrap();
+            btree.get(&"Hello".to_string()).unwrap();
+        }
+
+        // both trees charge the same cache: a miss then a hit for the root & leaf of each
+        let stats = btree1.block_cache_stats().unwrap();
+
+        assert_eq!(stats, btree2.block_cache_stats().unwrap());
+        assert_eq!(stats.misses, 4);
+        assert_eq!(stats.hits, 4);
+        assert_eq!(stats.blocks, 4);
+
+        fs::remove_dir_all(dir_path1);
+        fs::remove_dir_all(dir_path2);
     }
 }
@@ -0,0 +1,420 @@
+use bincode::SizeLimit;
+use bincode::rustc_serialize::{encode, decode};
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+use crc::crc32;
+
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write, ErrorKind, Seek, SeekFrom};
+use std::io::Error as IOError;
+
+/// A single change to the set of files making up a tree
+#[derive(RustcEncodable, RustcDecodable, PartialEq, Clone, Debug)]
+pub enum ManifestRecord {
+    Options(usize, usize),   // the key size & value size the tree was created with
+    AddFile(String),         // a tree file (relative to the manifest) became live
+    RemoveFile(String),      // a tree file is no longer live
+    NextFileNumber(u64),     // the next number to use when naming a tree file
+    LastSequence(u64),       // the sequence number of the last insert stored in the tree files
+    Codecs(String, String),  // the names of the key & value codecs; bincode for both if missing
+    Map(bool),               // true if each key has a single value; false if missing
+    Comparator(String),      // the name of the comparator ordering the keys; ascending if missing
+}
+
+// the length & checksum before each edit
+const EDIT_HEADER_SIZE: u64 = 8;
+
+/// The manifest is an append-only log of edits describing which files constitute
+/// the tree. Each edit is a group of records applied atomically. The on-disk
+/// format is a sequence of edits:
+/// |-------------------------------------------|
+/// | length of edit (u32, big endian)          |
+/// |-------------------------------------------|
+/// | CRC-32C of the edit (u32, big endian)     |
+/// |-------------------------------------------|
+/// | Vec<ManifestRecord> in bincode format     |
+/// |-------------------------------------------|
+/// | ...                                       |
+/// |-------------------------------------------|
+/// A partially written edit at the end of the file (from a crash) is ignored. Any other
+/// edit that can't be read is an error, as the edits after it would be lost with it.
+pub struct Manifest {
+    fd: File,
+    key_size: usize,
+    value_size: usize,
+    key_codec: String,
+    value_codec: String,
+    map: bool,
+    comparator: String,
+    live_files: Vec<String>,  // in the order they were added
+    next_file_number: u64,
+    last_sequence: u64,
+}
+
+impl Manifest {
+    /// Opens the manifest at the given path, creating it if needed. A new manifest
+    /// records the options, the codecs & comparator, whether the tree is a map and the
+    /// initial tree file; an existing one keeps whether the tree was created as a map.
+    pub fn new(manifest_path: &String, key_size: usize, value_size: usize, codecs: (&str, &str), comparator: &str, map: bool, initial_file: &String) -> Result<Manifest, Box<Error>> {
+        let fd = try!(OpenOptions::new().read(true).write(true).create(true).open(manifest_path));
+
+        let mut manifest = Manifest{fd: fd,
+                                    key_size: key_size,
+                                    value_size: value_size,
+                                    key_codec: String::from("bincode"),
+                                    value_codec: String::from("bincode"),
+                                    map: false,
+                                    comparator: String::from("ascending"),
+                                    live_files: Vec::new(),
+                                    next_file_number: 1,
+                                    last_sequence: 0};
+
+        if try!(manifest.fd.metadata()).len() == 0 {
+            try!(manifest.log_edit(vec![ManifestRecord::Options(key_size, value_size),
+                                        ManifestRecord::Codecs(codecs.0.to_owned(), codecs.1.to_owned()),
+                                        ManifestRecord::Comparator(comparator.to_owned()),
+                                        ManifestRecord::Map(map),
+                                        ManifestRecord::AddFile(initial_file.to_owned())]));
+        } else {
+            let valid_len = try!(manifest.replay());
+
+            // drop any partially written edit so new edits are appended after the last good one
+            try!(manifest.fd.set_len(valid_len));
+            try!(manifest.fd.seek(SeekFrom::End(0)));
+
+            if manifest.key_size != key_size || manifest.value_size != value_size {
+                return Err(From::from(IOError::new(ErrorKind::InvalidInput,
+                    format!("Tree was created with key size {} and value size {}", manifest.key_size, manifest.value_size))));
+            }
+
+            try!(manifest.check_codecs(codecs));
+            try!(manifest.check_comparator(comparator));
+        }
+
+        return Ok(manifest);
+    }
+
+    /// Opens an existing manifest without ever writing to it. The key & value sizes
+    /// are taken from the manifest.
+    pub fn open_read_only(manifest_path: &String) -> Result<Manifest, Box<Error>> {
+        let fd = try!(OpenOptions::new().read(true).open(manifest_path));
+
+        let mut manifest = Manifest{fd: fd,
+                                    key_size: 0,
+                                    value_size: 0,
+                                    key_codec: String::from("bincode"),
+                                    value_codec: String::from("bincode"),
+                                    map: false,
+                                    comparator: String::from("ascending"),
+                                    live_files: Vec::new(),
+                                    next_file_number: 1,
+                                    last_sequence: 0};
+
+        try!(manifest.replay());
+
+        return Ok(manifest);
+    }
+
+    /// Opens a possibly damaged manifest without writing to it, as open_read_only, but
+    /// stops at the first damaged edit instead of failing. Returns the manifest as of the
+    /// edits before it, and the offset of the damaged edit if there is one.
+    pub fn salvage(manifest_path: &String) -> Result<(Manifest, Option<u64>), Box<Error>> {
+        let fd = try!(OpenOptions::new().read(true).open(manifest_path));
+
+        let mut manifest = Manifest{fd: fd,
+                                    key_size: 0,
+                                    value_size: 0,
+                                    key_codec: String::from("bincode"),
+                                    value_codec: String::from("bincode"),
+                                    map: false,
+                                    comparator: String::from("ascending"),
+                                    live_files: Vec::new(),
+                                    next_file_number: 1,
+                                    last_sequence: 0};
+
+        let (valid_len, damaged) = try!(manifest.replay_edits());
+
+        return Ok((manifest, if damaged { Some(valid_len) } else { None }));
+    }
+
+    /// Reads all of the edits in the file, reconstructing the live state. Returns
+    /// the length of the file up to the end of the last complete edit.
+    fn replay(&mut self) -> Result<u64, Box<Error>> {
+        let (valid_len, damaged) = try!(self.replay_edits());
+
+        if damaged {
+            return Err(damaged_edit(valid_len));
+        }
+
+        Ok(valid_len)
+    }
+
+    /// Reads the edits in the file up to the first damaged one, returning the length
+    /// of the file up to the end of the last good edit and whether a damaged one follows
+    fn replay_edits(&mut self) -> Result<(u64, bool), Box<Error>> {
+        let file_len = try!(self.fd.metadata()).len();
+        let mut valid_len = 0;
+
+        try!(self.fd.seek(SeekFrom::Start(0)));
+
+        // only the last edit can have been cut short by a crash
+        while valid_len + EDIT_HEADER_SIZE <= file_len {
+            let mut header = [0; EDIT_HEADER_SIZE as usize];
+
+            try!(self.fd.read_exact(&mut header));
+
+            let len = BigEndian::read_u32(&header[..4]) as u64;
+
+            if valid_len + EDIT_HEADER_SIZE + len > file_len {
+                break;
+            }
+
+            let mut buff = vec![0; len as usize];
+
+            try!(self.fd.read_exact(&mut buff));
+
+            if crc32::checksum_castagnoli(&buff) != BigEndian::read_u32(&header[4..]) {
+                return Ok((valid_len, true));
+            }
+
+            let edit: Vec<ManifestRecord> = match decode(&buff) {
+                Ok(edit) => edit,
+                Err(_) => return Ok((valid_len, true))
+            };
+
+            for record in edit {
+                self.apply(record);
+            }
+
+            valid_len += EDIT_HEADER_SIZE + len;
+        }
+
+        Ok((valid_len, false))
+    }
+
+    fn apply(&mut self, record: ManifestRecord) {
+        match record {
+            ManifestRecord::Options(key_size, value_size) => {
+                self.key_size = key_size;
+                self.value_size = value_size;
+            },
+            ManifestRecord::AddFile(name) => self.live_files.push(name),
+            ManifestRecord::RemoveFile(name) => self.live_files.retain(|f| f != &name),
+            ManifestRecord::NextFileNumber(num) => self.next_file_number = num,
+            ManifestRecord::LastSequence(seq) => self.last_sequence = seq,
+            ManifestRecord::Codecs(key_codec, value_codec) => {
+                self.key_codec = key_codec;
+                self.value_codec = value_codec;
+            },
+            ManifestRecord::Map(map) => self.map = map,
+            ManifestRecord::Comparator(comparator) => self.comparator = comparator,
+        }
+    }
+
+    /// Fails unless the tree was created with the given key & value codecs
+    pub fn check_codecs(&self, codecs: (&str, &str)) -> Result<(), Box<Error>> {
+        if self.key_codec != codecs.0 || self.value_codec != codecs.1 {
+            return Err(From::from(IOError::new(ErrorKind::InvalidInput,
+                format!("Tree was created with key codec {} and value codec {}", self.key_codec, self.value_codec))));
+        }
+
+        Ok( () )
+    }
+
+    /// Fails unless the tree's keys were ordered by the given comparator. The tree files
+    /// record it too, but a tree whose records are all in the WAL has none to check.
+    pub fn check_comparator(&self, comparator: &str) -> Result<(), Box<Error>> {
+        if self.comparator != comparator {
+            return Err(From::from(IOError::new(ErrorKind::InvalidInput, format!("Tree was created with the {} comparator", self.comparator))));
+        }
+
+        Ok( () )
+    }
+
+    /// Appends an edit to the manifest and applies it once it is safely on disk
+    pub fn log_edit(&mut self, edit: Vec<ManifestRecord>) -> Result<(), Box<Error>> {
+        let buff = try!(encode(&edit, SizeLimit::Infinite));
+
+        let mut entry = Vec::with_capacity(EDIT_HEADER_SIZE as usize + buff.len());
+
+        try!(entry.write_u32::<BigEndian>(buff.len() as u32));
+        try!(entry.write_u32::<BigEndian>(crc32::checksum_castagnoli(&buff)));
+        entry.extend(buff);
+
+        try!(self.fd.write_all(&entry));
+        try!(self.fd.sync_data());
+
+        for record in edit {
+            self.apply(record);
+        }
+
+        Ok( () )
+    }
+
+    /// Reserves a number for naming a new tree file
+    pub fn new_file_number(&mut self) -> u64 {
+        let num = self.next_file_number;
+
+        self.next_file_number += 1;
+
+        return num;
+    }
+
+    pub fn next_file_number(&self) -> u64 {
+        return self.next_file_number;
+    }
+
+    pub fn live_files(&self) -> &Vec<String> {
+        return &self.live_files;
+    }
+
+    pub fn last_sequence(&self) -> u64 {
+        return self.last_sequence;
+    }
+
+    /// Returns true if the tree was created as a map, with a single value for each key
+    pub fn is_map(&self) -> bool {
+        return self.map;
+    }
+
+    pub fn key_size(&self) -> usize {
+        return self.key_size;
+    }
+
+    pub fn value_size(&self) -> usize {
+        return self.value_size;
+    }
+}
+fn damaged_edit(offset: u64) -> Box<Error> {
+    return From::from(IOError::new(ErrorKind::InvalidData, format!("Manifest edit at offset {} is damaged, repair the tree to rebuild it", offset)));
+}
+
+
+#[cfg(test)]
+mod tests {
+    use tests::gen_temp_name;
+    use std::fs;
+    use std::fs::OpenOptions;
+    use std::io::{Seek, SeekFrom, Write};
+    use manifest::{Manifest, ManifestRecord};
+
+    #[test]
+    fn replay_edits() {
+        let file_path = gen_temp_name() + ".manifest";
+
+        {
+            let mut manifest = Manifest::new(&file_path, 4, 8, ("bincode", "bincode"), "ascending", false, &String::from("a.btr")).unwrap();
+
+            let num = manifest.new_file_number();
+
+            manifest.log_edit(vec![ManifestRecord::AddFile(format!("a.btr.{}", num)),
+                                   ManifestRecord::RemoveFile(String::from("a.btr")),
+                                   ManifestRecord::NextFileNumber(manifest.next_file_number()),
+                                   ManifestRecord::LastSequence(17)]).unwrap();
+        }
+
+        let manifest = Manifest::new(&file_path, 4, 8, ("bincode", "bincode"), "ascending", true, &String::from("a.btr")).unwrap();
+
+        assert_eq!(manifest.live_files(), &vec![String::from("a.btr.1")]);
+        assert!(!manifest.is_map());
+        assert_eq!(manifest.next_file_number(), 2);
+        assert_eq!(manifest.last_sequence(), 17);
+
+        fs::remove_file(&file_path).unwrap();
+    }
+
+    #[test]
+    fn ignores_partial_edit() {
+        let file_path = gen_temp_name() + ".manifest";
+
+        { Manifest::new(&file_path, 4, 8, ("bincode", "bincode"), "ascending", false, &String::from("a.btr")).unwrap(); }
+
+        // simulate a crash part way through writing an edit
+        {
+            let mut fd = OpenOptions::new().append(true).open(&file_path).unwrap();
+            fd.write_all(&[0, 0, 0, 40, 1, 2]).unwrap();
+        }
+
+        let manifest = Manifest::new(&file_path, 4, 8, ("bincode", "bincode"), "ascending", false, &String::from("a.btr")).unwrap();
+
+        assert_eq!(manifest.live_files(), &vec![String::from("a.btr")]);
+
+        fs::remove_file(&file_path).unwrap();
+    }
+
+    #[test]
+    fn rejects_damaged_edit() {
+        let file_path = gen_temp_name() + ".manifest";
+
+        {
+            let mut manifest = Manifest::new(&file_path, 4, 8, ("bincode", "bincode"), "ascending", false, &String::from("a.btr")).unwrap();
+
+            manifest.log_edit(vec![ManifestRecord::LastSequence(17)]).unwrap();
+        }
+
+        // a partial edit claiming to be far longer than the file is ignored, without reading it
+        {
+            let mut fd = OpenOptions::new().append(true).open(&file_path).unwrap();
+            fd.write_all(&[0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0, 1]).unwrap();
+        }
+
+        assert_eq!(Manifest::open_read_only(&file_path).unwrap().last_sequence(), 17);
+        assert_eq!(Manifest::salvage(&file_path).unwrap().1, None);
+
+        // but a damaged edit followed by others fails, leaving the file for repair
+        {
+            let mut fd = OpenOptions::new().write(true).open(&file_path).unwrap();
+
+            fd.seek(SeekFrom::Start(10)).unwrap();
+            fd.write_all(&[0xff]).unwrap();
+        }
+
+        let len = fs::metadata(&file_path).unwrap().len();
+
+        assert!(Manifest::open_read_only(&file_path).is_err());
+        assert!(Manifest::new(&file_path, 4, 8, ("bincode", "bincode"), "ascending", false, &String::from("a.btr")).is_err());
+        assert_eq!(fs::metadata(&file_path).unwrap().len(), len);
+
+        // salvage reads up to the damaged edit instead
+        assert_eq!(Manifest::salvage(&file_path).unwrap().1, Some(0));
+
+        fs::remove_file(&file_path).unwrap();
+    }
+
+    #[test]
+    fn read_only_leaves_file_alone() {
+        let file_path = gen_temp_name() + ".manifest";
+
+        { Manifest::new(&file_path, 4, 8, ("bincode", "bincode"), "ascending", false, &String::from("a.btr")).unwrap(); }
+
+        {
+            let mut fd = OpenOptions::new().append(true).open(&file_path).unwrap();
+            fd.write_all(&[0, 0, 0, 40, 1, 2]).unwrap();
+        }
+
+        let len = fs::metadata(&file_path).unwrap().len();
+        let manifest = Manifest::open_read_only(&file_path).unwrap();
+
+        assert_eq!(manifest.key_size(), 4);
+        assert_eq!(manifest.value_size(), 8);
+        assert_eq!(manifest.live_files(), &vec![String::from("a.btr")]);
+        assert_eq!(fs::metadata(&file_path).unwrap().len(), len);
+
+        fs::remove_file(&file_path).unwrap();
+    }
+
+    #[test]
+    fn rejects_different_sizes() {
+        let file_path = gen_temp_name() + ".manifest";
+
+        { Manifest::new(&file_path, 4, 8, ("bincode", "bincode"), "ascending", false, &String::from("a.btr")).unwrap(); }
+
+        assert!(Manifest::new(&file_path, 5, 8, ("bincode", "bincode"), "ascending", false, &String::from("a.btr")).is_err());
+        assert!(Manifest::new(&file_path, 4, 8, ("bincode", "raw"), "ascending", false, &String::from("a.btr")).is_err());
+        assert!(Manifest::new(&file_path, 4, 8, ("bincode", "bincode"), "descending", false, &String::from("a.btr")).is_err());
+        assert!(Manifest::open_read_only(&file_path).unwrap().check_codecs(("bincode", "raw")).is_err());
+        assert!(Manifest::open_read_only(&file_path).unwrap().check_comparator("descending").is_err());
+
+        fs::remove_file(&file_path).unwrap();
+    }
+}
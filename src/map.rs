@@ -0,0 +1,237 @@
+use layout::FileLayout;
+
+use ::{BTree, Options, KeyType, ValueType, Codec, Bincode, Comparator, Ascending, MergeOperator};
+
+use std::error::Error;
+use std::fs;
+use std::io::ErrorKind;
+use std::io::Error as IOError;
+
+/// A tree with a single value for each key, like a BTreeMap: an insert replaces the
+/// key's value and get returns it, if there is one. It is a BTree created as a map,
+/// which is recorded in the manifest & the header of each tree file, so compactions
+/// keep only the newest value of each key. The tree can still be used as a BTree
+/// through tree_mut, and it stays a map.
+pub struct MapBTree<K: KeyType, V: ValueType, KC: Codec<K> = Bincode, VC: Codec<V> = Bincode, C: Comparator<K> = Ascending> {
+    tree: BTree<K,V,KC,VC,C>,
+}
+
+impl <K: KeyType, V: ValueType, KC: Codec<K>, VC: Codec<V>, C: Comparator<K>> MapBTree<K,V,KC,VC,C> {
+    /// Opens the map stored in the given directory, creating it if needed. Fails if the
+    /// tree there was created as a BTree, with many values per key.
+    pub fn open(dir_path: &String, key_size: usize, value_size: usize) -> Result<MapBTree<K,V,KC,VC,C>, Box<Error>> {
+        return MapBTree::open_with_options(dir_path, key_size, value_size, Options::default());
+    }
+
+    /// Same as open, but with the given options instead of the defaults
+    pub fn open_with_options(dir_path: &String, key_size: usize, value_size: usize, options: Options) -> Result<MapBTree<K,V,KC,VC,C>, Box<Error>> {
+        try!(fs::create_dir_all(dir_path));
+
+        return MapBTree::from_tree(try!(BTree::open_layout(FileLayout::directory(dir_path), key_size, value_size, options, true)));
+    }
+
+    /// Opens the map stored in the given directory for reading only, as BTree::open_read_only
+    pub fn open_read_only(dir_path: &String, replay_wal: bool) -> Result<MapBTree<K,V,KC,VC,C>, Box<Error>> {
+        return MapBTree::from_tree(try!(BTree::open_read_only(dir_path, replay_wal)));
+    }
+
+    fn from_tree(tree: BTree<K,V,KC,VC,C>) -> Result<MapBTree<K,V,KC,VC,C>, Box<Error>> {
+        if !tree.is_map() {
+            return Err(From::from(IOError::new(ErrorKind::InvalidInput, "Tree was created with many values per key, open it as a BTree")));
+        }
+
+        return Ok(MapBTree{tree: tree});
+    }
+
+    /// Sets the value of the key, replacing any value it had
+    pub fn insert(&mut self, key: K, value: V) -> Result<(), Box<Error>> {
+        return self.tree.insert(key, value);
+    }
+
+    /// Removes the key & its value
+    pub fn remove(&mut self, key: K) -> Result<(), Box<Error>> {
+        return self.tree.delete_key(key);
+    }
+
+    /// Returns the value of the key, if it has one
+    pub fn get(&self, key: &K) -> Result<Option<V>, Box<Error>> {
+        return self.tree.first_value(key);
+    }
+
+    /// Merges an operand into the key's value with the merge operator, as BTree::merge
+    pub fn merge(&mut self, key: K, operand: V) -> Result<(), Box<Error>> {
+        return self.tree.merge(key, operand);
+    }
+
+    pub fn set_merge_operator<M: MergeOperator<K,V> + 'static>(&mut self, merge_operator: M) {
+        self.tree.set_merge_operator(merge_operator);
+    }
+
+    pub fn contains_key(&self, key: &K) -> Result<bool, Box<Error>> {
+        return self.tree.contains_key(key);
+    }
+
+    /// Returns an iterator over every key & its value, in order, as BTree::iter
+    pub fn iter<'a>(&'a self) -> Result<Box<Iterator<Item=Result<(K,V), Box<Error>>> + 'a>, Box<Error>> {
+        return self.tree.iter();
+    }
+
+    /// Merges the records in memory into a new tree file, as BTree::compact
+    pub fn compact(&mut self) -> Result<(), Box<Error>> {
+        return self.tree.compact();
+    }
+
+    /// Same as BTree::write_tree_file, for a file to be ingested by a map. Only the last
+    /// of the values given for a key is kept.
+    pub fn write_tree_file<I: IntoIterator<Item=(K,V)>>(file_path: &String, records: I, options: &Options) -> Result<u64, Box<Error>> {
+        return ::disk_btree::build_tree_file::<K,V,KC,VC,C,I>(file_path, records, options, true);
+    }
+
+    /// Returns the tree, for everything else a tree can do
+    pub fn tree(&self) -> &BTree<K,V,KC,VC,C> {
+        return &self.tree;
+    }
+
+    pub fn tree_mut(&mut self) -> &mut BTree<K,V,KC,VC,C> {
+        return &mut self.tree;
+    }
+
+    pub fn into_tree(self) -> BTree<K,V,KC,VC,C> {
+        return self.tree;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use tests::gen_temp_name;
+    use map::MapBTree;
+    use ::{BTree, Options};
+    use std::fs;
+
+    #[test]
+    fn keeps_newest_value() {
+        let dir_path = gen_temp_name();
+        let file_path = gen_temp_name();
+
+        {
+            let mut map = MapBTree::<u32, u32>::open(&dir_path, 4, 4).unwrap();
+
+            for i in 0..1500 {
+                map.insert(i % 500, i).unwrap();
+            }
+
+            map.insert(7, 1).unwrap();
+            map.remove(8).unwrap();
+
+            assert_eq!(map.get(&7).unwrap(), Some(1));
+            assert_eq!(map.get(&8).unwrap(), None);
+            assert_eq!(map.get(&9).unwrap(), Some(1009));
+            assert_eq!(map.iter().unwrap().count(), 499);
+
+            // bulk loaded records replace values too, the last given for a key winning
+            map.tree_mut().bulk_load(vec![(9, 1), (9, 2), (600, 3)]).unwrap();
+
+            assert_eq!(map.get(&9).unwrap(), Some(2));
+            assert!(map.tree_mut().replace(10, vec![1, 2]).is_err());
+
+            // whatever the order of its values
+            map.insert(9, 5).unwrap();
+            map.tree_mut().bulk_load(vec![(9, 4), (9, 3)]).unwrap();
+
+            assert_eq!(map.get(&9).unwrap(), Some(3));
+        }
+
+        // the tree stays a map whichever way it's opened, and compactions keep one value per key
+        {
+            let mut btree = BTree::<u32, u32>::open(&dir_path, 4, 4).unwrap();
+
+            assert!(btree.is_map());
+
+            btree.insert(7, 2).unwrap();
+            btree.compact().unwrap();
+
+            assert_eq!(btree.tree_file.count().unwrap(), 500);
+            assert_eq!(btree.get(&7).unwrap().collect::<Vec<_>>(), [2]);
+        }
+
+        let map = MapBTree::<u32, u32>::open_read_only(&dir_path, true).unwrap();
+
+        assert_eq!(map.get(&7).unwrap(), Some(2));
+
+        // a file written for a map keeps the last value given for a key, as does its ingest
+        MapBTree::<u32, u32>::write_tree_file(&file_path, vec![(1, 1), (7, 9), (7, 8)], &Options::default()).unwrap();
+
+        {
+            let ingest_path = gen_temp_name();
+            let mut map = MapBTree::<u32, u32>::open(&ingest_path, 4, 4).unwrap();
+
+            map.tree_mut().ingest(&file_path).unwrap();
+
+            assert_eq!(map.iter().unwrap().collect::<Result<Vec<_>, _>>().unwrap(), [(1, 1), (7, 8)]);
+
+            map.insert(1, 2).unwrap();
+            map.tree_mut().ingest(&file_path).unwrap();
+
+            assert_eq!(map.iter().unwrap().collect::<Result<Vec<_>, _>>().unwrap(), [(1, 1), (7, 8)]);
+
+            fs::remove_dir_all(&ingest_path).unwrap();
+        }
+
+        // a multi-value tree can't be opened as a map, or ingest a map's file
+        fs::remove_dir_all(&dir_path).unwrap();
+
+        BTree::<u32, u32>::open(&dir_path, 4, 4).unwrap();
+
+        assert!(MapBTree::<u32, u32>::open(&dir_path, 4, 4).is_err());
+        assert!(BTree::<u32, u32>::open(&dir_path, 4, 4).unwrap().ingest(&file_path).is_err());
+
+        fs::remove_dir_all(&dir_path).unwrap();
+        fs::remove_file(&file_path).unwrap();
+    }
+
+    #[test]
+    fn new_values_hide_older_ones() {
+        let dir_path = gen_temp_name();
+
+        {
+            let mut map = MapBTree::<u32, u32>::open(&dir_path, 4, 4).unwrap();
+
+            for i in 0..600 {
+                map.insert(i, i).unwrap();
+            }
+
+            map.compact().unwrap();
+
+            // replacing values on disk fills memory no faster than new keys
+            for i in 0..600 {
+                map.insert(i, i + 1).unwrap();
+            }
+
+            let stats = map.tree().stats().unwrap();
+
+            assert_eq!((stats.memory_records, stats.memory_deletes), (600, 0));
+            assert_eq!(map.get(&5).unwrap(), Some(6));
+            assert_eq!(map.tree().value_count(&5).unwrap(), 1);
+            assert_eq!(map.iter().unwrap().take(2).collect::<Result<Vec<_>, _>>().unwrap(), [(0, 1), (1, 2)]);
+
+            // deleting the new value doesn't bring back the one on disk
+            map.tree_mut().delete(5, 6).unwrap();
+
+            assert_eq!(map.get(&5).unwrap(), None);
+            assert!(!map.contains_key(&5).unwrap());
+        }
+
+        let mut map = MapBTree::<u32, u32>::open(&dir_path, 4, 4).unwrap();
+
+        assert_eq!(map.get(&5).unwrap(), None);
+        assert_eq!(map.get(&6).unwrap(), Some(7));
+
+        map.compact().unwrap();
+
+        assert_eq!(map.tree().tree_file.count().unwrap(), 599);
+        assert_eq!(map.iter().unwrap().take(2).collect::<Result<Vec<_>, _>>().unwrap(), [(0, 1), (1, 2)]);
+
+        fs::remove_dir_all(&dir_path).unwrap();
+    }
+}
@@ -0,0 +1,228 @@
+use ::{KeyType, ValueType};
+
+use comparator::{Comparator, ComparedKey, range_contains};
+
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::{Add, Bound};
+
+/// Folds the operands given to BTree::merge into the value of their key. Only the
+/// operands are logged to the WAL; they are applied to the key's value, in the order
+/// they were merged, when the key is read and when the tree is compacted. Any function
+/// taking the key, the value & the operand is a MergeOperator.
+pub trait MergeOperator<K, V>: Send + Sync {
+    /// Returns the key's value once the operand is applied, value being None if the
+    /// key had no value
+    fn merge(&self, key: &K, value: Option<V>, operand: &V) -> V;
+}
+
+impl <K, V, F> MergeOperator<K,V> for F where F: Fn(&K, Option<V>, &V) -> V + Send + Sync {
+    fn merge(&self, key: &K, value: Option<V>, operand: &V) -> V {
+        return self(key, value, operand);
+    }
+}
+
+/// Adds the operands to the value, for counters
+pub struct Sum;
+
+impl <K, V: Clone + Add<Output=V>> MergeOperator<K,V> for Sum {
+    fn merge(&self, _key: &K, value: Option<V>, operand: &V) -> V {
+        return match value {
+            Some(value) => value + operand.clone(),
+            None => operand.clone()
+        };
+    }
+}
+
+/// Keeps the largest of the value & the operands
+pub struct Max;
+
+impl <K, V: Ord + Clone> MergeOperator<K,V> for Max {
+    fn merge(&self, _key: &K, value: Option<V>, operand: &V) -> V {
+        return value.map_or(operand.clone(), |value| cmp::max(value, operand.clone()));
+    }
+}
+
+/// Keeps the smallest of the value & the operands
+pub struct Min;
+
+impl <K, V: Ord + Clone> MergeOperator<K,V> for Min {
+    fn merge(&self, _key: &K, value: Option<V>, operand: &V) -> V {
+        return value.map_or(operand.clone(), |value| cmp::min(value, operand.clone()));
+    }
+}
+
+/// Adds the members of each operand to the value's set
+pub struct Union;
+
+impl <K, T: Ord + Clone> MergeOperator<K,BTreeSet<T>> for Union {
+    fn merge(&self, _key: &K, value: Option<BTreeSet<T>>, operand: &BTreeSet<T>) -> BTreeSet<T> {
+        let mut value = value.unwrap_or_default();
+
+        value.extend(operand.iter().cloned());
+
+        return value;
+    }
+}
+
+/// The operands merged into keys since the last compaction, in the order they were
+/// merged. A change that sets the key's value drops its operands, as they applied to
+/// the value it had before.
+pub struct Operands<K: KeyType, V: ValueType, C: Comparator<K>> {
+    operands: BTreeMap<ComparedKey<K,C>, Vec<V>>,
+}
+
+impl <K: KeyType, V: ValueType, C: Comparator<K>> Operands<K,V,C> {
+    pub fn new() -> Operands<K,V,C> {
+        return Operands{operands: BTreeMap::new()};
+    }
+
+    pub fn push(&mut self, key: K, operand: V) {
+        self.operands.entry(ComparedKey::new(key)).or_insert_with(Vec::new).push(operand);
+    }
+
+    /// Returns the key's operands, oldest first
+    pub fn get(&self, key: &K) -> Option<&Vec<V>> {
+        return self.operands.get(&ComparedKey::new(key.clone()));
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        return self.operands.contains_key(&ComparedKey::new(key.clone()));
+    }
+
+    /// Returns the keys with operands, in order
+    pub fn keys(&self) -> Vec<K> {
+        return self.operands.keys().map(|k| k.key.clone()).collect();
+    }
+
+    pub fn remove_key(&mut self, key: &K) {
+        self.operands.remove(&ComparedKey::new(key.clone()));
+    }
+
+    pub fn remove_range(&mut self, range: &(Bound<K>, Bound<K>)) {
+        self.operands.retain(|k, _| !range_contains::<K,C>(range, &k.key));
+    }
+
+    /// Returns the number of operands
+    pub fn size(&self) -> usize {
+        return self.operands.values().map(|operands| operands.len()).sum();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.operands.is_empty();
+    }
+
+    pub fn clear(&mut self) {
+        self.operands.clear();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use tests::gen_temp_name;
+    use map::MapBTree;
+    use merge::{MergeOperator, Sum, Max, Union};
+    use ::{BTree, ExportFormat, WalRecord};
+    use std::collections::BTreeSet;
+    use std::fs;
+
+    #[test]
+    fn builtin_operators() {
+        assert_eq!(Sum.merge(&"a", Some(2u64), &3), 5);
+        assert_eq!(Sum.merge(&"a", None, &3u64), 3);
+        assert_eq!(Max.merge(&"a", Some(2u32), &1), 2);
+
+        let set: BTreeSet<u32> = vec![1, 2].into_iter().collect();
+
+        assert_eq!(Union.merge(&"a", Some(set), &vec![2, 3].into_iter().collect()).into_iter().collect::<Vec<_>>(), [1, 2, 3]);
+    }
+
+    #[test]
+    fn folds_operands() {
+        let dir_path = gen_temp_name();
+
+        {
+            let mut map = MapBTree::<u32, u64>::open(&dir_path, 4, 8).unwrap();
+
+            // an operand isn't logged without an operator to fold it
+            map.insert(1, 10).unwrap();
+            map.compact().unwrap();
+
+            assert!(map.merge(1, 5).is_err());
+
+            map.set_merge_operator(Sum);
+            map.merge(1, 5).unwrap();
+
+            for i in 0..3 {
+                map.merge(1, 1).unwrap();
+                map.merge(2, i).unwrap();
+            }
+
+            assert_eq!(map.get(&1).unwrap(), Some(18));
+            assert_eq!(map.get(&2).unwrap(), Some(3));
+            assert_eq!(map.tree().value_count(&2).unwrap(), 1);
+            assert_eq!(map.iter().unwrap().collect::<Result<Vec<_>, _>>().unwrap(), [(1, 18), (2, 3)]);
+
+            match map.tree().wal_records().unwrap().last().unwrap().1 {
+                WalRecord::Merge(2, 2) => (),
+                ref record => panic!("Expected a merge, found {:?}", record)
+            }
+
+            // a change setting the value drops the operands before it
+            map.insert(2, 7).unwrap();
+            map.merge(2, 1).unwrap();
+            map.merge(3, 4).unwrap();
+            map.tree_mut().delete_range(3..).unwrap();
+
+            assert_eq!(map.get(&2).unwrap(), Some(8));
+            assert_eq!(map.get(&3).unwrap(), None);
+
+            // deleting a value other than the merged one does nothing
+            map.tree_mut().delete(1, 5).unwrap();
+            assert_eq!(map.get(&1).unwrap(), Some(18));
+            map.tree_mut().delete(1, 18).unwrap();
+            assert_eq!(map.get(&1).unwrap(), None);
+
+            map.merge(1, 2).unwrap();
+        }
+
+        // the WAL is replayed on open, then the operands are folded by a compaction
+        {
+            let mut map = MapBTree::<u32, u64>::open(&dir_path, 4, 8).unwrap();
+
+            // the replayed operands wait for an operator
+            assert_eq!(map.tree().merge_operand_count(), 2);
+            assert!(map.get(&1).is_err());
+            assert!(map.iter().is_err());
+            assert!(map.tree().export(Vec::new(), ExportFormat::JsonLines).is_err());
+
+            map.set_merge_operator(Sum);
+
+            assert_eq!(map.iter().unwrap().collect::<Result<Vec<_>, _>>().unwrap(), [(1, 2), (2, 8)]);
+
+            // a bulk load that fails leaves the operands for the next compaction, and no new tree file
+            assert!(map.tree_mut().bulk_load(vec![(5, 1), (4, 1)]).is_err());
+            assert_eq!(map.tree().merge_operand_count(), 2);
+            assert_eq!(fs::read_dir(&dir_path).unwrap().filter(|entry| entry.as_ref().unwrap().path().extension().map_or(false, |ext| ext == "btr")).count(), 1);
+            assert_eq!(map.iter().unwrap().collect::<Result<Vec<_>, _>>().unwrap(), [(1, 2), (2, 8)]);
+
+            map.compact().unwrap();
+
+            assert!(map.tree().wal_records().unwrap().is_empty());
+            assert_eq!(map.tree().merge_operand_count(), 0);
+            assert_eq!(map.iter().unwrap().collect::<Result<Vec<_>, _>>().unwrap(), [(1, 2), (2, 8)]);
+        }
+
+        fs::remove_dir_all(&dir_path).unwrap();
+
+        // a key in a multi-value tree has no single value to merge into
+        let mut btree = BTree::<u32, u64>::open(&dir_path, 4, 8).unwrap();
+
+        btree.set_merge_operator(|_key: &u32, value: Option<u64>, operand: &u64| value.unwrap_or(0) * operand);
+
+        assert!(btree.merge(1, 2).is_err());
+
+        fs::remove_dir_all(&dir_path).unwrap();
+    }
+}
@@ -1,5 +1,6 @@
 use ::{KeyType, ValueType};
 
+use comparator::{Comparator, ComparedKey, range_contains};
 use wal_file::KeyValuePair;
 
 use std::collections::{BTreeMap, BTreeSet};
@@ -7,26 +8,30 @@ use std::collections::btree_map::Entry::Occupied;
 use std::collections::btree_map;
 use std::collections::btree_set;
 use std::collections::btree_set::Iter;
+use std::ops::Bound;
 
-pub struct MultiMap<K: KeyType, V: ValueType> {
-    multi_map: BTreeMap<K, BTreeSet<V>>,
+/// A map from each key to a set of values, with the keys ordered by the comparator
+pub struct MultiMap<K: KeyType, V: ValueType, C: Comparator<K>> {
+    multi_map: BTreeMap<ComparedKey<K,C>, BTreeSet<V>>,
     count: usize  // total number of KV pairs
 }
 
-pub struct MultiMapIterator<'a, K: KeyType + 'a, V: ValueType + 'a> {
+pub struct MultiMapIterator<'a, K: KeyType + 'a, V: ValueType + 'a, C: Comparator<K> + 'a> {
     cur_key: Option<&'a K>,
-    key_it: btree_map::Iter<'a,K,BTreeSet<V>>,
+    key_it: btree_map::Iter<'a,ComparedKey<K,C>,BTreeSet<V>>,
     value_it: Option<btree_set::Iter<'a,V>>,
 }
 
-impl <'a, K: KeyType, V: ValueType> MultiMap<K,V> {
-    pub fn new() -> MultiMap<K,V> {
-        return MultiMap{multi_map: BTreeMap::<K,BTreeSet<V>>::new(), count: 0};
+impl <'a, K: KeyType, V: ValueType, C: Comparator<K>> MultiMap<K,V,C> {
+    pub fn new() -> MultiMap<K,V,C> {
+        return MultiMap{multi_map: BTreeMap::<ComparedKey<K,C>,BTreeSet<V>>::new(), count: 0};
     }
 
     pub fn insert(&mut self, key: K, value: V) -> usize {
         self.count += 1;
 
+        let key = ComparedKey::new(key);
+
         if let Some(set) = self.multi_map.get_mut(&key) {
             set.insert(value);
             return self.count;
@@ -47,7 +52,12 @@ impl <'a, K: KeyType, V: ValueType> MultiMap<K,V> {
      * sure how: https://goo.gl/9sisAb
      */
     pub fn get(&self, key: &K) -> Option<Iter<V>> {
-        return self.multi_map.get(key).map(|set| set.iter());
+        return self.multi_map.get(&ComparedKey::new(key.clone())).map(|set| set.iter());
+    }
+
+    /// Returns the set of the key's values, if it has any
+    pub fn values(&self, key: &K) -> Option<&BTreeSet<V>> {
+        return self.multi_map.get(&ComparedKey::new(key.clone()));
     }
 
     pub fn contains_key(&self, key: &K) -> bool {
@@ -57,13 +67,18 @@ impl <'a, K: KeyType, V: ValueType> MultiMap<K,V> {
         }
     }
 
+    /// Returns true if the value is one of the key's values
+    pub fn contains(&self, key: &K, value: &V) -> bool {
+        return self.multi_map.get(&ComparedKey::new(key.clone())).map_or(false, |set| set.contains(value));
+    }
+
     /*
      * Might want to re-think this and return an Error
      * as there isn't a great way to tell the user that a
      * key or value wasn't found
      */
     pub fn delete(&mut self, key: K, value: V) -> usize {
-        if let Occupied(mut entry) = self.multi_map.entry(key) {
+        if let Occupied(mut entry) = self.multi_map.entry(ComparedKey::new(key)) {
 
             if entry.get_mut().remove(&value) {
                 self.count -= 1;            
@@ -77,14 +92,51 @@ impl <'a, K: KeyType, V: ValueType> MultiMap<K,V> {
         return self.count;
     }
 
+    /// Removes every value of the key
+    pub fn remove_key(&mut self, key: &K) -> usize {
+        if let Some(set) = self.multi_map.remove(&ComparedKey::new(key.clone())) {
+            self.count -= set.len();
+        }
+
+        return self.count;
+    }
+
+    /// Removes every value of every key within the range
+    pub fn remove_range(&mut self, range: &(Bound<K>, Bound<K>)) -> usize {
+        let start = match range.0 {
+            Bound::Included(ref key) => Bound::Included(ComparedKey::new(key.clone())),
+            Bound::Excluded(ref key) => Bound::Excluded(ComparedKey::new(key.clone())),
+            Bound::Unbounded => Bound::Unbounded
+        };
+
+        // an end before the start would make range panic, so the end is checked as the keys are read
+        let keys: Vec<ComparedKey<K,C>> = self.multi_map.range((start, Bound::Unbounded))
+                                                        .take_while(|&(key, _)| range_contains::<K,C>(range, &key.key))
+                                                        .map(|(key, _)| ComparedKey::new(key.key.clone()))
+                                                        .collect();
+
+        for key in keys {
+            if let Some(set) = self.multi_map.remove(&key) {
+                self.count -= set.len();
+            }
+        }
+
+        return self.count;
+    }
+
     pub fn size(&self) -> usize {
         return self.count;
     }
+
+    pub fn clear(&mut self) {
+        self.multi_map.clear();
+        self.count = 0;
+    }
 }
 
-impl <'a, K: KeyType, V: ValueType> IntoIterator for &'a mut MultiMap<K,V> {
+impl <'a, K: KeyType, V: ValueType, C: Comparator<K>> IntoIterator for &'a MultiMap<K,V,C> {
     type Item = KeyValuePair<K,V>;
-    type IntoIter = MultiMapIterator<'a,K,V>;
+    type IntoIter = MultiMapIterator<'a,K,V,C>;
 
     fn into_iter(self) -> Self::IntoIter {
         let mut key_it = self.multi_map.iter();
@@ -98,11 +150,11 @@ impl <'a, K: KeyType, V: ValueType> IntoIterator for &'a mut MultiMap<K,V> {
         // safe to call unwrap as we tested above
         let (cur_key, cur_set) = cur_entry.unwrap();
 
-        return MultiMapIterator{cur_key: Some(cur_key), key_it: key_it, value_it: Some(cur_set.iter())};
+        return MultiMapIterator{cur_key: Some(&cur_key.key), key_it: key_it, value_it: Some(cur_set.iter())};
     }
 }
 
-impl <'a, K: KeyType, V: ValueType> Iterator for MultiMapIterator<'a,K,V> {
+impl <'a, K: KeyType, V: ValueType, C: Comparator<K>> Iterator for MultiMapIterator<'a,K,V,C> {
     type Item = KeyValuePair<K,V>;
 
     fn next(&mut self) -> Option<Self::Item> {
@@ -126,7 +178,7 @@ impl <'a, K: KeyType, V: ValueType> Iterator for MultiMapIterator<'a,K,V> {
             // safe to call unwrap because we checked it above
             let (cur_key, cur_set) = cur_entry.unwrap();
 
-            self.cur_key = Some(cur_key); // set our key
+            self.cur_key = Some(&cur_key.key); // set our key
             self.value_it = Some(cur_set.iter()); // set our value iterator
             cur_val = self.value_it.as_mut().unwrap().next(); // set our current value
         }
@@ -139,10 +191,12 @@ impl <'a, K: KeyType, V: ValueType> Iterator for MultiMapIterator<'a,K,V> {
 #[cfg(test)]
 mod tests {
     use multi_map::MultiMap;
+    use comparator::{Ascending, CaseInsensitive};
+    use std::ops::Bound;
 
     #[test]
     fn test_insert() {
-        let mut mmap = MultiMap::<i32,String>::new();
+        let mut mmap = MultiMap::<i32,String,Ascending>::new();
 
         assert!(mmap.insert(12, String::from("abc")) == 1);
         assert!(mmap.insert(23, String::from("abc")) == 2);
@@ -165,7 +219,7 @@ mod tests {
 
     #[test]
     fn test_get() {
-        let mut mmap = MultiMap::<i32,String>::new();
+        let mut mmap = MultiMap::<i32,String,Ascending>::new();
         
         assert!(mmap.insert(12, String::from("abc")) == 1);
         assert!(mmap.insert(23, String::from("abc")) == 2);
@@ -185,7 +239,7 @@ mod tests {
 
     #[test]
     fn test_delete() {
-        let mut mmap = MultiMap::<i32,String>::new();
+        let mut mmap = MultiMap::<i32,String,Ascending>::new();
 
         assert!(mmap.insert(12, String::from("abc")) == 1);
         assert!(mmap.insert(23, String::from("abc")) == 2);
@@ -203,7 +257,36 @@ mod tests {
         let mut it = mmap.into_iter();
 
         assert!(it.next() == None);
+
+        mmap.insert(12, String::from("abc"));
+        mmap.insert(23, String::from("abc"));
+        mmap.insert(23, String::from("def"));
+
+        assert!(mmap.remove_key(&23) == 1);
+        assert!(mmap.remove_key(&23) == 1);
+        assert!(!mmap.contains_key(&23));
+
+        mmap.insert(23, String::from("abc"));
+        mmap.insert(30, String::from("abc"));
+
+        assert!(mmap.remove_range(&(Bound::Excluded(12), Bound::Included(30))) == 1);
+        assert!(mmap.contains_key(&12));
     }
-}
 
+    #[test]
+    fn test_comparator() {
+        let mut mmap = MultiMap::<String,u32,CaseInsensitive>::new();
+
+        mmap.insert(String::from("b"), 1);
+        mmap.insert(String::from("A"), 2);
+        mmap.insert(String::from("a"), 3);
+
+        // keys equal under the comparator are the same key, keeping the first spelling
+        assert_eq!(mmap.get(&String::from("a")).unwrap().cloned().collect::<Vec<_>>(), [2, 3]);
+        assert!(mmap.contains(&String::from("B"), &1));
 
+        let keys: Vec<String> = mmap.into_iter().map(|kv| kv.key).collect();
+
+        assert_eq!(keys, ["A", "A", "b"]);
+    }
+}
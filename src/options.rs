@@ -0,0 +1,38 @@
+use block_cache::BlockCache;
+use compression::Compression;
+
+use std::sync::Arc;
+
+const DEFAULT_BLOCK_CACHE_SIZE: usize = 8 * 1024 * 1024;
+const DEFAULT_BLOCK_SIZE: usize = 4096;
+
+/// Tuning options used when opening a tree for writing
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// The number of bloom filter bits per key in each tree file, 0 disables the filter
+    pub bloom_bits_per_key: usize,
+    /// The cache for blocks read from tree files, None disables caching. The same
+    /// cache can be given to several trees to share a single memory budget.
+    pub block_cache: Option<Arc<BlockCache>>,
+    /// Read tree files through a memory map instead of the block cache
+    pub use_mmap: bool,
+    /// The size leaves are filled to, before compression
+    pub block_size: usize,
+    /// The compression for the nodes at each level of a tree file, starting with the
+    /// leaves. Levels past the end of the list use the last entry.
+    pub compression_per_level: Vec<Compression>,
+    /// Keep the WAL of each compaction in the tree's archive directory instead of
+    /// discarding it, so incremental backups can be taken with BTree::backup_since
+    pub archive_wal: bool,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options{bloom_bits_per_key: 10,
+                block_cache: Some(Arc::new(BlockCache::new(DEFAULT_BLOCK_CACHE_SIZE))),
+                use_mmap: false,
+                block_size: DEFAULT_BLOCK_SIZE,
+                compression_per_level: vec![Compression::Lz4, Compression::None],
+                archive_wal: false}
+    }
+}
@@ -0,0 +1,287 @@
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+
+use codec::Codec;
+
+use std::error::Error;
+use std::io::ErrorKind;
+use std::io::Error as IOError;
+
+// strings & byte vectors end with TERMINATOR, and any 0x00 inside them is written as ESCAPED_ZERO
+const TERMINATOR: [u8; 2] = [0x00, 0x01];
+const ESCAPED_ZERO: [u8; 2] = [0x00, 0xff];
+
+/// A type with an encoding whose bytes sort in the same order as its values, so
+/// encoded keys can be compared without decoding them. The encodings are:
+/// * unsigned integers - big endian
+/// * signed integers - big endian with the sign bit flipped, so negatives sort first
+/// * bool - a single byte, 0 or 1
+/// * String & Vec<u8> - the bytes with each 0x00 escaped as 0x00 0xff, then 0x00 0x01
+/// * Option - 0 for None, or 1 followed by the value
+/// * tuples - each element in turn; every encoding above knows where it ends
+pub trait OrderedKey: Sized {
+    /// Appends the encoding of the item to the buffer
+    fn encode_ordered(&self, buff: &mut Vec<u8>);
+
+    /// Decodes an item starting at the offset, advancing the offset past it
+    fn decode_ordered(buff: &[u8], offset: &mut usize) -> Result<Self, Box<Error>>;
+}
+
+/// Stores keys, or values, with their OrderedKey encoding. The tree compares keys
+/// stored with this codec as bytes rather than decoding them.
+pub struct Ordered;
+
+fn invalid_key() -> Box<Error> {
+    return From::from(IOError::new(ErrorKind::InvalidData, "Ordered key is corrupt"));
+}
+
+/// Returns the next n bytes at the offset, advancing the offset past them
+fn take<'a>(buff: &'a [u8], offset: &mut usize, n: usize) -> Result<&'a [u8], Box<Error>> {
+    if *offset + n > buff.len() {
+        return Err(invalid_key());
+    }
+
+    let bytes = &buff[*offset..*offset + n];
+
+    *offset += n;
+
+    return Ok(bytes);
+}
+
+macro_rules! ordered_unsigned {
+    ($t:ty, $size:expr, $write:ident, $read:ident) => {
+        impl OrderedKey for $t {
+            fn encode_ordered(&self, buff: &mut Vec<u8>) {
+                buff.$write::<BigEndian>(*self as _).unwrap();
+            }
+
+            fn decode_ordered(buff: &[u8], offset: &mut usize) -> Result<$t, Box<Error>> {
+                return Ok(BigEndian::$read(try!(take(buff, offset, $size))) as $t);
+            }
+        }
+    }
+}
+
+macro_rules! ordered_signed {
+    ($t:ty, $ut:ty, $size:expr, $write:ident, $read:ident) => {
+        impl OrderedKey for $t {
+            fn encode_ordered(&self, buff: &mut Vec<u8>) {
+                let flipped = (*self as $ut) ^ (1 << ($size * 8 - 1));
+
+                buff.$write::<BigEndian>(flipped as _).unwrap();
+            }
+
+            fn decode_ordered(buff: &[u8], offset: &mut usize) -> Result<$t, Box<Error>> {
+                let flipped = BigEndian::$read(try!(take(buff, offset, $size))) as $ut;
+
+                return Ok((flipped ^ (1 << ($size * 8 - 1))) as $t);
+            }
+        }
+    }
+}
+
+ordered_unsigned!(u16, 2, write_u16, read_u16);
+ordered_unsigned!(u32, 4, write_u32, read_u32);
+ordered_unsigned!(u64, 8, write_u64, read_u64);
+ordered_unsigned!(usize, 8, write_u64, read_u64);
+ordered_signed!(i16, u16, 2, write_u16, read_u16);
+ordered_signed!(i32, u32, 4, write_u32, read_u32);
+ordered_signed!(i64, u64, 8, write_u64, read_u64);
+ordered_signed!(isize, u64, 8, write_u64, read_u64);
+
+impl OrderedKey for u8 {
+    fn encode_ordered(&self, buff: &mut Vec<u8>) {
+        buff.push(*self);
+    }
+
+    fn decode_ordered(buff: &[u8], offset: &mut usize) -> Result<u8, Box<Error>> {
+        return Ok(try!(take(buff, offset, 1))[0]);
+    }
+}
+
+impl OrderedKey for i8 {
+    fn encode_ordered(&self, buff: &mut Vec<u8>) {
+        buff.push((*self as u8) ^ 0x80);
+    }
+
+    fn decode_ordered(buff: &[u8], offset: &mut usize) -> Result<i8, Box<Error>> {
+        return Ok((try!(take(buff, offset, 1))[0] ^ 0x80) as i8);
+    }
+}
+
+impl OrderedKey for bool {
+    fn encode_ordered(&self, buff: &mut Vec<u8>) {
+        buff.push(*self as u8);
+    }
+
+    fn decode_ordered(buff: &[u8], offset: &mut usize) -> Result<bool, Box<Error>> {
+        match try!(take(buff, offset, 1))[0] {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(invalid_key())
+        }
+    }
+}
+
+impl OrderedKey for Vec<u8> {
+    fn encode_ordered(&self, buff: &mut Vec<u8>) {
+        for &b in self {
+            if b == 0 {
+                buff.extend_from_slice(&ESCAPED_ZERO);
+            } else {
+                buff.push(b);
+            }
+        }
+
+        buff.extend_from_slice(&TERMINATOR);
+    }
+
+    fn decode_ordered(buff: &[u8], offset: &mut usize) -> Result<Vec<u8>, Box<Error>> {
+        let mut bytes = Vec::new();
+
+        loop {
+            let b = try!(take(buff, offset, 1))[0];
+
+            if b != 0 {
+                bytes.push(b);
+                continue;
+            }
+
+            match try!(take(buff, offset, 1))[0] {
+                0x01 => return Ok(bytes),
+                0xff => bytes.push(0),
+                _ => return Err(invalid_key())
+            }
+        }
+    }
+}
+
+impl OrderedKey for String {
+    fn encode_ordered(&self, buff: &mut Vec<u8>) {
+        // UTF-8 sorts the same way as the chars it encodes
+        for &b in self.as_bytes() {
+            if b == 0 {
+                buff.extend_from_slice(&ESCAPED_ZERO);
+            } else {
+                buff.push(b);
+            }
+        }
+
+        buff.extend_from_slice(&TERMINATOR);
+    }
+
+    fn decode_ordered(buff: &[u8], offset: &mut usize) -> Result<String, Box<Error>> {
+        match String::from_utf8(try!(Vec::<u8>::decode_ordered(buff, offset))) {
+            Ok(s) => Ok(s),
+            Err(_) => Err(invalid_key())
+        }
+    }
+}
+
+impl <T: OrderedKey> OrderedKey for Option<T> {
+    fn encode_ordered(&self, buff: &mut Vec<u8>) {
+        match *self {
+            None => buff.push(0),
+            Some(ref t) => {
+                buff.push(1);
+                t.encode_ordered(buff);
+            }
+        }
+    }
+
+    fn decode_ordered(buff: &[u8], offset: &mut usize) -> Result<Option<T>, Box<Error>> {
+        match try!(take(buff, offset, 1))[0] {
+            0 => Ok(None),
+            1 => Ok(Some(try!(T::decode_ordered(buff, offset)))),
+            _ => Err(invalid_key())
+        }
+    }
+}
+
+macro_rules! ordered_tuple {
+    ($($name:ident $index:tt),+) => {
+        impl <$($name: OrderedKey),+> OrderedKey for ($($name,)+) {
+            fn encode_ordered(&self, buff: &mut Vec<u8>) {
+                $(self.$index.encode_ordered(buff);)+
+            }
+
+            fn decode_ordered(buff: &[u8], offset: &mut usize) -> Result<($($name,)+), Box<Error>> {
+                return Ok(($(try!($name::decode_ordered(buff, offset)),)+));
+            }
+        }
+    }
+}
+
+ordered_tuple!(A 0, B 1);
+ordered_tuple!(A 0, B 1, C 2);
+ordered_tuple!(A 0, B 1, C 2, D 3);
+
+impl <T: OrderedKey> Codec<T> for Ordered {
+    fn name() -> &'static str {
+        return "ordered";
+    }
+
+    fn preserves_order() -> bool {
+        return true;
+    }
+
+    fn encode(item: &T, buff: &mut Vec<u8>) -> Result<(), Box<Error>> {
+        item.encode_ordered(buff);
+
+        Ok( () )
+    }
+
+    fn decode(buff: &[u8]) -> Result<T, Box<Error>> {
+        let mut offset = 0;
+        let item = try!(T::decode_ordered(buff, &mut offset));
+
+        if offset != buff.len() {
+            return Err(invalid_key());
+        }
+
+        return Ok(item);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use codec::{Codec, encode_to_vec};
+    use ordered::{Ordered, OrderedKey};
+    use std::fmt::Debug;
+
+    /// Checks every item round trips, and that sorting by encoding gives the same order
+    fn check_order<T: OrderedKey + Ord + Clone + Debug>(mut items: Vec<T>) {
+        let mut encoded: Vec<(Vec<u8>, T)> = items.iter().map(|t| (encode_to_vec::<T, Ordered>(t).unwrap(), t.clone())).collect();
+
+        for &(ref buff, ref t) in &encoded {
+            assert_eq!(&<Ordered as Codec<T>>::decode(buff).unwrap(), t);
+        }
+
+        items.sort();
+        encoded.sort();
+
+        assert_eq!(encoded.into_iter().map(|(_, t)| t).collect::<Vec<_>>(), items);
+    }
+
+    #[test]
+    fn sorts_like_values() {
+        check_order(vec![0u32, 1, 255, 256, 65536, u32::max_value()]);
+        check_order(vec![i64::min_value(), -65536, -256, -1, 0, 1, 255, i64::max_value()]);
+        check_order(vec![-128i8, -1, 0, 1, 127]);
+        check_order(vec![String::new(), String::from("\0"), String::from("\0\0"), String::from("\x01"),
+                         String::from("a"), String::from("a\0"), String::from("a\0b"), String::from("ab"), String::from("é")]);
+        check_order(vec![vec![], vec![0u8], vec![0, 0xff], vec![0, 1], vec![1], vec![0xff, 0]]);
+        check_order(vec![None, Some(-1i32), Some(0), Some(3)]);
+        check_order(vec![(String::from("a"), -1i32), (String::from("a"), 2), (String::from("a\0"), -5), (String::from("b"), 0)]);
+        check_order(vec![(1u8, String::from("z"), true), (1, String::from("z"), false), (0, String::from("zz"), true)]);
+    }
+
+    #[test]
+    fn rejects_bad_encodings() {
+        // trailing bytes, a missing terminator, a bad escape & a bad bool
+        assert!(<Ordered as Codec<u32>>::decode(&[0, 0, 0, 1, 2]).is_err());
+        assert!(<Ordered as Codec<String>>::decode(b"abc").is_err());
+        assert!(<Ordered as Codec<Vec<u8>>>::decode(&[1, 0, 2]).is_err());
+        assert!(<Ordered as Codec<bool>>::decode(&[2]).is_err());
+    }
+}
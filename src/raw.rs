@@ -0,0 +1,130 @@
+use disk_btree::SliceGuard;
+
+use ::{BTree, Options, Raw};
+
+use std::error::Error;
+use itertools::Itertools;
+
+/// A tree of byte strings, for when keys & values are just bytes. It is a BTree with
+/// the Raw codec for both, so it shares the same files and can be opened as a typed
+/// BTree<Vec<u8>, Vec<u8>, Raw, Raw> too, but reads return the values as they are
+/// stored rather than decoding them into new vectors: each is a SliceGuard borrowing
+/// the bytes from the block cache, the memory map or the in-memory BTree.
+pub struct RawBTree {
+    tree: BTree<Vec<u8>, Vec<u8>, Raw, Raw>,
+}
+
+impl RawBTree {
+    /// Opens the tree stored in the given directory, creating it if needed
+    pub fn open(dir_path: &String, key_size: usize, value_size: usize) -> Result<RawBTree, Box<Error>> {
+        return Ok(RawBTree{tree: try!(BTree::open(dir_path, key_size, value_size))});
+    }
+
+    /// Same as open, but with the given options instead of the defaults
+    pub fn open_with_options(dir_path: &String, key_size: usize, value_size: usize, options: Options) -> Result<RawBTree, Box<Error>> {
+        return Ok(RawBTree{tree: try!(BTree::open_with_options(dir_path, key_size, value_size, options))});
+    }
+
+    /// Opens the tree stored in the given directory for reading only, as BTree::open_read_only
+    pub fn open_read_only(dir_path: &String, replay_wal: bool) -> Result<RawBTree, Box<Error>> {
+        return Ok(RawBTree{tree: try!(BTree::open_read_only(dir_path, replay_wal))});
+    }
+
+    pub fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<(), Box<Error>> {
+        return self.tree.insert(key.to_vec(), value.to_vec());
+    }
+
+    pub fn delete(&mut self, key: &[u8], value: &[u8]) -> Result<(), Box<Error>> {
+        return self.tree.delete(key.to_vec(), value.to_vec());
+    }
+
+    /// Returns all of the values associated with the key, in order. The values are
+    /// borrowed from the tree, so it can't be changed while they are held.
+    pub fn get<'a>(&'a self, key: &[u8]) -> Result<Vec<SliceGuard<'a>>, Box<Error>> {
+        let key = key.to_vec();
+
+        // the value of a key with merge operands is made when it's read
+        if self.tree.operands.contains_key(&key) {
+            return Ok(try!(self.tree.get(&key)).map(SliceGuard::from).collect());
+        }
+
+        let deleted: Vec<&[u8]> = match self.tree.deleted.values(&key) {
+            Some(values) => values.iter().map(|v| &v[..]).collect(),
+            None => Vec::new()
+        };
+
+        let disk_values = if self.tree.disk_key_hidden(&key) { Vec::new() } else { try!(self.tree.tree_file.get_slices(&key)) };
+        let disk_values = disk_values.into_iter().filter(|v| !deleted.contains(&&v[..]));
+        let mem_values = self.tree.mem_tree.get(&key).into_iter().flat_map(|values| values.map(|v| SliceGuard::from(&v[..])));
+
+        // both are in order, but a value can be in both
+        let values = disk_values.merge_by(mem_values, |a, b| a[..] <= b[..]).coalesce(|a, b| if a[..] == b[..] { Ok(a) } else { Err((a, b)) });
+
+        return Ok(values.collect());
+    }
+
+    pub fn contains_key(&self, key: &[u8]) -> Result<bool, Box<Error>> {
+        return self.tree.contains_key(&key.to_vec());
+    }
+
+    /// Merges the records in memory into a new tree file, as BTree::compact
+    pub fn compact(&mut self) -> Result<(), Box<Error>> {
+        return self.tree.compact();
+    }
+
+    /// Returns the typed tree, for everything else a tree can do
+    pub fn tree(&self) -> &BTree<Vec<u8>, Vec<u8>, Raw, Raw> {
+        return &self.tree;
+    }
+
+    pub fn tree_mut(&mut self) -> &mut BTree<Vec<u8>, Vec<u8>, Raw, Raw> {
+        return &mut self.tree;
+    }
+
+    pub fn into_tree(self) -> BTree<Vec<u8>, Vec<u8>, Raw, Raw> {
+        return self.tree;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use tests::gen_temp_name;
+    use raw::RawBTree;
+    use ::{BTree, Compression, Options, Raw};
+    use std::fs;
+
+    #[test]
+    fn borrows_values() {
+        // read through the block cache, then straight from an uncompressed memory map
+        let mmap_options = Options{use_mmap: true, compression_per_level: vec![Compression::None], ..Options::default()};
+
+        for options in vec![Options::default(), mmap_options] {
+            let dir_path = gen_temp_name();
+
+            {
+                let mut btree = RawBTree::open_with_options(&dir_path, 8, 8, options.clone()).unwrap();
+
+                for i in 0..1100u32 {
+                    btree.insert(format!("key{:04}", i % 550).as_bytes(), format!("value{}", i / 550).as_bytes()).unwrap();
+                }
+
+                btree.insert(b"key0001", b"value2").unwrap();
+                btree.delete(b"key0001", b"value0").unwrap();
+
+                let values = btree.get(b"key0001").unwrap();
+
+                assert_eq!(values.iter().map(|v| &v[..]).collect::<Vec<_>>(), [&b"value1"[..], &b"value2"[..]]);
+                assert!(btree.get(b"missing").unwrap().is_empty());
+                assert!(btree.contains_key(b"key0549").unwrap());
+            }
+
+            // the same files open as a typed tree
+            let btree = BTree::<Vec<u8>, Vec<u8>, Raw, Raw>::open_with_options(&dir_path, 8, 8, options).unwrap();
+
+            assert_eq!(btree.get(&b"key0002".to_vec()).unwrap().collect::<Vec<_>>(), [b"value0".to_vec(), b"value1".to_vec()]);
+
+            fs::remove_dir_all(&dir_path).unwrap();
+        }
+    }
+}
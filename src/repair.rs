@@ -0,0 +1,402 @@
+use disk_btree::{OnDiskBTree, OnDiskBTreeBuilder};
+use layout::FileLayout;
+use manifest::{Manifest, ManifestRecord};
+use options::Options;
+use wal_file::{RecordFile, WalRecord};
+use codec::{Codec, Bincode};
+use comparator::{Comparator, Ascending};
+use multi_map::MultiMap;
+use merge::Operands;
+
+use ::{KeyType, ValueType};
+
+use std::cmp::Ordering;
+use std::error::Error;
+use std::fs;
+use std::io::ErrorKind;
+use std::io::Error as IOError;
+
+/// What repair was able to recover, and what was lost
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct RepairReport {
+    pub files_scanned: usize,         // the number of live tree files read
+    pub records_recovered: u64,       // the number of distinct records in the rebuilt tree
+    pub blocks_lost: u64,             // leaves dropped because they failed their checksum or couldn't be decoded
+    pub bytes_lost: u64,              // bytes of tree files skipped over to find good leaves
+    pub records_lost: u64,            // records missing compared to the footers of the damaged files, where readable
+    pub wal_records_recovered: u64,
+    pub wal_records_lost: u64,        // WAL records that couldn't be decoded
+    pub damaged_files: Vec<String>,   // damaged or missing tree files; damaged ones are kept with a .corrupt suffix
+    pub manifest_damaged: bool,       // the manifest had a damaged edit; it's kept with a .corrupt suffix & rebuilt
+}
+
+impl RepairReport {
+    /// Returns true if every record that was written could be recovered
+    pub fn is_lossless(&self) -> bool {
+        return self.blocks_lost == 0 && self.records_lost == 0 && self.wal_records_lost == 0 && self.damaged_files.is_empty() && !self.manifest_damaged;
+    }
+}
+
+/// Rebuilds the tree at the given path (a directory given to BTree::open, or a file
+/// given to BTree::new) from whatever can be read back. Every leaf of the live tree
+/// files that passes its checksum & decodes, and every WAL record that decodes, is
+/// written to a fresh tree file which replaces the old files; the WAL is emptied,
+/// except for the merge operands of a map that were still to be folded, as there's no
+/// merge operator to fold them with. They are logged again.
+/// Damaged tree files are kept alongside with a .corrupt suffix for inspection. A
+/// manifest with a damaged edit is kept the same way, and rebuilt: the edits after the
+/// damaged one are lost, so every tree file in the directory is read instead of the
+/// live ones.
+/// The tree must use bincode for its keys & values and order keys by their Ord; use
+/// BTree::repair for other codecs or comparators.
+pub fn repair<K, V>(path: &String) -> Result<RepairReport, Box<Error>>
+    where K: KeyType, V: ValueType, Bincode: Codec<K> + Codec<V>
+{
+    return repair_tree::<K,V,Bincode,Bincode,Ascending>(path);
+}
+
+/// Same as repair, for a tree using the given codecs & comparator
+pub fn repair_tree<K: KeyType, V: ValueType, KC: Codec<K>, VC: Codec<V>, C: Comparator<K>>(path: &String) -> Result<RepairReport, Box<Error>> {
+    let layout = match fs::metadata(path) {
+        Ok(ref metadata) if metadata.is_dir() => FileLayout::directory(path),
+        _ => try!(FileLayout::single_file(path))
+    };
+
+    let _lock_file = try!(layout.lock());
+
+    let (salvaged, damaged_edit) = try!(Manifest::salvage(&layout.manifest_path()));
+
+    // the first edit holds the options the tree was created with
+    if damaged_edit == Some(0) {
+        return Err(From::from(IOError::new(ErrorKind::InvalidData, "The manifest's first edit is damaged, so the tree's options are lost")));
+    }
+
+    try!(salvaged.check_codecs((KC::name(), VC::name())));
+    try!(salvaged.check_comparator(C::name()));
+
+    let (key_size, value_size, map) = (salvaged.key_size(), salvaged.value_size(), salvaged.is_map());
+    let mut report = RepairReport::default();
+
+    report.manifest_damaged = damaged_edit.is_some();
+
+    let old_files = if report.manifest_damaged {
+        try!(tree_files(&layout))
+    } else {
+        salvaged.live_files().clone()
+    };
+
+    let mut manifest = if report.manifest_damaged {
+        salvaged
+    } else {
+        try!(Manifest::new(&layout.manifest_path(), key_size, value_size, (KC::name(), VC::name()), C::name(), map, &layout.tree_file_name(0)))
+    };
+
+    // the records ordered by the comparator
+    let mut records = MultiMap::<K,V,C>::new();
+    let mut intact_files = Vec::new();
+
+    for file_name in &old_files {
+        report.files_scanned += 1;
+
+        let salvage = match OnDiskBTree::<K,V,KC,VC,C>::salvage(&layout.path(file_name)) {
+            Ok(salvage) => salvage,
+            Err(_) => {
+                report.damaged_files.push(file_name.to_owned());
+                continue;
+            }
+        };
+
+        let found = salvage.records.len() as u64;
+
+        report.blocks_lost += salvage.blocks_lost;
+        report.bytes_lost += salvage.bytes_lost;
+        report.records_lost += salvage.expected_records.unwrap_or(0).saturating_sub(found);
+
+        if salvage.blocks_lost > 0 || salvage.expected_records != Some(found) {
+            report.damaged_files.push(file_name.to_owned());
+        } else {
+            intact_files.push(file_name.to_owned());
+        }
+
+        for kv in salvage.records {
+            records.insert(kv.key, kv.value);
+        }
+    }
+
+    let mut wal_file = try!(RecordFile::<K,V,KC,VC>::new(&layout.wal_path(), key_size, value_size));
+    let (wal_records, wal_records_lost) = try!(wal_file.salvage());
+
+    report.wal_records_recovered = wal_records.len() as u64;
+    report.wal_records_lost = wal_records_lost;
+
+    // the WAL is newer than the tree files, so its changes are applied on top of them.
+    // Salvaged records can have gaps, so a replace keeps whichever of its Inserts survived.
+    let mut operands = Operands::<K,V,C>::new();
+
+    for record in wal_records {
+        match record {
+            WalRecord::Insert(key, value) => {
+                // in a map the value replaces the key's value
+                if map {
+                    records.remove_key(&key);
+                    operands.remove_key(&key);
+                }
+
+                records.insert(key, value);
+            },
+            WalRecord::Delete(key, value) => { records.delete(key, value); },
+            WalRecord::DeleteKey(key) | WalRecord::Replace(key, _) => {
+                records.remove_key(&key);
+                operands.remove_key(&key);
+            },
+            WalRecord::DeleteRange(start, end) => {
+                let range = (start, end);
+
+                records.remove_range(&range);
+                operands.remove_range(&range);
+            },
+            WalRecord::Merge(key, operand) => operands.push(key, operand)
+        }
+    }
+
+    // write everything that was recovered to a fresh file. The edits lost from a damaged
+    // manifest may have added files past its next file number.
+    let mut new_file_name = layout.tree_file_name(manifest.new_file_number());
+
+    while fs::metadata(layout.path(&new_file_name)).is_ok() {
+        new_file_name = layout.tree_file_name(manifest.new_file_number());
+    }
+
+    let mut builder = try!(OnDiskBTreeBuilder::<K,V,KC,VC,C>::new(layout.path(&new_file_name), &Options::default(), map));
+    let mut records = records.into_iter().peekable();
+
+    while let Some(kv) = records.next() {
+        // the age of recovered values isn't known, so a map keeps the largest of a key's values
+        if map && records.peek().map_or(false, |next| C::compare(&next.key, &kv.key) == Ordering::Equal) {
+            continue;
+        }
+
+        try!(builder.insert_record(&kv));
+
+        report.records_recovered += 1;
+    }
+
+    try!(builder.finish());
+
+    // every WAL record, even those lost, was given a sequence number; the operands logged
+    // again take the last ones
+    let last_sequence = manifest.last_sequence() + report.wal_records_recovered + report.wal_records_lost - operands.size() as u64;
+
+    if report.manifest_damaged {
+        // the new manifest is written alongside and moved over the damaged one, so there's always one to open
+        let manifest_path = layout.manifest_path();
+        let new_manifest_path = manifest_path.to_owned() + ".new";
+
+        if fs::metadata(&new_manifest_path).is_ok() {
+            try!(fs::remove_file(&new_manifest_path));
+        }
+
+        let mut new_manifest = try!(Manifest::new(&new_manifest_path, key_size, value_size, (KC::name(), VC::name()), C::name(), map, &new_file_name));
+
+        try!(new_manifest.log_edit(vec![ManifestRecord::NextFileNumber(manifest.next_file_number()), ManifestRecord::LastSequence(last_sequence)]));
+        try!(fs::copy(&manifest_path, manifest_path.to_owned() + ".corrupt"));
+        try!(fs::rename(&new_manifest_path, &manifest_path));
+    } else {
+        let mut edit = vec![ManifestRecord::AddFile(new_file_name)];
+
+        edit.extend(old_files.iter().map(|f| ManifestRecord::RemoveFile(f.to_owned())));
+        edit.push(ManifestRecord::NextFileNumber(manifest.next_file_number()));
+        edit.push(ManifestRecord::LastSequence(last_sequence));
+
+        try!(manifest.log_edit(edit));
+    }
+
+    for file_name in &intact_files {
+        try!(fs::remove_file(layout.path(file_name)));
+    }
+
+    for file_name in &report.damaged_files {
+        let file_path = layout.path(file_name);
+
+        if fs::metadata(&file_path).is_ok() {
+            try!(fs::rename(&file_path, file_path.to_owned() + ".corrupt"));
+        }
+    }
+
+    try!(wal_file.truncate());
+
+    let mut merges = Vec::new();
+
+    for key in operands.keys() {
+        merges.extend(operands.get(&key).unwrap().iter().map(|operand| WalRecord::Merge(key.clone(), operand.clone())));
+    }
+
+    try!(wal_file.insert_records(&merges));
+
+    return Ok(report);
+}
+
+/// Returns the name of every tree file in the layout's directory, live or not
+fn tree_files(layout: &FileLayout) -> Result<Vec<String>, Box<Error>> {
+    let mut file_names = Vec::new();
+
+    for entry in try!(fs::read_dir(layout.dir())) {
+        let file_name = match try!(entry).file_name().into_string() {
+            Ok(name) => name,
+            Err(_) => continue
+        };
+
+        if layout.is_tree_file_name(&file_name) || file_name == layout.tree_file_name(0) {
+            file_names.push(file_name);
+        }
+    }
+
+    file_names.sort();
+
+    return Ok(file_names);
+}
+
+
+#[cfg(test)]
+mod tests {
+    use tests::gen_temp_name;
+    use repair::repair;
+    use ::{BTree, MapBTree, Sum};
+    use std::fs;
+    use std::fs::OpenOptions;
+    use std::io::{Seek, SeekFrom, Write};
+
+    #[test]
+    fn rebuilds_damaged_tree() {
+        let dir_path = gen_temp_name();
+
+        {
+            let mut btree = BTree::<String, String>::open(&dir_path, 20, 20).unwrap();
+
+            for i in 0..500 {
+                btree.insert(format!("key{:04}", i), String::from("value")).unwrap();
+            }
+
+            btree.compact().unwrap();
+
+            for i in 500..505 {
+                btree.insert(format!("key{:04}", i), String::from("value")).unwrap();
+            }
+        }
+
+        // flip a byte in the first leaf
+        {
+            let mut fd = OpenOptions::new().read(true).write(true).open(dir_path.to_owned() + "/000001.btr").unwrap();
+
+            fd.seek(SeekFrom::Start(73)).unwrap();
+            fd.write_all(&[0xff]).unwrap();
+        }
+
+        assert!(!BTree::<String, String>::open(&dir_path, 20, 20).unwrap().verify().unwrap().is_ok());
+
+        let report = repair::<String, String>(&dir_path).unwrap();
+
+        assert!(!report.is_lossless());
+        assert_eq!(report.files_scanned, 1);
+        assert_eq!(report.blocks_lost, 1);
+        assert!(report.records_lost > 0);
+        assert_eq!(report.records_recovered, 505 - report.records_lost);
+        assert_eq!(report.wal_records_recovered, 5);
+        assert_eq!(report.damaged_files, vec![String::from("000001.btr")]);
+        assert!(fs::metadata(dir_path.to_owned() + "/000001.btr.corrupt").is_ok());
+
+        let btree = BTree::<String, String>::open(&dir_path, 20, 20).unwrap();
+
+        assert!(btree.verify().unwrap().is_ok());
+        assert!(btree.wal_file.is_new().unwrap());
+        assert!(btree.contains_key(&String::from("key0499")).unwrap());
+        assert!(btree.contains_key(&String::from("key0504")).unwrap());
+        assert!(!btree.contains_key(&String::from("key0000")).unwrap());
+
+        // repairing an intact tree loses nothing
+        drop(btree);
+
+        assert!(repair::<String, String>(&dir_path).unwrap().is_lossless());
+
+        fs::remove_dir_all(&dir_path).unwrap();
+    }
+
+    #[test]
+    fn rebuilds_damaged_manifest() {
+        let dir_path = gen_temp_name();
+        let manifest_path = dir_path.to_owned() + "/MANIFEST";
+
+        {
+            let mut btree = BTree::<u32, u32>::open(&dir_path, 4, 4).unwrap();
+
+            for i in 0..500 {
+                btree.insert(i, i).unwrap();
+            }
+
+            btree.compact().unwrap();
+            btree.insert(500, 500).unwrap();
+        }
+
+        // flip the last byte of the compaction's edit, which added the live tree file
+        {
+            let mut fd = OpenOptions::new().read(true).write(true).open(&manifest_path).unwrap();
+
+            fd.seek(SeekFrom::End(-1)).unwrap();
+            fd.write_all(&[0xff]).unwrap();
+        }
+
+        assert!(BTree::<u32, u32>::open(&dir_path, 4, 4).is_err());
+
+        let report = repair::<u32, u32>(&dir_path).unwrap();
+
+        assert!(report.manifest_damaged);
+        assert!(!report.is_lossless());
+        assert_eq!(report.files_scanned, 1);
+        assert!(report.damaged_files.is_empty());
+        assert_eq!(report.records_recovered, 501);
+        assert!(fs::metadata(manifest_path.to_owned() + ".corrupt").is_ok());
+
+        let btree = BTree::<u32, u32>::open(&dir_path, 4, 4).unwrap();
+
+        assert!(btree.verify().unwrap().is_ok());
+        assert_eq!(btree.iter().unwrap().count(), 501);
+
+        drop(btree);
+
+        assert!(repair::<u32, u32>(&dir_path).unwrap().is_lossless());
+
+        fs::remove_dir_all(&dir_path).unwrap();
+    }
+
+    #[test]
+    fn keeps_merge_operands() {
+        let dir_path = gen_temp_name();
+
+        {
+            let mut map = MapBTree::<u32, u64>::open(&dir_path, 4, 8).unwrap();
+
+            map.set_merge_operator(Sum);
+            map.insert(1, 10).unwrap();
+            map.merge(1, 1).unwrap();
+            map.merge(2, 5).unwrap();
+            map.insert(2, 3).unwrap();
+            map.merge(1, 2).unwrap();
+        }
+
+        let report = repair::<u32, u64>(&dir_path).unwrap();
+
+        assert!(report.is_lossless());
+        assert_eq!(report.records_recovered, 2);
+
+        // the operands of key 1 are folded into the value that was repaired
+        let mut map = MapBTree::<u32, u64>::open(&dir_path, 4, 8).unwrap();
+
+        map.set_merge_operator(Sum);
+
+        assert_eq!(map.tree().wal_records().unwrap().len(), 2);
+        assert_eq!(map.tree().last_sequence(), 5);
+        assert_eq!(map.iter().unwrap().collect::<Result<Vec<_>, _>>().unwrap(), [(1, 13), (2, 3)]);
+
+        fs::remove_dir_all(&dir_path).unwrap();
+    }
+}
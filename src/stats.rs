@@ -0,0 +1,20 @@
+use bloom::BloomFilterStats;
+use block_cache::BlockCacheStats;
+use compression::CompressionStats;
+
+/// A snapshot of the size of a tree and how its filters & caches are doing
+#[derive(Clone, PartialEq, Debug)]
+pub struct TreeStats {
+    pub key_size: usize,
+    pub value_size: usize,
+    pub last_sequence: u64,                   // the sequence number of the last change
+    pub tree_files: Vec<String>,              // the live tree files, relative to the tree's directory
+    pub disk_records: u64,                    // the number of records in the tree files
+    pub disk_bytes: u64,                      // the size of the tree files
+    pub memory_records: usize,                // the number of records in the in-memory BTree
+    pub memory_deletes: usize,                // deletes waiting for a compaction to remove records from the tree files
+    pub wal_bytes: u64,
+    pub bloom_filter: BloomFilterStats,
+    pub compression: CompressionStats,
+    pub block_cache: Option<BlockCacheStats>, // None if caching is disabled
+}
@@ -0,0 +1,104 @@
+use ::{KeyType, ValueType};
+
+use comparator::{Comparator, ComparedKey, range_contains};
+use multi_map::MultiMap;
+
+use std::collections::BTreeSet;
+use std::ops::Bound;
+
+/// The deletes made since the last compaction, which hide records that may still be in
+/// the tree file. A value inserted after a delete is in the in-memory BTree, so it is
+/// never hidden.
+pub struct Tombstones<K: KeyType, V: ValueType, C: Comparator<K>> {
+    values: MultiMap<K,V,C>,           // single values deleted
+    keys: BTreeSet<ComparedKey<K,C>>,  // keys with every value deleted
+    ranges: Vec<(Bound<K>, Bound<K>)>, // ranges of keys with every value deleted
+}
+
+impl <K: KeyType, V: ValueType, C: Comparator<K>> Tombstones<K,V,C> {
+    pub fn new() -> Tombstones<K,V,C> {
+        return Tombstones{values: MultiMap::new(), keys: BTreeSet::new(), ranges: Vec::new()};
+    }
+
+    pub fn delete_value(&mut self, key: K, value: V) {
+        self.values.insert(key, value);
+    }
+
+    /// Forgets the delete of a value that has been inserted again
+    pub fn undelete_value(&mut self, key: K, value: V) {
+        self.values.delete(key, value);
+    }
+
+    /// Deletes every value of the key, replacing any deletes of single values
+    pub fn delete_key(&mut self, key: K) {
+        self.values.remove_key(&key);
+        self.keys.insert(ComparedKey::new(key));
+    }
+
+    /// Deletes every value of every key within the range. A range is kept as a single
+    /// delete however many keys it covers; the deletes of keys within it are left, as
+    /// they'd only be dropped by the next compaction anyway.
+    pub fn delete_range(&mut self, range: (Bound<K>, Bound<K>)) {
+        self.ranges.push(range);
+    }
+
+    /// Returns true if every value of the key in the tree file is deleted
+    pub fn covers_key(&self, key: &K) -> bool {
+        return self.keys.contains(&ComparedKey::new(key.clone())) || self.ranges.iter().any(|range| range_contains::<K,C>(range, key));
+    }
+
+    /// Returns true if the record in the tree file is deleted
+    pub fn covers(&self, key: &K, value: &V) -> bool {
+        return self.covers_key(key) || self.values.contains(key, value);
+    }
+
+    /// Returns true if any of the key's values are deleted
+    pub fn contains_key(&self, key: &K) -> bool {
+        return self.covers_key(key) || self.values.contains_key(key);
+    }
+
+    /// Returns the single values of the key that are deleted
+    pub fn values(&self, key: &K) -> Option<&BTreeSet<V>> {
+        return self.values.values(key);
+    }
+
+    /// Returns the number of deletes
+    pub fn size(&self) -> usize {
+        return self.values.size() + self.keys.len() + self.ranges.len();
+    }
+
+    pub fn clear(&mut self) {
+        self.values.clear();
+        self.keys.clear();
+        self.ranges.clear();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use tombstones::Tombstones;
+    use comparator::CaseInsensitive;
+    use std::ops::Bound;
+
+    #[test]
+    fn covers_records() {
+        let mut deleted = Tombstones::<String,u32,CaseInsensitive>::new();
+
+        deleted.delete_value(String::from("a"), 1);
+        deleted.delete_value(String::from("b"), 1);
+        deleted.undelete_value(String::from("a"), 1);
+        deleted.delete_key(String::from("B"));
+
+        assert!(!deleted.covers(&String::from("a"), &1));
+        assert!(deleted.covers(&String::from("b"), &2));
+        assert!(deleted.values(&String::from("b")).is_none());
+        assert_eq!(deleted.size(), 1);
+
+        deleted.delete_range((Bound::Included(String::from("C")), Bound::Excluded(String::from("e"))));
+
+        assert!(deleted.covers_key(&String::from("d")));
+        assert!(!deleted.covers_key(&String::from("E")));
+        assert_eq!(deleted.size(), 2);
+    }
+}
@@ -0,0 +1,48 @@
+use std::fmt;
+
+/// The kinds of damage BTree::verify can find in a tree file
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DamageKind {
+    Checksum,          // a block's contents don't match its checksum
+    Corrupt,           // a block couldn't be read, decompressed or decoded
+    OutOfOrder,        // a record is smaller than the record before it
+    KeyOutOfBounds,    // a node holds a key outside the range given by its parent
+    MissingFromBloom,  // a key in a leaf is ruled out by the bloom filter
+    RecordCount,       // the leaves don't hold the number of records in the footer
+    Structure,         // the leaves reached from the root aren't the leaves in the file
+}
+
+/// A single problem found in a tree file
+#[derive(Clone, PartialEq, Debug)]
+pub struct Damage {
+    pub file: String,    // the tree file, relative to the tree's directory
+    pub offset: u64,     // the offset of the damaged block, or 0 when it applies to the whole file
+    pub kind: DamageKind,
+    pub detail: String,
+}
+
+/// The result of walking every block of a tree
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct VerifyReport {
+    pub files: usize,         // the number of tree files checked
+    pub blocks: u64,          // the number of leaves & internal nodes checked
+    pub records: u64,         // the number of records read from the leaves
+    pub damage: Vec<Damage>,
+}
+
+impl VerifyReport {
+    /// Returns true if no damage was found
+    pub fn is_ok(&self) -> bool {
+        return self.damage.is_empty();
+    }
+
+    pub fn add_damage(&mut self, file: &str, offset: u64, kind: DamageKind, detail: String) {
+        self.damage.push(Damage{file: file.to_owned(), offset: offset, kind: kind, detail: detail});
+    }
+}
+
+impl fmt::Display for Damage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} @ {}: {:?}: {}", self.file, self.offset, self.kind, self.detail)
+    }
+}
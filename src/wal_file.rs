@@ -1,8 +1,6 @@
-extern crate bincode;
-extern crate rustc_serialize;
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
 
-use bincode::SizeLimit;
-use bincode::rustc_serialize::{encode, decode};
+use codec::Codec;
 
 use ::{KeyType, ValueType};
 
@@ -12,8 +10,9 @@ use std::io::{Read, Write, ErrorKind, Seek, SeekFrom};
 use std::io::Error as IOError;
 use std::marker::PhantomData;
 use std::cmp::Ordering;
+use std::ops::Bound;
 
-#[derive(RustcEncodable, RustcDecodable, PartialEq)]
+#[derive(PartialEq)]
 pub struct KeyValuePair<K: KeyType, V: ValueType> {
     pub key: K,
     pub value: V,
@@ -29,37 +28,127 @@ impl <K: KeyType, V: ValueType> PartialOrd for KeyValuePair<K,V> {
     }
 }
 
-pub struct RecordFile<K: KeyType, V: ValueType> {
+/// A change to the tree, as logged to the WAL
+#[derive(PartialEq, Clone, Debug)]
+pub enum WalRecord<K: KeyType, V: ValueType> {
+    Insert(K, V),
+    Delete(K, V),
+    /// Deletes every value of the key
+    DeleteKey(K),
+    /// Deletes every value of the key, replacing them with the values of the Inserts in
+    /// the given number of records that follow, which are applied with it or not at all
+    Replace(K, u32),
+    /// Deletes every value of every key within the range, by the tree's comparator
+    DeleteRange(Bound<K>, Bound<K>),
+    /// An operand for the tree's MergeOperator to fold into the key's value
+    Merge(K, V),
+}
+
+const FILE_HEADER: &'static str = "B+TWAL\0";
+const CURRENT_VERSION: u8 = 0x01;
+const HEADER_SIZE: u64 = 8;
+
+// the bytes taken by a WalRecord on top of the key & value: the tag & two lengths
+const RECORD_OVERHEAD: usize = 9;
+
+const INSERT_TAG: u8 = 0;
+const DELETE_TAG: u8 = 1;
+const DELETE_KEY_TAG: u8 = 2;
+const REPLACE_TAG: u8 = 3;
+const DELETE_RANGE_TAG: u8 = 4;
+const MERGE_TAG: u8 = 5;
+
+// the kinds of the bounds of a delete range, kept in the top bits of its tag
+const INCLUDED: u8 = 0;
+const EXCLUDED: u8 = 1;
+const UNBOUNDED: u8 = 2;
+
+/// A header followed by fixed size records, each a WalRecord padded with zeros. Every
+/// record takes key_size + value_size + RECORD_OVERHEAD bytes:
+/// |-------------------------------------------|
+/// | "B+TWAL\0" | version (u8), once per file  |
+/// |-------------------------------------------|
+/// | 0 insert, 1 delete, 2 delete key,         |
+/// | 3 replace, 4 delete range, 5 merge (u8)   |
+/// |-------------------------------------------|
+/// | key length (u32, big endian)              |
+/// |-------------------------------------------|
+/// | key, encoded with the key codec           |
+/// |-------------------------------------------|
+/// | value length (u32, big endian), or the    |
+/// | number of Inserts for a replace           |
+/// |-------------------------------------------|
+/// | value or merge operand, encoded with the  |
+/// | value codec; none                         |
+/// | for a delete key or replace               |
+/// |-------------------------------------------|
+/// A delete range holds the keys of its start & end bounds in place of the key &
+/// value, so both have to fit in that space. The top four bits of its tag are the
+/// kinds of the bounds, 0 included, 1 excluded or 2 unbounded, with an unbounded
+/// bound's key left empty.
+pub struct RecordFile<K: KeyType, V: ValueType, KC: Codec<K>, VC: Codec<V>> {
     fd: File,  // the file
     key_size: usize,
     value_size: usize,
     _k_marker: PhantomData<K>,
-    _v_marker: PhantomData<V>
+    _v_marker: PhantomData<V>,
+    _kc_marker: PhantomData<KC>,
+    _vc_marker: PhantomData<VC>
 }
 
-pub struct RecordFileIterator<'a, K: KeyType + 'a, V: ValueType + 'a> {
-    wal_file: &'a mut RecordFile<K,V>,  // the file
+pub struct RecordFileIterator<'a, K: KeyType + 'a, V: ValueType + 'a, KC: Codec<K> + 'a, VC: Codec<V> + 'a> {
+    wal_file: &'a mut RecordFile<K,V,KC,VC>,  // the file
 }
 
-impl <K: KeyType, V: ValueType> RecordFile<K,V> {
-    pub fn new(wal_file_path: &String, key_size: usize, value_size: usize) -> Result<RecordFile<K,V>, Box<Error>> {
+impl <K: KeyType, V: ValueType, KC: Codec<K>, VC: Codec<V>> RecordFile<K,V,KC,VC> {
+    /// Opens the file, creating it if needed. The header is written to a file without
+    /// one, which is empty or was torn while its header was written.
+    pub fn new(wal_file_path: &String, key_size: usize, value_size: usize) -> Result<RecordFile<K,V,KC,VC>, Box<Error>> {
         let wal_file = try!(OpenOptions::new().read(true).write(true).create(true).open(wal_file_path));
 
-        return Ok(RecordFile{fd: wal_file,
+        let mut record_file = RecordFile{fd: wal_file,
+                                         key_size: key_size,
+                                         value_size: value_size,
+                                         _k_marker: PhantomData,
+                                         _v_marker: PhantomData,
+                                         _kc_marker: PhantomData,
+                                         _vc_marker: PhantomData};
+
+        let mut header = Vec::new();
+
+        try!((&mut record_file.fd).take(HEADER_SIZE).read_to_end(&mut header));
+
+        if header.len() < HEADER_SIZE as usize && header[..] == header_bytes()[..header.len()] {
+            try!(record_file.fd.set_len(0));
+            try!(record_file.fd.seek(SeekFrom::Start(0)));
+            try!(record_file.fd.write_all(&header_bytes()));
+        }
+
+        return Ok(record_file);
+    }
+
+    /// Opens an existing file without ever writing to it
+    pub fn open_read_only(file_path: &String, key_size: usize, value_size: usize) -> Result<RecordFile<K,V,KC,VC>, Box<Error>> {
+        let fd = try!(OpenOptions::new().read(true).open(file_path));
+
+        return Ok(RecordFile{fd: fd,
                           key_size: key_size,
                           value_size: value_size,
                           _k_marker: PhantomData,
-                          _v_marker: PhantomData});
+                          _v_marker: PhantomData,
+                          _kc_marker: PhantomData,
+                          _vc_marker: PhantomData});
     }
 
+    /// Returns true if the file holds no records
     pub fn is_new(&self) -> Result<bool, Box<Error>> {
-        Ok(try!(self.fd.metadata()).len() == 0)
+        Ok(try!(self.fd.metadata()).len() <= HEADER_SIZE)
     }
 
     /// Returns the number of records in the WAL file
     pub fn count(&self) -> Result<u64, Box<Error>> {
-        let file_size = try!(self.fd.metadata()).len();
-        let rec_size: u64 = (self.key_size + self.value_size) as u64;
+        let file_size = try!(self.fd.metadata()).len().saturating_sub(HEADER_SIZE);
+        let rec_size: u64 = self.record_size() as u64;
 
         if file_size % rec_size != 0 {
             Err(From::from(IOError::new(ErrorKind::InvalidData, "File size is NOT a multiple of key size + value size")))
@@ -68,60 +157,321 @@ impl <K: KeyType, V: ValueType> RecordFile<K,V> {
         }
     }
 
-    pub fn insert_record(&mut self, kv: &KeyValuePair<K,V>) -> Result<(), Box<Error>> {
-        // encode the record
-        let record_size = self.key_size + self.value_size;
-        let mut buff = try!(encode(&kv, SizeLimit::Bounded(record_size as u64)));
+    pub fn record_size(&self) -> usize {
+        return record_size(self.key_size, self.value_size);
+    }
 
-        // padd it out to the max size
-        if buff.len() > self.key_size + self.value_size {
-            return Err(From::from(IOError::new(ErrorKind::InvalidData, "Key and value size are too large")));
-        } else {
-            let diff = (self.key_size + self.value_size) - buff.len();
-            buff.extend(vec![0; diff]);
+    /// Returns the length of the file holding the given number of records
+    pub fn len_of(&self, count: u64) -> u64 {
+        return HEADER_SIZE + count * self.record_size() as u64;
+    }
+
+    /// Reads the records, after checking the header. A WAL written before the header
+    /// was added has records of another format, so it can't be read.
+    fn read_records(&mut self) -> Result<Vec<u8>, Box<Error>> {
+        let mut buff = Vec::new();
+
+        try!(self.fd.seek(SeekFrom::Start(0)));
+        try!(self.fd.read_to_end(&mut buff));
+
+        if buff.is_empty() {
+            return Ok(buff);
+        }
+
+        if buff.len() < HEADER_SIZE as usize || &buff[..HEADER_SIZE as usize - 1] != FILE_HEADER.as_bytes() {
+            return Err(From::from(IOError::new(ErrorKind::InvalidData, "WAL has no header, so was written by an older version; compact the tree with that version first")));
+        }
+
+        if buff[HEADER_SIZE as usize - 1] != CURRENT_VERSION {
+            return Err(From::from(IOError::new(ErrorKind::InvalidData, format!("WAL has unknown version {}", buff[HEADER_SIZE as usize - 1]))));
         }
 
+        return Ok(buff.split_off(HEADER_SIZE as usize));
+    }
+
+    pub fn insert_record(&mut self, record: &WalRecord<K,V>) -> Result<(), Box<Error>> {
+        let buff = try!(encode_record::<K,V,KC,VC>(record, self.record_size()));
+
         match self.fd.write_all(&buff) {
             Ok(_) => Ok( () ),
             Err(e) => Err(From::from(e))
         }
     }
+
+    /// Appends the records with a single write, so a Replace & its Inserts go together
+    pub fn insert_records(&mut self, records: &[WalRecord<K,V>]) -> Result<(), Box<Error>> {
+        let mut buff = Vec::with_capacity(records.len() * self.record_size());
+
+        for record in records {
+            buff.extend(try!(encode_record::<K,V,KC,VC>(record, self.record_size())));
+        }
+
+        Ok(try!(self.fd.write_all(&buff)))
+    }
+
+    /// Reads every record that can be decoded, skipping any that can't rather than
+    /// stopping at the first one like the iterator. Returns the records & the number
+    /// of records skipped, counting a partially written record at the end.
+    pub fn salvage(&mut self) -> Result<(Vec<WalRecord<K,V>>, u64), Box<Error>> {
+        let buff = try!(self.read_records());
+
+        let mut records = Vec::new();
+        let mut lost = 0;
+
+        for chunk in buff.chunks(self.record_size()) {
+            match decode_record::<K,V,KC,VC>(chunk) {
+                Ok(record) if chunk.len() == self.record_size() => records.push(record),
+                _ => lost += 1
+            }
+        }
+
+        return Ok((records, lost));
+    }
+
+    /// Reads the records of every complete change, for replaying the WAL. Anything after
+    /// them can only be a change torn by a crash: a partial record at the end, or a
+    /// Replace at the end missing some of its Inserts. A record that can't be decoded is
+    /// damage rather than a torn write, so it's an error, leaving the records after it
+    /// for repair to recover.
+    pub fn read_changes(&mut self) -> Result<Vec<WalRecord<K,V>>, Box<Error>> {
+        let buff = try!(self.read_records());
+
+        let mut records = Vec::new();
+
+        // only a partial record at the end is shorter than a record
+        for (i, chunk) in buff.chunks(self.record_size()).filter(|chunk| chunk.len() == self.record_size()).enumerate() {
+            match decode_record::<K,V,KC,VC>(chunk) {
+                Ok(record) => records.push(record),
+                Err(_) => return Err(From::from(IOError::new(ErrorKind::InvalidData, format!("WAL record {} is corrupt, repair the tree to recover the records after it", i))))
+            }
+        }
+
+        let complete = complete_records(&records);
+
+        records.truncate(complete);
+
+        return Ok(records);
+    }
+
+    /// Removes all records from the file, keeping the header
+    pub fn truncate(&mut self) -> Result<(), Box<Error>> {
+        return self.truncate_to(0);
+    }
+
+    /// Keeps only the first count records, dropping anything after them, and positions
+    /// the file to append after them
+    pub fn truncate_to(&mut self, count: u64) -> Result<(), Box<Error>> {
+        let len = self.len_of(count);
+
+        try!(self.fd.set_len(len));
+        try!(self.fd.seek(SeekFrom::Start(len)));
+
+        Ok( () )
+    }
 }
 
-impl <'a, K: KeyType, V: ValueType> IntoIterator for &'a mut RecordFile<K,V> {
-    type Item = KeyValuePair<K,V>;
-    type IntoIter = RecordFileIterator<'a, K,V>;
+fn header_bytes() -> Vec<u8> {
+    let mut header = FILE_HEADER.as_bytes().to_vec();
+
+    header.push(CURRENT_VERSION);
+
+    return header;
+}
+
+/// Returns the number of records that make up complete changes, leaving out a Replace
+/// at the end that is missing some of its Inserts
+pub fn complete_records<K: KeyType, V: ValueType>(records: &[WalRecord<K,V>]) -> usize {
+    let mut complete = 0;
+
+    while complete < records.len() {
+        let group = match records[complete] {
+            WalRecord::Replace(_, count) => 1 + count as usize,
+            _ => 1
+        };
+
+        if complete + group > records.len() {
+            break;
+        }
+
+        complete += group;
+    }
+
+    return complete;
+}
+
+/// Returns the size of each record in a file for the given key & value sizes
+pub fn record_size(key_size: usize, value_size: usize) -> usize {
+    return key_size + value_size + RECORD_OVERHEAD;
+}
+
+/// Encodes a record, padded out to exactly record_size bytes
+pub fn encode_record<K, V, KC, VC>(record: &WalRecord<K,V>, record_size: usize) -> Result<Vec<u8>, Box<Error>>
+    where K: KeyType, V: ValueType, KC: Codec<K>, VC: Codec<V>
+{
+    let mut buff = Vec::with_capacity(record_size);
+
+    match *record {
+        WalRecord::Insert(ref key, ref value) => {
+            buff.push(INSERT_TAG);
+            try!(encode_field(&mut buff, |b| KC::encode(key, b)));
+            try!(encode_field(&mut buff, |b| VC::encode(value, b)));
+        },
+        WalRecord::Delete(ref key, ref value) => {
+            buff.push(DELETE_TAG);
+            try!(encode_field(&mut buff, |b| KC::encode(key, b)));
+            try!(encode_field(&mut buff, |b| VC::encode(value, b)));
+        },
+        WalRecord::Merge(ref key, ref operand) => {
+            buff.push(MERGE_TAG);
+            try!(encode_field(&mut buff, |b| KC::encode(key, b)));
+            try!(encode_field(&mut buff, |b| VC::encode(operand, b)));
+        },
+        WalRecord::DeleteKey(ref key) => {
+            buff.push(DELETE_KEY_TAG);
+            try!(encode_field(&mut buff, |b| KC::encode(key, b)));
+            try!(buff.write_u32::<BigEndian>(0));
+        },
+        WalRecord::Replace(ref key, count) => {
+            buff.push(REPLACE_TAG);
+            try!(encode_field(&mut buff, |b| KC::encode(key, b)));
+            try!(buff.write_u32::<BigEndian>(count));
+        },
+        WalRecord::DeleteRange(ref start, ref end) => {
+            buff.push(DELETE_RANGE_TAG | bound_kind(start) << 4 | bound_kind(end) << 6);
+
+            for bound in &[start, end] {
+                match **bound {
+                    Bound::Included(ref key) | Bound::Excluded(ref key) => try!(encode_field(&mut buff, |b| KC::encode(key, b))),
+                    Bound::Unbounded => try!(buff.write_u32::<BigEndian>(0))
+                }
+            }
+        }
+    }
+
+    // padd it out to the max size
+    if buff.len() > record_size {
+        return Err(From::from(IOError::new(ErrorKind::InvalidData, "Key and value size are too large")));
+    } else {
+        let diff = record_size - buff.len();
+        buff.extend(vec![0; diff]);
+    }
+
+    return Ok(buff);
+}
+
+/// Appends the length of what encode writes, then what it writes
+fn encode_field<F>(buff: &mut Vec<u8>, encode: F) -> Result<(), Box<Error>>
+    where F: FnOnce(&mut Vec<u8>) -> Result<(), Box<Error>>
+{
+    let start = buff.len();
+
+    // the length is filled in once the field is encoded
+    try!(buff.write_u32::<BigEndian>(0));
+    try!(encode(buff));
+
+    let len = buff.len() - start - 4;
+
+    BigEndian::write_u32(&mut buff[start..start + 4], len as u32);
+
+    Ok( () )
+}
+
+fn bound_kind<K>(bound: &Bound<K>) -> u8 {
+    match *bound {
+        Bound::Included(_) => INCLUDED,
+        Bound::Excluded(_) => EXCLUDED,
+        Bound::Unbounded => UNBOUNDED
+    }
+}
+
+fn invalid_record() -> Box<Error> {
+    return From::from(IOError::new(ErrorKind::InvalidData, "WAL record is corrupt"));
+}
+
+/// Returns the field written by encode_field at the offset, advancing the offset past it
+fn decode_field<'a>(buff: &'a [u8], offset: &mut usize) -> Result<&'a [u8], Box<Error>> {
+    if *offset + 4 > buff.len() {
+        return Err(invalid_record());
+    }
+
+    let end = *offset + 4 + BigEndian::read_u32(&buff[*offset..*offset + 4]) as usize;
+
+    if end > buff.len() {
+        return Err(invalid_record());
+    }
+
+    let field = &buff[*offset + 4..end];
+
+    *offset = end;
+
+    return Ok(field);
+}
+
+/// Decodes a bound of a range, given its kind & the field holding its key
+fn decode_bound<K, KC: Codec<K>>(kind: u8, key_buff: &[u8]) -> Result<Bound<K>, Box<Error>> {
+    match kind {
+        INCLUDED => Ok(Bound::Included(try!(KC::decode(key_buff)))),
+        EXCLUDED => Ok(Bound::Excluded(try!(KC::decode(key_buff)))),
+        UNBOUNDED if key_buff.is_empty() => Ok(Bound::Unbounded),
+        _ => Err(invalid_record())
+    }
+}
+
+/// Decodes a record, ignoring the padding after it
+pub fn decode_record<K, V, KC, VC>(buff: &[u8]) -> Result<WalRecord<K,V>, Box<Error>>
+    where K: KeyType, V: ValueType, KC: Codec<K>, VC: Codec<V>
+{
+    if buff.len() < RECORD_OVERHEAD {
+        return Err(invalid_record());
+    }
+
+    let mut offset = 1;
+    let key_buff = try!(decode_field(buff, &mut offset));
+
+    // only a delete range keeps anything in the top bits of the tag
+    match (buff[0] & 0x0f, buff[0] >> 4) {
+        (INSERT_TAG, 0) => Ok(WalRecord::Insert(try!(KC::decode(key_buff)), try!(VC::decode(try!(decode_field(buff, &mut offset)))))),
+        (DELETE_TAG, 0) => Ok(WalRecord::Delete(try!(KC::decode(key_buff)), try!(VC::decode(try!(decode_field(buff, &mut offset)))))),
+        (MERGE_TAG, 0) => Ok(WalRecord::Merge(try!(KC::decode(key_buff)), try!(VC::decode(try!(decode_field(buff, &mut offset)))))),
+        (DELETE_KEY_TAG, 0) => Ok(WalRecord::DeleteKey(try!(KC::decode(key_buff)))),
+        (REPLACE_TAG, 0) if offset + 4 <= buff.len() => Ok(WalRecord::Replace(try!(KC::decode(key_buff)), BigEndian::read_u32(&buff[offset..offset + 4]))),
+        (DELETE_RANGE_TAG, kinds) => {
+            let end_buff = try!(decode_field(buff, &mut offset));
+
+            Ok(WalRecord::DeleteRange(try!(decode_bound::<K,KC>(kinds & 0x03, key_buff)), try!(decode_bound::<K,KC>(kinds >> 2, end_buff))))
+        },
+        _ => Err(invalid_record())
+    }
+}
+
+impl <'a, K: KeyType, V: ValueType, KC: Codec<K>, VC: Codec<V>> IntoIterator for &'a mut RecordFile<K,V,KC,VC> {
+    type Item = WalRecord<K,V>;
+    type IntoIter = RecordFileIterator<'a,K,V,KC,VC>;
 
     fn into_iter(self) -> Self::IntoIter {
-        // seek back to the start
-        self.fd.seek(SeekFrom::Start(0));
+        // seek back to the first record
+        self.fd.seek(SeekFrom::Start(HEADER_SIZE));
 
         // create our iterator
         RecordFileIterator{wal_file: self}
     }
 }
 
-impl <'a, K: KeyType, V: ValueType> Iterator for RecordFileIterator<'a,K,V> {
-    type Item = KeyValuePair<K,V>;
+impl <'a, K: KeyType, V: ValueType, KC: Codec<K>, VC: Codec<V>> Iterator for RecordFileIterator<'a,K,V,KC,VC> {
+    type Item = WalRecord<K,V>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let total_size = self.wal_file.key_size + self.wal_file.value_size;
-        let mut buff = vec![0; total_size];
-
-        println!("Creating buffer: {}", total_size);
+        let mut buff = vec![0; self.wal_file.record_size()];
 
         // attempt to read a buffer's worth and decode
         match self.wal_file.fd.read_exact(&mut buff) {
             Ok(_) => {
-                match decode(&buff) {
+                match decode_record::<K,V,KC,VC>(&buff) {
                     Ok(record) => Some(record),
                     Err(_) => None
                 }
             },
-            Err(e) => {
-                println!("ERROR: {}", e);
-                None
-            }
+            Err(_) => None
         }
     }
 }
@@ -131,7 +481,11 @@ impl <'a, K: KeyType, V: ValueType> Iterator for RecordFileIterator<'a,K,V> {
 mod tests {
     use tests::gen_temp_name;
     use std::fs;
-    use wal_file::{RecordFile, KeyValuePair};
+    use std::fs::OpenOptions;
+    use std::io::{Seek, SeekFrom, Write};
+    use std::ops::Bound;
+    use wal_file::{RecordFile, WalRecord, HEADER_SIZE, complete_records, encode_record, record_size};
+    use codec::Bincode;
 
     #[test]
     fn test_iterator() {
@@ -139,30 +493,107 @@ mod tests {
         let file_path = temp_path.to_owned() + ".wal";
 
         // create a new blank file
-        let mut wal_file = RecordFile::new(&file_path, 20, 20).unwrap();
+        let mut wal_file = RecordFile::<_, _, Bincode, Bincode>::new(&file_path, 20, 20).unwrap();
 
         assert!(wal_file.is_new().unwrap());
 
-        let kv1 = KeyValuePair{key: "hello".to_owned(), value: "world".to_owned()};
-        let kv2 = KeyValuePair{key: "foo".to_owned(), value: "bar".to_owned()};
+        let kv1 = WalRecord::Insert("hello".to_owned(), "world".to_owned());
+        let kv2 = WalRecord::Delete("foo".to_owned(), "bar".to_owned());
+        let kv3 = WalRecord::DeleteKey("foo".to_owned());
+        let kv4 = WalRecord::Replace("hello".to_owned(), 1);
+        let kv5 = WalRecord::DeleteRange(Bound::Excluded("a".to_owned()), Bound::Unbounded);
+        let kv6 = WalRecord::Merge("hello".to_owned(), "!".to_owned());
 
         wal_file.insert_record(&kv1).unwrap();
         wal_file.insert_record(&kv2).unwrap();
+        wal_file.insert_records(&[kv3.clone(), kv4.clone(), kv1.clone(), kv5.clone(), kv6.clone()]).unwrap();
 
-        assert!(wal_file.count().unwrap() == 2);
+        assert!(wal_file.count().unwrap() == 7);
 
         let mut wal_it = wal_file.into_iter();
 
         let it_kv1 = wal_it.next().unwrap();
 
-        assert!(kv1.key == it_kv1.key);
-        assert!(kv1.value == it_kv1.value);
+        assert!(kv1 == it_kv1);
 
         let it_kv2 = wal_it.next().unwrap();
 
-        assert!(kv2.key == it_kv2.key);
-        assert!(kv2.value == it_kv2.value);
+        assert!(kv2 == it_kv2);
+        assert_eq!(wal_it.collect::<Vec<_>>(), [kv3, kv4, kv1, kv5, kv6]);
 
         fs::remove_file(&file_path);
     }
+
+    #[test]
+    fn salvage_skips_bad_records() {
+        let file_path = gen_temp_name() + ".wal";
+
+        {
+            let mut wal_file = RecordFile::<_, _, Bincode, Bincode>::new(&file_path, 20, 20).unwrap();
+
+            for i in 0..3 {
+                wal_file.insert_record(&WalRecord::Insert(format!("key{}", i), "value".to_owned())).unwrap();
+            }
+        }
+
+        // corrupt the length of the first key & leave a partial record at the end
+        {
+            let mut fd = OpenOptions::new().read(true).write(true).open(&file_path).unwrap();
+
+            fd.seek(SeekFrom::Start(HEADER_SIZE + 4)).unwrap();
+            fd.write_all(&[0xff; 8]).unwrap();
+            fd.seek(SeekFrom::End(0)).unwrap();
+            fd.write_all(&[0; 10]).unwrap();
+        }
+
+        let mut wal_file = RecordFile::<String, String, Bincode, Bincode>::new(&file_path, 20, 20).unwrap();
+        let (records, lost) = wal_file.salvage().unwrap();
+
+        assert_eq!(records, vec![WalRecord::Insert("key1".to_owned(), "value".to_owned()),
+                                 WalRecord::Insert("key2".to_owned(), "value".to_owned())]);
+        assert_eq!(lost, 2);
+
+        fs::remove_file(&file_path).unwrap();
+    }
+
+    #[test]
+    fn checks_header() {
+        let file_path = gen_temp_name() + ".wal";
+
+        // a log from before the header, its records starting at the beginning of the file
+        fs::File::create(&file_path).unwrap().write_all(&encode_record::<u32,u32,Bincode,Bincode>(&WalRecord::Insert(1, 1), record_size(4, 4)).unwrap()).unwrap();
+
+        {
+            let mut wal_file = RecordFile::<u32, u32, Bincode, Bincode>::new(&file_path, 4, 4).unwrap();
+
+            assert!(wal_file.read_changes().is_err());
+            assert!(wal_file.salvage().is_err());
+        }
+
+        assert_eq!(fs::metadata(&file_path).unwrap().len(), record_size(4, 4) as u64);
+
+        // a header torn by a crash is written again
+        fs::File::create(&file_path).unwrap().write_all(b"B+T").unwrap();
+
+        let mut wal_file = RecordFile::<u32, u32, Bincode, Bincode>::new(&file_path, 4, 4).unwrap();
+
+        assert!(wal_file.is_new().unwrap());
+
+        wal_file.insert_record(&WalRecord::Insert(1, 1)).unwrap();
+
+        assert_eq!(wal_file.read_changes().unwrap(), [WalRecord::Insert(1, 1)]);
+        assert_eq!(fs::metadata(&file_path).unwrap().len(), HEADER_SIZE + record_size(4, 4) as u64);
+
+        fs::remove_file(&file_path).unwrap();
+    }
+
+    #[test]
+    fn drops_partial_replace() {
+        let insert = WalRecord::Insert(1u32, 2u32);
+        let replace = WalRecord::Replace(1u32, 2);
+
+        assert_eq!(complete_records(&[insert.clone(), replace.clone(), insert.clone(), insert.clone()]), 4);
+        assert_eq!(complete_records(&[insert.clone(), replace.clone(), insert.clone()]), 1);
+        assert_eq!(complete_records(&[WalRecord::<u32, u32>::Replace(1, 0), WalRecord::DeleteKey(1)]), 2);
+    }
 }